@@ -1,65 +1,473 @@
-use std::net::SocketAddr;
+use pnet::ipnetwork::IpNetwork;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
 use super::config;
-use super::coordinator::node::{Node, NodeCoordinator};
-use super::device::{Device, config::DeviceConfig};
-use super::tunnels::{header::HEADER_SIZE, outgoing};
+use super::coordinator::fec::{DEFAULT_FEC_TIMEOUT, FecConfig};
+use super::coordinator::lease::Lease;
+use super::coordinator::node::{
+    DEFAULT_HEALTH_INTERVAL, DEFAULT_HEALTH_THRESHOLD, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_NODE_WEIGHT, HealthCheck, Node, NodeCoordinator,
+    domain::DomainTable, rule::CoodinatorRules, scheduling::Scheduler,
+};
+use super::coordinator::resolver;
+use super::device::{
+    Device,
+    config::{DEFAULT_IPV6_PREFIX, DeviceConfig},
+    dns::{self, DNS_PORT, DnsForwarder},
+    route::{DEFAULT_FWMARK, DEFAULT_ROUTE_TABLE, RouteConfig, Routes},
+};
+use super::tunnels::{
+    header::{FrameKind, HEADER_SIZE},
+    noise::{self, StaticKeypair},
+    outgoing::{self, REQUEST_ATTEMPTS, REQUEST_TIMEOUT},
+};
 
 pub async fn run(path: PathBuf) -> anyhow::Result<()> {
     let config = config::from_file(path)?;
-    let primary_node = config
-        .nodes
-        .iter()
-        .find(|n| n.primary.unwrap_or_default())
-        .expect("the primary node must be set");
+    let keypair = Arc::new(StaticKeypair::from_hex(&config.private_key)?);
+    info!("local public key: {}", hex::encode(keypair.public));
+
+    let bypass = bypass_domains(&config)?;
+
+    let primaries = primary_nodes(&config)?;
+    let primary_index = primaries[0];
+    let primary_node = &config.nodes[primary_index];
+
+    let fwmark = config.routes.as_ref().map(|routes| routes.fwmark.unwrap_or(DEFAULT_FWMARK));
+    let nodes = create_nodes(
+        &config.nodes,
+        config.device.mtu as usize,
+        primary_node.addr.parse().unwrap(),
+        keypair,
+        fwmark,
+    )?;
+    let lease_node = nodes[primary_index].clone();
+    let lease = match config.device.addr {
+        Some(_) => None,
+        None => Some(request_lease(&lease_node, config.device.mtu as usize).await?),
+    };
 
-    let nodes = create_nodes(&config.nodes, config.device.mtu as usize, primary_node.addr.parse().unwrap());
-    let device = new_network_device(&config.device)?;
+    let resolvers = match (config.dns.as_ref(), lease_resolvers(lease.as_ref())) {
+        (Some(_), Some(resolvers)) => Some(resolvers),
+        (Some(_), None) => Some(request_resolvers(&lease_node, config.device.mtu as usize).await?),
+        (None, _) => None,
+    };
+
+    let device = new_network_device(&config.device, lease.as_ref())?;
     let (tun_tx, tun_rx) = device.forward().await?;
 
-    let node_coord = Arc::new(NodeCoordinator::new(nodes));
-    let (nc_tx, nc_rx) = node_coord.forward();
+    let routes = match config.routes.as_ref() {
+        Some(routes) => Some(Arc::new(Mutex::new(Routes::up(route_config(routes, &config)?).await?))),
+        None => None,
+    };
 
-    tokio::spawn(async move {
-        while let Ok(payload) = tun_rx.recv().await {
-            let _ = nc_tx.send(payload).await;
+    // NOTE(nosiee): the routes and the system resolver are restored whatever way the client stops, an error included
+    let served = async {
+        let domains = Arc::new(DomainTable::new(bypass, routes.clone()));
+        let rules = match config.rules.as_ref() {
+            Some(rules) => Some(CoodinatorRules::new(rules, &nodes)?.set_domains(domains.clone())),
+            None => None,
+        };
+
+        let scheduler = match config.scheduling.as_ref() {
+            Some(scheduling) => Scheduler::from_config(scheduling)?,
+            None => Scheduler::default(),
+        };
+
+        let mut node_coord = NodeCoordinator::new(nodes, rules, health_check(config.health.as_ref()))
+            .set_primaries(primaries)
+            .set_scheduler(scheduler);
+
+        if let Some(fec) = config.fec.as_ref() {
+            let timeout = fec.timeout.map(Duration::from_millis).unwrap_or(DEFAULT_FEC_TIMEOUT);
+            node_coord = node_coord.set_fec(FecConfig::new(fec.data, fec.parity, timeout)?);
         }
-    });
 
-    while let Ok(payload) = nc_rx.recv().await {
-        tun_tx.send(payload).await.unwrap();
+        let node_coord = Arc::new(node_coord);
+        let keepalive_interval = config.keepalive_interval.map(Duration::from_secs).unwrap_or(DEFAULT_KEEPALIVE_INTERVAL);
+
+        if !keepalive_interval.is_zero() {
+            node_coord.clone().keepalive(keepalive_interval);
+        }
+
+        let (nc_tx, mut nc_rx) = node_coord.forward();
+
+        if let Some(resolvers) = resolvers {
+            let addr = device_ipv4(&config.device, lease.as_ref())?;
+            let mut forwarder = DnsForwarder::bind(addr, resolvers).await?;
+
+            if has_domains(config.rules.as_ref()) {
+                forwarder = forwarder.set_domains(domains.clone());
+            }
+
+            let forwarder = Arc::new(forwarder);
+            info!("dns forwarder listening on {}:{}", addr, DNS_PORT);
+            nc_rx = forwarder.forward(nc_tx.clone(), nc_rx);
+
+            if config.dns.as_ref().and_then(|dns| dns.system).unwrap_or_default() {
+                dns::set_system_resolver(&[IpAddr::V4(addr)])?;
+                info!("system resolver set to {}", addr);
+            }
+        }
+
+        // NOTE(nosiee): without the forwarder the leased dns servers are used by the system resolver directly
+        if let Some(lease) = lease.as_ref().filter(|lease| config.dns.is_none() && !lease.dns.is_empty()) {
+            dns::set_system_resolver(&lease.dns)?;
+            info!("system resolver set to {:?} from the lease", lease.dns);
+        }
+
+        if let Some(lease) = lease {
+            renew_lease(lease_node, lease);
+        }
+
+        // NOTE(nosiee): the dns replies crossing the device are learned from along with the forwarder's ones
+        let tracked = has_domains(config.rules.as_ref()).then(|| domains.clone());
+        if let Some(domains) = tracked.clone() {
+            domains.clone().sweep();
+            domains.bypass_routes();
+        }
+
+        let tracked_c = tracked.clone();
+        tokio::spawn(async move {
+            while let Ok(payload) = tun_rx.recv().await {
+                if let Some(domains) = tracked_c.as_ref() {
+                    domains.track_query(&payload);
+                }
+
+                let _ = nc_tx.send(payload).await;
+            }
+        });
+
+        tokio::select! {
+            _ = async {
+                while let Ok(payload) = nc_rx.recv().await {
+                    if let Some(domains) = tracked.as_ref() {
+                        domains.learn_packet(&payload);
+                    }
+
+                    tun_tx.send(payload).await.unwrap();
+                }
+            } => (),
+            _ = shutdown() => info!("shutting down"),
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    if let Some(routes) = routes {
+        routes.lock().await.down().await;
     }
 
+    restore_system_resolver();
+
+    served
+}
+
+// NOTE(nosiee): removes what a client that didn't exit cleanly has left, the kill switch and the system resolver included
+pub async fn unlock(path: PathBuf) -> anyhow::Result<()> {
+    let config = config::from_file(path)?;
+
+    if let Some(routes) = config.routes.as_ref() {
+        Routes::clear(route_config(routes, &config)?).await?;
+        info!("routes and kill switch removed");
+    }
+
+    restore_system_resolver();
+
     Ok(())
 }
 
-fn create_nodes(nc: &Vec<config::NodeConfig>, mtu: usize, primary_node: SocketAddr) -> Vec<Arc<Node>> {
+fn restore_system_resolver() {
+    match dns::restore_system_resolver() {
+        Ok(true) => info!("system resolver restored"),
+        Ok(false) => (),
+        Err(err) => error!("failed to restore the system resolver: {}", err),
+    }
+}
+
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for the terminate signal");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+// NOTE(nosiee): `primaries` lists the node ids in the order of preference,
+// without it the nodes marked as primary are used in the config order. a leased address is known only to
+// the node that leased it, the next primary would drop the traffic from it, so a leasing client has one primary
+fn primary_nodes(config: &config::Config) -> anyhow::Result<Vec<usize>> {
+    let indexes: Vec<usize> = match config.primaries.as_ref() {
+        Some(primaries) => {
+            let mut indexes = Vec::with_capacity(primaries.len());
+            for id in primaries {
+                match config.nodes.iter().position(|n| &n.id == id) {
+                    Some(i) => indexes.push(i),
+                    None => return Err(anyhow::anyhow!("unknown primary node: {}", id)),
+                }
+            }
+
+            indexes
+        }
+        None => (0..config.nodes.len()).filter(|&i| config.nodes[i].primary.unwrap_or_default()).collect(),
+    };
+
+    if indexes.is_empty() {
+        return Err(anyhow::anyhow!("at least one primary node must be set"));
+    }
+
+    if config.device.addr.is_none() && indexes.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "failover between primary nodes isn't supported with a leased address, set device.addr or a single primary node"
+        ));
+    }
+
+    Ok(indexes)
+}
+
+fn create_nodes(
+    nc: &Vec<config::NodeConfig>,
+    mtu: usize,
+    primary_node: SocketAddr,
+    keypair: Arc<StaticKeypair>,
+    fwmark: Option<u32>,
+) -> anyhow::Result<Vec<Arc<Node>>> {
     let mut nodes = Vec::with_capacity(nc.len());
 
     for node in nc {
+        let mut tunnel = outgoing::OutgoingTunnel::new()
+            .set_addr(node.addr.parse().unwrap())
+            .set_keypair(keypair.clone())
+            .set_public_key(noise::decode_key(&node.public_key)?)
+            .set_primary_node(primary_node);
+
+        if let Some(fwmark) = fwmark {
+            tunnel = tunnel.set_fwmark(fwmark);
+        }
+
         let node = Node {
             id: node.id.clone(),
             addr: node.addr.parse().unwrap(),
-            tunnel: outgoing::OutgoingTunnel::new()
-                .set_addr(node.addr.parse().unwrap())
-                .set_primary_node(primary_node),
+            tags: node.tags.clone().unwrap_or_default(),
+            weight: node.weight.unwrap_or(DEFAULT_NODE_WEIGHT),
+            tunnel,
             max_fragment_size: mtu + HEADER_SIZE,
         };
 
         nodes.push(Arc::new(node));
     }
 
-    nodes
+    Ok(nodes)
+}
+
+// NOTE(nosiee): the bypass rules are installed as routes, a rule with addresses or without domains can't be one
+fn bypass_domains(config: &config::Config) -> anyhow::Result<Vec<String>> {
+    let mut bypass = Vec::new();
+
+    let policy = config.rules.as_ref().and_then(|rules| rules.policy.as_ref());
+    for rule in policy.iter().copied().flatten().filter(|rule| rule.bypass.unwrap_or_default()) {
+        if config.routes.is_none() {
+            return Err(anyhow::anyhow!("the bypass rules need the routes to be set"));
+        }
+
+        match rule.domains.as_ref() {
+            Some(domains) if rule.destination.is_none() && rule.ports.is_none() && rule.protocol.is_none() => bypass.extend(domains.iter().cloned()),
+            _ => return Err(anyhow::anyhow!("a bypass rule must have only the domains set")),
+        }
+    }
+
+    Ok(bypass)
+}
+
+fn has_domains(conf: Option<&config::ClientRules>) -> bool {
+    conf.and_then(|rules| rules.policy.as_ref())
+        .is_some_and(|policy| policy.iter().any(|rule| rule.domains.is_some()))
+}
+
+// NOTE(nosiee): the node addresses are always excluded, the tunnel never goes through itself
+fn route_config(conf: &config::RoutesConfig, config: &config::Config) -> anyhow::Result<RouteConfig> {
+    let mut destinations = Vec::with_capacity(conf.destinations.len());
+    for net in &conf.destinations {
+        destinations.push(net.parse()?);
+    }
+
+    let mut excluded = Vec::with_capacity(config.nodes.len());
+    for node in &config.nodes {
+        excluded.push(node.addr.parse::<SocketAddr>()?.ip());
+    }
+
+    Ok(RouteConfig {
+        device: config.device.name.clone(),
+        destinations,
+        excluded,
+        table: conf.table.unwrap_or(DEFAULT_ROUTE_TABLE),
+        fwmark: conf.fwmark.unwrap_or(DEFAULT_FWMARK),
+        kill_switch: conf.kill_switch.unwrap_or_default(),
+    })
 }
 
-fn new_network_device(conf: &config::DeviceConfig) -> anyhow::Result<Device> {
+fn health_check(conf: Option<&config::HealthConfig>) -> Option<HealthCheck> {
+    let interval = conf.and_then(|c| c.interval).map(Duration::from_secs).unwrap_or(DEFAULT_HEALTH_INTERVAL);
+    let threshold = conf.and_then(|c| c.threshold).unwrap_or(DEFAULT_HEALTH_THRESHOLD);
+
+    if interval.is_zero() {
+        return None;
+    }
+
+    Some(HealthCheck {
+        interval,
+        threshold: threshold.max(1),
+    })
+}
+
+// NOTE(nosiee): the control requests are made before the device is created, nothing else reads the tunnel yet,
+// so the reply is received here
+async fn request(node: &Node, kind: FrameKind, reply_kind: FrameKind, mtu: usize) -> anyhow::Result<Vec<u8>> {
+    let mut reply_rx = node.tunnel.subscribe_reply(reply_kind);
+    let mut buffer = vec![0; mtu + HEADER_SIZE];
+
+    for _ in 0..REQUEST_ATTEMPTS {
+        if let Err(err) = node.tunnel.send_request(kind).await {
+            warn!("failed to send {:?} to {} node: {:?}", kind, node.id, err);
+            tokio::time::sleep(REQUEST_TIMEOUT).await;
+            continue;
+        }
+
+        let reply = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            loop {
+                tokio::select! {
+                    _ = node.tunnel.recv(&mut buffer) => continue,
+                    _ = reply_rx.changed() => return reply_rx.borrow_and_update().clone(),
+                }
+            }
+        })
+        .await;
+
+        if let Ok(Some(reply)) = reply {
+            return Ok(reply);
+        }
+    }
+
+    Err(anyhow::anyhow!("no {:?} from {} node", reply_kind, node.id))
+}
+
+// NOTE(nosiee): without the device address the client asks the primary node for a lease
+async fn request_lease(node: &Node, mtu: usize) -> anyhow::Result<Lease> {
+    let reply = request(node, FrameKind::LeaseRequest, FrameKind::LeaseReply, mtu).await?;
+    let lease = Lease::decode(&reply).ok_or_else(|| anyhow::anyhow!("malformed lease from {} node", node.id))?;
+
+    info!(
+        "{}/{} leased from {} node for {:?}, dns: {:?}",
+        lease.addr, lease.prefix, node.id, lease.duration, lease.dns
+    );
+
+    Ok(lease)
+}
+
+// NOTE(nosiee): the dns servers of the lease take the place of the resolvers of the primary node,
+// the forwarder uses only the ipv4 ones
+fn lease_resolvers(lease: Option<&Lease>) -> Option<Vec<Ipv4Addr>> {
+    let resolvers: Vec<Ipv4Addr> = lease?
+        .dns
+        .iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+
+    if resolvers.is_empty() {
+        return None;
+    }
+
+    info!("resolvers {:?} from the lease", resolvers);
+    Some(resolvers)
+}
+
+// NOTE(nosiee): the primary node chooses the resolvers, only the ipv4 ones are used by the forwarder
+async fn request_resolvers(node: &Node, mtu: usize) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let reply = request(node, FrameKind::ResolverRequest, FrameKind::ResolverReply, mtu).await?;
+    let resolvers = resolver::decode(&reply).ok_or_else(|| anyhow::anyhow!("malformed resolvers from {} node", node.id))?;
+    info!("resolvers {:?} from {} node", resolvers, node.id);
+
+    Ok(resolvers
+        .into_iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect())
+}
+
+// NOTE(nosiee): the lease is renewed at half of its duration, the reply is received by the node coordinator.
+// the device keeps its address, a different one in the reply means the lease was lost on the node
+fn renew_lease(node: Arc<Node>, lease: Lease) {
+    tokio::spawn(async move {
+        let mut lease_rx = node.tunnel.subscribe_reply(FrameKind::LeaseReply);
+        let interval = (lease.duration / 2).max(REQUEST_TIMEOUT);
+
+        loop {
+            tokio::time::sleep(interval).await;
+            lease_rx.mark_unchanged();
+
+            if let Err(err) = node.tunnel.send_request(FrameKind::LeaseRequest).await {
+                warn!("failed to renew the lease from {} node: {:?}", node.id, err);
+                continue;
+            }
+
+            if tokio::time::timeout(REQUEST_TIMEOUT, lease_rx.changed()).await.is_err() {
+                warn!("no lease renewal from {} node", node.id);
+                continue;
+            }
+
+            let renewed = lease_rx.borrow_and_update().as_deref().and_then(Lease::decode);
+            match renewed {
+                Some(renewed) if renewed.addr == lease.addr => info!("{} lease renewed from {} node", lease.addr, node.id),
+                Some(renewed) => error!("{} lease lost, {} node leased {} instead", lease.addr, node.id, renewed.addr),
+                None => warn!("malformed lease renewal from {} node", node.id),
+            }
+        }
+    });
+}
+
+fn device_ipv4(conf: &config::DeviceConfig, lease: Option<&Lease>) -> anyhow::Result<Ipv4Addr> {
+    match lease.map(|lease| lease.addr) {
+        Some(IpAddr::V4(addr)) => Ok(addr),
+        Some(addr) => Err(anyhow::anyhow!("unexpected ipv6 lease: {}", addr)),
+        None => Ok(conf
+            .addr
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("the device address must be set"))?
+            .parse()?),
+    }
+}
+
+fn new_network_device(conf: &config::DeviceConfig, lease: Option<&Lease>) -> anyhow::Result<Device> {
+    let (addr, mask) = match lease {
+        Some(lease) if lease.addr.is_ipv4() => (lease.addr.to_string(), IpNetwork::new(lease.addr, lease.prefix)?.mask().to_string()),
+        Some(lease) => return Err(anyhow::anyhow!("unexpected ipv6 lease: {}", lease.addr)),
+        None => (
+            conf.addr.clone().unwrap_or_default(),
+            conf.mask
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("the device mask must be set along with the address"))?,
+        ),
+    };
+
     Device::new_tun(DeviceConfig {
         name: conf.name.clone(),
         mtu: conf.mtu,
-        addr: conf.addr.parse().unwrap(),
-        mask: conf.mask.clone(),
+        addr,
+        mask,
+        ipv6_addr: conf.ipv6_addr.clone(),
+        ipv6_prefix: conf.ipv6_prefix.unwrap_or(DEFAULT_IPV6_PREFIX),
         disable_on_exit: conf.disable_on_exit,
     })
 }
@@ -3,18 +3,34 @@ use std::{fs, path::PathBuf};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    pub private_key: String,
+    pub keepalive_interval: Option<u64>,
+    pub health: Option<HealthConfig>,
+    pub primaries: Option<Vec<String>>,
+    pub scheduling: Option<SchedulingConfig>,
+    pub reorder: Option<ReorderConfig>,
+    pub fec: Option<FecConfig>,
+    pub conntrack: Option<ConntrackConfig>,
+    pub snat: Option<SnatConfig>,
+    pub lease: Option<LeaseConfig>,
+    pub masquerade: Option<MasqueradeConfig>,
+    pub routes: Option<RoutesConfig>,
+    pub dns: Option<DnsConfig>,
     pub device: DeviceConfig,
     pub tunnel: Option<TunnelConfig>,
     pub rules: Option<ClientRules>,
     pub nodes: Vec<NodeConfig>,
+    pub clients: Option<Vec<ClientConfig>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct DeviceConfig {
     pub name: String,
     pub mtu: u16,
-    pub addr: String,
-    pub mask: String,
+    pub addr: Option<String>,
+    pub mask: Option<String>,
+    pub ipv6_addr: Option<String>,
+    pub ipv6_prefix: Option<u8>,
     pub disable_on_exit: bool,
 }
 
@@ -23,17 +39,107 @@ pub struct TunnelConfig {
     pub addr: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    pub interval: Option<u64>,
+    pub threshold: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SchedulingConfig {
+    pub mode: Option<String>,
+    pub flowlet_gap: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReorderConfig {
+    pub timeout: Option<u64>,
+    pub capacity: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FecConfig {
+    pub data: u8,
+    pub parity: u8,
+    pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConntrackConfig {
+    pub max_entries: Option<usize>,
+    pub tcp_timeout: Option<u64>,
+    pub udp_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnatConfig {
+    pub pools: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LeaseConfig {
+    pub pool: String,
+    pub dns: Option<Vec<String>>,
+    pub duration: Option<u64>,
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MasqueradeConfig {
+    pub addr: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoutesConfig {
+    pub destinations: Vec<String>,
+    pub table: Option<u32>,
+    pub fwmark: Option<u32>,
+    pub kill_switch: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DnsConfig {
+    pub resolvers: Option<Vec<String>>,
+    pub system: Option<bool>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ClientRules {
-    pub tunnels: Vec<String>,
-    pub nodes: u64,
+    pub tunnels: Option<Vec<String>>,
+    pub nodes: Option<u64>,
+    pub redundancy: Option<u8>,
+    pub policy: Option<Vec<PolicyRule>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolicyRule {
+    pub destination: Option<Vec<String>>,
+    pub domains: Option<Vec<String>>,
+    pub bypass: Option<bool>,
+    pub ports: Option<Vec<u16>>,
+    pub protocol: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub tunnels: Option<Vec<String>>,
+    pub nodes: Option<u64>,
+    pub redundancy: Option<u8>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct NodeConfig {
     pub id: String,
     pub addr: String,
+    pub public_key: String,
     pub primary: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    pub weight: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    pub id: String,
+    pub public_key: String,
+    pub allowed_ips: Vec<String>,
 }
 
 pub fn from_file(path: PathBuf) -> anyhow::Result<Config> {
@@ -0,0 +1,279 @@
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::tcp::TcpFlags;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::device;
+
+pub const DEFAULT_MAX_ENTRIES: usize = 0x10000;
+pub const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(7200);
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(120);
+const TCP_CLOSED_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConntrackConfig {
+    pub max_entries: usize,
+    pub tcp_timeout: Duration,
+    pub udp_timeout: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for ConntrackConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            tcp_timeout: DEFAULT_TCP_TIMEOUT,
+            udp_timeout: DEFAULT_UDP_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    TcpOpen,
+    TcpClosing,
+    TcpClosed,
+    Udp,
+    Other,
+}
+
+#[derive(Debug)]
+struct Entry {
+    peers: Vec<String>,
+    state: State,
+    last_seen: Instant,
+    tick: u64,
+}
+
+// NOTE(nosiee): the coordination table with the conntrack like state. every entry expires after the idle
+// timeout of its protocol, a tcp entry expires soon after FIN or RST. the table is capped, once it's full
+// the least recently seen entry is evicted, `lru` keeps the entries ordered by the last touch
+#[derive(Debug)]
+pub struct CoordinationTable {
+    config: ConntrackConfig,
+    entries: HashMap<String, Entry>,
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl CoordinationTable {
+    pub fn new(config: ConntrackConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn insert(&mut self, identity: String, peer: String, redundancy: u8, payload: &[u8]) {
+        if !self.entries.contains_key(&identity) && self.entries.len() >= self.config.max_entries {
+            self.evict();
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.entry(identity.clone()).or_insert_with(|| Entry {
+            peers: Vec::new(),
+            state: State::Other,
+            last_seen: Instant::now(),
+            tick,
+        });
+
+        entry.peers.retain(|p| *p != peer);
+        entry.peers.insert(0, peer);
+        entry.peers.truncate(redundancy.max(1) as usize);
+
+        Self::track(entry, payload);
+        self.lru.remove(&entry.tick);
+        entry.tick = tick;
+        self.lru.insert(tick, identity);
+    }
+
+    pub fn get(&mut self, identity: &String, payload: &[u8]) -> Option<Vec<String>> {
+        let entry = self.entries.get_mut(identity)?;
+
+        self.tick += 1;
+        Self::track(entry, payload);
+        self.lru.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.lru.insert(self.tick, identity.clone());
+
+        Some(entry.peers.clone())
+    }
+
+    pub fn expire(&mut self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> usize {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.last_seen) >= self.timeout(entry.state))
+            .map(|(identity, _)| identity.clone())
+            .collect();
+
+        for identity in expired.iter() {
+            if let Some(entry) = self.entries.remove(identity) {
+                self.lru.remove(&entry.tick);
+            }
+        }
+
+        expired.len()
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, identity)) = self.lru.pop_first() {
+            self.entries.remove(&identity);
+        }
+    }
+
+    fn timeout(&self, state: State) -> Duration {
+        match state {
+            State::TcpOpen => self.config.tcp_timeout,
+            State::TcpClosing => TCP_CLOSING_TIMEOUT.min(self.config.tcp_timeout),
+            State::TcpClosed => TCP_CLOSED_TIMEOUT.min(self.config.tcp_timeout),
+            State::Udp => self.config.udp_timeout,
+            State::Other => self.config.timeout,
+        }
+    }
+
+    // NOTE(nosiee): a new SYN reopens a closed entry, the port may be reused by the next connection
+    fn track(entry: &mut Entry, payload: &[u8]) {
+        entry.last_seen = Instant::now();
+
+        let protocol = match device::util::get_flow(payload) {
+            Some(flow) => flow.protocol,
+            None => return,
+        };
+
+        entry.state = match protocol {
+            IpNextHeaderProtocols::Tcp => match device::util::get_tcp_flags(payload) {
+                Some(flags) if flags & TcpFlags::RST != 0 => State::TcpClosed,
+                Some(flags) if flags & TcpFlags::FIN != 0 && entry.state != State::TcpClosed => State::TcpClosing,
+                Some(flags) if flags & TcpFlags::SYN != 0 => State::TcpOpen,
+                _ if matches!(entry.state, State::TcpClosing | State::TcpClosed) => entry.state,
+                _ => State::TcpOpen,
+            },
+            IpNextHeaderProtocols::Udp => State::Udp,
+            _ => State::Other,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ConntrackConfig = ConntrackConfig {
+        max_entries: 3,
+        tcp_timeout: DEFAULT_TCP_TIMEOUT,
+        udp_timeout: DEFAULT_UDP_TIMEOUT,
+        timeout: DEFAULT_TIMEOUT,
+    };
+
+    fn packet(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, protocol, 0, 0, 10, 0, 0, 2, 198, 51, 100, 1];
+        packet[2..4].copy_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(transport);
+
+        packet
+    }
+
+    fn tcp(flags: u8) -> Vec<u8> {
+        let mut segment = vec![0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 0, 0, 0, 0, 0, 0x50, 0, 0, 0, 0, 0, 0, 0];
+        segment[13] = flags;
+
+        packet(6, &segment)
+    }
+
+    fn udp() -> Vec<u8> {
+        packet(17, &[0x9c, 0x40, 0, 53, 0, 8, 0, 0])
+    }
+
+    fn insert(table: &mut CoordinationTable, identity: &str, payload: &[u8]) {
+        table.insert(identity.to_string(), "peer".to_string(), 1, payload);
+    }
+
+    fn has(table: &CoordinationTable, identity: &str) -> bool {
+        table.entries.contains_key(identity)
+    }
+
+    #[test]
+    fn keeps_the_latest_peers() {
+        let mut table = CoordinationTable::new(CONFIG);
+
+        table.insert("flow".to_string(), "a".to_string(), 2, &udp());
+        table.insert("flow".to_string(), "b".to_string(), 2, &udp());
+        table.insert("flow".to_string(), "c".to_string(), 2, &udp());
+        table.insert("flow".to_string(), "b".to_string(), 2, &udp());
+
+        assert_eq!(table.get(&"flow".to_string(), &udp()), Some(vec!["b".to_string(), "c".to_string()]));
+        assert_eq!(table.get(&"other".to_string(), &udp()), None);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_seen() {
+        let mut table = CoordinationTable::new(CONFIG);
+
+        insert(&mut table, "a", &udp());
+        insert(&mut table, "b", &udp());
+        insert(&mut table, "c", &udp());
+        table.get(&"a".to_string(), &udp()).unwrap();
+        insert(&mut table, "d", &udp());
+
+        assert!(has(&table, "a") && !has(&table, "b") && has(&table, "c") && has(&table, "d"));
+        assert_eq!(table.lru.len(), 3);
+
+        insert(&mut table, "c", &udp());
+        insert(&mut table, "e", &udp());
+        assert!(!has(&table, "a") && has(&table, "c") && has(&table, "d") && has(&table, "e"));
+    }
+
+    #[test]
+    fn expires_by_protocol() {
+        let mut table = CoordinationTable::new(CONFIG);
+
+        insert(&mut table, "tcp", &tcp(TcpFlags::SYN));
+        insert(&mut table, "udp", &udp());
+        insert(&mut table, "other", &packet(132, &[0; 12]));
+
+        assert_eq!(table.expire_at(Instant::now()), 0);
+        assert_eq!(table.expire_at(Instant::now() + DEFAULT_TIMEOUT), 1);
+        assert!(!has(&table, "other"));
+        assert_eq!(table.expire_at(Instant::now() + DEFAULT_UDP_TIMEOUT), 1);
+        assert_eq!(table.expire_at(Instant::now() + DEFAULT_TCP_TIMEOUT), 1);
+        assert!(table.entries.is_empty() && table.lru.is_empty());
+    }
+
+    #[test]
+    fn closes_tcp_early() {
+        let mut table = CoordinationTable::new(CONFIG);
+
+        insert(&mut table, "fin", &tcp(TcpFlags::SYN));
+        insert(&mut table, "fin", &tcp(TcpFlags::FIN | TcpFlags::ACK));
+        insert(&mut table, "fin", &tcp(TcpFlags::ACK));
+        insert(&mut table, "rst", &tcp(TcpFlags::ACK));
+        insert(&mut table, "rst", &tcp(TcpFlags::RST));
+
+        assert_eq!(table.expire_at(Instant::now() + TCP_CLOSED_TIMEOUT), 1);
+        assert!(has(&table, "fin") && !has(&table, "rst"));
+        assert_eq!(table.expire_at(Instant::now() + TCP_CLOSING_TIMEOUT), 1);
+    }
+
+    #[test]
+    fn reopens_closed_tcp_on_syn() {
+        let mut table = CoordinationTable::new(CONFIG);
+
+        insert(&mut table, "tcp", &tcp(TcpFlags::RST));
+        insert(&mut table, "tcp", &tcp(TcpFlags::SYN));
+
+        assert_eq!(table.expire_at(Instant::now() + TCP_CLOSING_TIMEOUT), 0);
+    }
+}
@@ -0,0 +1,69 @@
+use pnet::ipnetwork::IpNetwork;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::tunnels::noise::Key;
+
+pub type Tenant = u64;
+
+#[derive(Debug, Clone)]
+pub struct ClientEntry {
+    pub id: String,
+    pub tenant: Tenant,
+    pub allowed_ips: Vec<IpNetwork>,
+}
+
+#[derive(Debug)]
+pub enum Authority<'a> {
+    Client(&'a ClientEntry),
+    Node(&'a String),
+}
+
+// NOTE(nosiee): the controlling table. a peer is allowed to create or update a coordination only if
+// its static key is known and the packet source belongs to the client's private range.
+// the nodes are trusted to relay any source, they validate their own clients
+#[derive(Debug, Default)]
+pub struct ControlTable {
+    clients: HashMap<Key, ClientEntry>,
+    nodes: HashMap<Key, String>,
+}
+
+impl ControlTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_client(&mut self, public_key: Key, entry: ClientEntry) {
+        self.clients.insert(public_key, entry);
+    }
+
+    pub fn add_node(&mut self, public_key: Key, id: String) {
+        self.nodes.insert(public_key, id);
+    }
+
+    pub fn client(&self, public_key: &Key) -> Option<&ClientEntry> {
+        self.clients.get(public_key)
+    }
+
+    pub fn is_known(&self, public_key: &Key) -> bool {
+        self.nodes.contains_key(public_key) || self.clients.contains_key(public_key)
+    }
+
+    pub fn authorize(&self, public_key: &Key, source: IpAddr) -> Option<Authority<'_>> {
+        if let Some(id) = self.nodes.get(public_key) {
+            return Some(Authority::Node(id));
+        }
+
+        let entry = self.clients.get(public_key)?;
+        if entry.allowed_ips.iter().any(|net| net.contains(source)) {
+            return Some(Authority::Client(entry));
+        }
+
+        None
+    }
+}
+
+// NOTE(nosiee): every node knows a client by the same tenant, the first 8 bytes of its key. 0 means no tenant
+pub fn tenant(public_key: &Key) -> Tenant {
+    u64::from_be_bytes(public_key[..8].try_into().unwrap()).max(1)
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::tunnels::replay::{ReplayWindow, WINDOW_SIZE};
+
+// NOTE(nosiee): drops the copies of the packets sent through several nodes. the copies arrive close to each
+// other, so a sequence older than the whole window is not a copy, it means the other side started over
+#[derive(Debug, Default)]
+pub struct DedupWindow {
+    window: ReplayWindow,
+}
+
+impl DedupWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if self.window.update(sequence) {
+            return true;
+        }
+
+        match self.window.last() {
+            Some(last) if last.saturating_sub(sequence) >= WINDOW_SIZE => {
+                self.window = ReplayWindow::new();
+                self.window.update(sequence)
+            }
+            _ => false,
+        }
+    }
+}
+
+// NOTE(nosiee): the primary node numbers its responses per destination address, so every address
+// of the client has its own window, the sequences of the ipv4 and ipv6 responses never meet
+#[derive(Debug, Default)]
+pub struct DedupTable {
+    windows: HashMap<IpAddr, DedupWindow>,
+}
+
+impl DedupTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn accept(&mut self, addr: IpAddr, sequence: u64) -> bool {
+        self.windows.entry(addr).or_default().accept(sequence)
+    }
+
+    pub fn clear(&mut self) {
+        self.windows.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const V4: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+
+    #[test]
+    fn drops_copies() {
+        let mut window = DedupWindow::new();
+
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        assert!(window.accept(9));
+        assert!(!window.accept(9));
+    }
+
+    #[test]
+    fn starts_over_behind_the_window() {
+        let mut window = DedupWindow::new();
+
+        assert!(window.accept(1_000_000));
+        assert!(window.accept(1_000_000 - WINDOW_SIZE));
+        assert!(window.accept(1_000_001 - WINDOW_SIZE));
+    }
+
+    #[test]
+    fn keeps_a_window_per_address() {
+        let mut table = DedupTable::new();
+
+        // NOTE(nosiee): both sequences start at random values, far apart from each other
+        let (v4, v6) = (5_000_000, 17);
+        for i in 0..100 {
+            assert!(table.accept(V4, v4 + i));
+            assert!(table.accept(V6, v6 + i));
+            assert!(!table.accept(V4, v4 + i));
+            assert!(!table.accept(V6, v6 + i));
+        }
+    }
+
+    #[test]
+    fn clears_all_windows() {
+        let mut table = DedupTable::new();
+
+        assert!(table.accept(V4, 1));
+        assert!(table.accept(V6, 1));
+        table.clear();
+
+        assert!(table.accept(V4, 1));
+        assert!(table.accept(V6, 1));
+    }
+}
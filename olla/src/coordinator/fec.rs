@@ -0,0 +1,536 @@
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use super::control::Tenant;
+use crate::device::{self, Message};
+use crate::tunnels::header::HeaderFrame;
+
+pub const DEFAULT_FEC_TIMEOUT: Duration = Duration::from_millis(20);
+pub const MAX_GROUP_SIZE: usize = u64::BITS as usize;
+// NOTE(nosiee): the address prefix of an ipv6 group, the length and the flow sequence of the packet in the shard
+pub const PARITY_OVERHEAD: usize = 1 + 16 + SHARD_HEADER_SIZE;
+
+const LENGTH_SIZE: usize = 2;
+const SHARD_HEADER_SIZE: usize = LENGTH_SIZE + 4;
+const GROUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FecConfig {
+    pub data: u8,
+    pub parity: u8,
+    pub timeout: Duration,
+}
+
+impl FecConfig {
+    pub fn new(data: u8, parity: u8, timeout: Duration) -> anyhow::Result<Self> {
+        if data == 0 || parity == 0 || data as usize + parity as usize > MAX_GROUP_SIZE {
+            return Err(anyhow!(
+                "invalid fec group: {} data and {} parity packets, at most {} in total",
+                data,
+                parity,
+                MAX_GROUP_SIZE
+            ));
+        }
+
+        Ok(Self { data, parity, timeout })
+    }
+}
+
+// NOTE(nosiee): a rebuilt packet along with the flow sequence of its original, so the reorder buffer gets it in place
+pub type RebuiltMessage = (u32, Message);
+
+#[derive(Debug, Clone)]
+pub struct Parity {
+    pub group_id: u32,
+    pub index: u8,
+    pub data: u8,
+    pub parity: u8,
+    pub payload: Bytes,
+}
+
+#[derive(Debug)]
+struct EncoderGroup {
+    id: u32,
+    packets: Vec<(u32, Message)>,
+    opened: Instant,
+}
+
+// NOTE(nosiee): the data packets are sent as is right away, the encoder only keeps a copy. once the group is full
+// or `timeout` passed since it was opened, the parity packets are computed over whatever the group has.
+// the groups are per source address, the primary node can't tell the parity of different clients apart otherwise
+#[derive(Debug)]
+pub struct FecEncoder {
+    config: FecConfig,
+    next_group: u32,
+    groups: HashMap<IpAddr, EncoderGroup>,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            next_group: 0,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, payload: &Message, flow_sequence: u32) -> Option<(u32, u8)> {
+        let source = device::util::get_source_addr(payload)?;
+
+        if !self.groups.contains_key(&source) {
+            self.next_group = match self.next_group.wrapping_add(1) {
+                0 => 1,
+                id => id,
+            };
+
+            let group = EncoderGroup {
+                id: self.next_group,
+                packets: Vec::with_capacity(self.config.data as usize),
+                opened: Instant::now(),
+            };
+
+            self.groups.insert(source, group);
+        }
+
+        let group = self.groups.get_mut(&source)?;
+        group.packets.push((flow_sequence, payload.clone()));
+
+        Some((group.id, (group.packets.len() - 1) as u8))
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.groups.values().map(|group| group.opened + self.config.timeout).min()
+    }
+
+    pub fn ready(&mut self) -> Vec<Parity> {
+        let data = self.config.data as usize;
+        self.close(|group| group.packets.len() >= data)
+    }
+
+    pub fn expired(&mut self) -> Vec<Parity> {
+        let now = Instant::now();
+        let timeout = self.config.timeout;
+
+        self.close(|group| now.duration_since(group.opened) >= timeout)
+    }
+
+    fn close(&mut self, filter: impl Fn(&EncoderGroup) -> bool) -> Vec<Parity> {
+        let closed: Vec<IpAddr> = self.groups.iter().filter(|(_, group)| filter(group)).map(|(source, _)| *source).collect();
+        let mut parities = Vec::new();
+
+        for source in closed {
+            if let Some(group) = self.groups.remove(&source) {
+                parities.extend(self.encode(source, group));
+            }
+        }
+
+        parities
+    }
+
+    fn encode(&self, source: IpAddr, group: EncoderGroup) -> Vec<Parity> {
+        let (data, parity) = (group.packets.len(), self.config.parity as usize);
+        let size = group.packets.iter().map(|(_, packet)| packet.len()).max().unwrap_or_default() + SHARD_HEADER_SIZE;
+
+        let mut shards: Vec<Vec<u8>> = group
+            .packets
+            .iter()
+            .map(|(flow_sequence, packet)| to_shard(packet, *flow_sequence, size))
+            .collect();
+        shards.resize(data + parity, vec![0; size]);
+
+        let encoded = ReedSolomon::new(data, parity).and_then(|rs| rs.encode(&mut shards));
+        if let Err(err) = encoded {
+            debug!("{} fec group omitted, failed to encode: {:?}", group.id, err);
+            return Vec::new();
+        }
+
+        let prefix = address_prefix(source);
+        shards
+            .into_iter()
+            .enumerate()
+            .skip(data)
+            .map(|(i, shard)| {
+                let mut payload = BytesMut::with_capacity(prefix.len() + shard.len());
+                payload.extend_from_slice(&prefix);
+                payload.extend_from_slice(&shard);
+
+                Parity {
+                    group_id: group.id,
+                    index: i as u8,
+                    data: data as u8,
+                    parity: parity as u8,
+                    payload: payload.freeze(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct DecoderGroup {
+    data: HashMap<u8, (u32, Message)>,
+    parity: HashMap<u8, Bytes>,
+    size: Option<(u8, u8)>,
+    delivered: u64,
+    done: bool,
+    created: Instant,
+}
+
+// NOTE(nosiee): the primary node side. every data packet is delivered right away and kept until its group
+// is complete, as soon as the group has enough packets and parity the missing packets are rebuilt.
+// a late original of a rebuilt packet is dropped, the group is kept for a while after it's done for that.
+// the groups expire in the order they were opened, so the expired ones are always at the front of the queue
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    groups: HashMap<(Tenant, IpAddr, u32), DecoderGroup>,
+    opened: VecDeque<(Tenant, IpAddr, u32)>,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // NOTE(nosiee): none if the packet was rebuilt already and must be dropped, otherwise the rebuilt packets
+    pub fn push_data(&mut self, tenant: Tenant, frame: &HeaderFrame, payload: &Message) -> Option<Vec<RebuiltMessage>> {
+        let source = match device::util::get_source_addr(payload) {
+            Some(source) if frame.group_id != 0 && (frame.group_index as usize) < MAX_GROUP_SIZE => source,
+            _ => return Some(Vec::new()),
+        };
+
+        let group = self.group(tenant, source, frame.group_id);
+        let bit = 1 << frame.group_index;
+
+        if group.delivered & bit != 0 {
+            debug!(
+                "{} packet omitted, already rebuilt from the {} fec group",
+                hex::encode(payload),
+                frame.group_id
+            );
+            return None;
+        }
+
+        group.delivered |= bit;
+        if !group.done {
+            group.data.insert(frame.group_index, (frame.flow_sequence, payload.clone()));
+        }
+
+        Some(Self::recover(frame.group_id, group))
+    }
+
+    pub fn push_parity(&mut self, tenant: Tenant, frame: &HeaderFrame, payload: &[u8]) -> Vec<RebuiltMessage> {
+        let (data, parity, index) = (frame.group_data as usize, frame.group_parity as usize, frame.group_index as usize);
+        if frame.group_id == 0 || data == 0 || parity == 0 || data + parity > MAX_GROUP_SIZE || index < data || index >= data + parity {
+            debug!("{} parity omitted, invalid fec group: {:?}", hex::encode(payload), frame);
+            return Vec::new();
+        }
+
+        let (source, shard) = match parse_address_prefix(payload) {
+            Some(parsed) => parsed,
+            None => {
+                debug!("{} parity omitted, unknown source", hex::encode(payload));
+                return Vec::new();
+            }
+        };
+
+        let group = self.group(tenant, source, frame.group_id);
+        if group.done {
+            return Vec::new();
+        }
+
+        group.size = Some((frame.group_data, frame.group_parity));
+        group.parity.insert(frame.group_index, Bytes::copy_from_slice(shard));
+
+        Self::recover(frame.group_id, group)
+    }
+
+    fn group(&mut self, tenant: Tenant, source: IpAddr, group_id: u32) -> &mut DecoderGroup {
+        let key = (tenant, source, group_id);
+        if !self.groups.contains_key(&key) {
+            self.expire();
+            self.opened.push_back(key);
+        }
+
+        self.groups.entry(key).or_insert_with(|| DecoderGroup {
+            data: HashMap::new(),
+            parity: HashMap::new(),
+            size: None,
+            delivered: 0,
+            done: false,
+            created: Instant::now(),
+        })
+    }
+
+    fn expire(&mut self) {
+        while let Some(key) = self.opened.front() {
+            match self.groups.get(key) {
+                Some(group) if group.created.elapsed() < GROUP_TIMEOUT => break,
+                _ => {
+                    self.groups.remove(key);
+                    self.opened.pop_front();
+                }
+            }
+        }
+    }
+
+    fn recover(group_id: u32, group: &mut DecoderGroup) -> Vec<RebuiltMessage> {
+        let (data, parity) = match group.size {
+            Some((data, parity)) if !group.done => (data as usize, parity as usize),
+            _ => return Vec::new(),
+        };
+
+        let all_delivered = if data == MAX_GROUP_SIZE { u64::MAX } else { (1 << data) - 1 };
+        if group.delivered & all_delivered == all_delivered {
+            Self::finish(group);
+            return Vec::new();
+        }
+
+        if group.data.len() + group.parity.len() < data {
+            return Vec::new();
+        }
+
+        let size = match group.parity.values().next() {
+            Some(shard) => shard.len(),
+            None => return Vec::new(),
+        };
+
+        if group.parity.values().any(|shard| shard.len() != size) || group.data.values().any(|(_, packet)| packet.len() + SHARD_HEADER_SIZE > size) {
+            debug!("{} fec group omitted, shard sizes mismatch", group_id);
+            Self::finish(group);
+            return Vec::new();
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = (0..data)
+            .map(|i| {
+                group
+                    .data
+                    .get(&(i as u8))
+                    .map(|(flow_sequence, packet)| to_shard(packet, *flow_sequence, size))
+            })
+            .chain((data..data + parity).map(|i| group.parity.get(&(i as u8)).map(|shard| shard.to_vec())))
+            .collect();
+
+        let rebuilt = ReedSolomon::new(data, parity).and_then(|rs| rs.reconstruct_data(&mut shards));
+        if let Err(err) = rebuilt {
+            debug!("{} fec group omitted, failed to rebuild: {:?}", group_id, err);
+            return Vec::new();
+        }
+
+        let mut packets = Vec::new();
+        for (i, shard) in shards.into_iter().enumerate().take(data) {
+            if group.delivered & (1 << i) != 0 {
+                continue;
+            }
+
+            if let Some((flow_sequence, packet)) = shard.as_deref().and_then(from_shard) {
+                debug!("{} packet rebuilt from the {} fec group", hex::encode(&packet), group_id);
+                packets.push((flow_sequence, packet));
+            }
+
+            group.delivered |= 1 << i;
+        }
+
+        Self::finish(group);
+        packets
+    }
+
+    fn finish(group: &mut DecoderGroup) {
+        group.done = true;
+        group.data.clear();
+        group.parity.clear();
+    }
+}
+
+// NOTE(nosiee): the packets differ in size, a shard is the packet length, the flow sequence, the packet and
+// the zero padding. the flow sequence is rebuilt along with the packet, it's not in the packet itself
+fn to_shard(packet: &[u8], flow_sequence: u32, size: usize) -> Vec<u8> {
+    let mut shard = vec![0; size];
+    shard[..LENGTH_SIZE].copy_from_slice(&(packet.len() as u16).to_be_bytes());
+    shard[LENGTH_SIZE..SHARD_HEADER_SIZE].copy_from_slice(&flow_sequence.to_be_bytes());
+    shard[SHARD_HEADER_SIZE..SHARD_HEADER_SIZE + packet.len()].copy_from_slice(packet);
+
+    shard
+}
+
+fn from_shard(shard: &[u8]) -> Option<RebuiltMessage> {
+    let len = u16::from_be_bytes(shard.get(..LENGTH_SIZE)?.try_into().ok()?) as usize;
+    let flow_sequence = u32::from_be_bytes(shard.get(LENGTH_SIZE..SHARD_HEADER_SIZE)?.try_into().ok()?);
+    let packet = shard.get(SHARD_HEADER_SIZE..SHARD_HEADER_SIZE + len).map(Bytes::copy_from_slice)?;
+
+    Some((flow_sequence, packet))
+}
+
+fn address_prefix(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => [&[4u8][..], &addr.octets()].concat(),
+        IpAddr::V6(addr) => [&[6u8][..], &addr.octets()].concat(),
+    }
+}
+
+fn parse_address_prefix(payload: &[u8]) -> Option<(IpAddr, &[u8])> {
+    match payload.first()? {
+        4 => {
+            let octets: [u8; 4] = payload.get(1..5)?.try_into().ok()?;
+            Some((IpAddr::V4(Ipv4Addr::from(octets)), &payload[5..]))
+        }
+        6 => {
+            let octets: [u8; 16] = payload.get(1..17)?.try_into().ok()?;
+            Some((IpAddr::V6(Ipv6Addr::from(octets)), &payload[17..]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnels::header::FrameKind;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    const TENANT: Tenant = 1;
+
+    fn packet(source: Ipv4Addr, id: u8, size: usize) -> Message {
+        let mut buffer = vec![id; 20 + size];
+        let mut ip_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ip_packet.set_version(4);
+        ip_packet.set_header_length(5);
+        ip_packet.set_total_length((20 + size) as u16);
+        ip_packet.set_source(source);
+        ip_packet.set_destination(Ipv4Addr::new(198, 51, 100, 1));
+
+        Bytes::from(buffer)
+    }
+
+    fn flow_sequence(i: usize) -> u32 {
+        100 + i as u32
+    }
+
+    fn encode(data: u8, parity: u8, packets: &[Message]) -> (Vec<HeaderFrame>, Vec<Parity>) {
+        let mut encoder = FecEncoder::new(FecConfig::new(data, parity, DEFAULT_FEC_TIMEOUT).unwrap());
+        let frames = packets
+            .iter()
+            .enumerate()
+            .map(|(i, packet)| {
+                let (group_id, index) = encoder.push(packet, flow_sequence(i)).unwrap();
+                HeaderFrame::new(FrameKind::Data)
+                    .set_flow_sequence(flow_sequence(i))
+                    .set_group(group_id, index)
+            })
+            .collect();
+
+        (frames, encoder.ready())
+    }
+
+    fn push_parities(decoder: &mut FecDecoder, parities: &[Parity]) -> Vec<RebuiltMessage> {
+        let mut rebuilt = Vec::new();
+        for parity in parities {
+            let frame = HeaderFrame::new(FrameKind::Parity)
+                .set_group(parity.group_id, parity.index)
+                .set_group_size(parity.data, parity.parity);
+            rebuilt.extend(decoder.push_parity(TENANT, &frame, &parity.payload));
+        }
+
+        rebuilt
+    }
+
+    #[test]
+    fn recovers_up_to_parity_losses() {
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        let packets: Vec<Message> = (0..4).map(|i| packet(source, i, 10 + i as usize * 7)).collect();
+        let (frames, parities) = encode(4, 2, &packets);
+        assert_eq!(parities.len(), 2);
+
+        for lost in 0u8..1 << packets.len() {
+            if lost.count_ones() > 2 {
+                continue;
+            }
+
+            let mut decoder = FecDecoder::new();
+            for (i, (packet, frame)) in packets.iter().zip(frames.iter()).enumerate() {
+                if lost & (1 << i) == 0 {
+                    assert_eq!(decoder.push_data(TENANT, frame, packet), Some(Vec::new()));
+                }
+            }
+
+            let mut rebuilt = push_parities(&mut decoder, &parities);
+            rebuilt.sort();
+
+            let expected: Vec<RebuiltMessage> = (0..packets.len())
+                .filter(|i| lost & (1 << i) != 0)
+                .map(|i| (flow_sequence(i), packets[i].clone()))
+                .collect();
+            assert_eq!(rebuilt, expected, "lost: {:04b}", lost);
+        }
+    }
+
+    #[test]
+    fn gives_up_beyond_parity_losses() {
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        let packets: Vec<Message> = (0..4).map(|i| packet(source, i, 16)).collect();
+        let (frames, parities) = encode(4, 2, &packets);
+
+        let mut decoder = FecDecoder::new();
+        decoder.push_data(TENANT, &frames[0], &packets[0]);
+
+        assert!(push_parities(&mut decoder, &parities).is_empty());
+    }
+
+    #[test]
+    fn drops_late_original_of_rebuilt_packet() {
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        let packets: Vec<Message> = (0..2).map(|i| packet(source, i, 24)).collect();
+        let (frames, parities) = encode(2, 1, &packets);
+
+        let mut decoder = FecDecoder::new();
+        decoder.push_data(TENANT, &frames[0], &packets[0]);
+
+        assert_eq!(push_parities(&mut decoder, &parities), vec![(flow_sequence(1), packets[1].clone())]);
+        assert_eq!(decoder.push_data(TENANT, &frames[1], &packets[1]), None);
+    }
+
+    #[test]
+    fn recovers_partial_group() {
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        let packets: Vec<Message> = (0..2).map(|i| packet(source, i, 32)).collect();
+
+        let mut encoder = FecEncoder::new(FecConfig::new(8, 2, Duration::ZERO).unwrap());
+        let frames: Vec<HeaderFrame> = packets
+            .iter()
+            .map(|packet| {
+                let (group_id, index) = encoder.push(packet, 0).unwrap();
+                HeaderFrame::new(FrameKind::Data).set_group(group_id, index)
+            })
+            .collect();
+
+        assert!(encoder.ready().is_empty());
+        let parities = encoder.expired();
+        assert!(parities.iter().all(|parity| parity.data == 2));
+
+        let mut decoder = FecDecoder::new();
+        decoder.push_data(TENANT, &frames[1], &packets[1]);
+
+        assert_eq!(push_parities(&mut decoder, &parities), vec![(0, packets[0].clone())]);
+    }
+
+    #[test]
+    fn keeps_sources_apart() {
+        let packets = [packet(Ipv4Addr::new(10, 0, 0, 2), 0, 16), packet(Ipv4Addr::new(10, 0, 0, 3), 1, 16)];
+        let (frames, parities) = encode(1, 1, &packets);
+        assert_eq!(parities.len(), 2);
+        assert_ne!(frames[0].group_id, frames[1].group_id);
+
+        let mut decoder = FecDecoder::new();
+        let mut rebuilt = push_parities(&mut decoder, &parities);
+        rebuilt.sort();
+
+        assert_eq!(
+            rebuilt,
+            vec![(flow_sequence(0), packets[0].clone()), (flow_sequence(1), packets[1].clone())]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::control::Tenant;
+use crate::device::Message;
+use crate::device::util::FragmentKey;
+use crate::tunnels::header::HeaderFrame;
+
+// NOTE(nosiee): the same as the default reassembly timeout of linux
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_FRAGMENTS: usize = 0x1000;
+const MAX_HELD_FRAGMENTS: usize = 0x40;
+
+pub type HeldFragment = (Message, HeaderFrame);
+
+#[derive(Debug)]
+struct Entry {
+    identity: Option<String>,
+    held: Vec<HeldFragment>,
+    created: Instant,
+}
+
+// NOTE(nosiee): only the first fragment of a packet has the transport header, the identity learned from it
+// is used for the rest of the fragments. a fragment that comes before the first one is held until the identity
+// is known, then it's released along with the first one. both are dropped once the entry expires
+#[derive(Debug, Default)]
+pub struct FragmentCache {
+    entries: HashMap<(Tenant, FragmentKey), Entry>,
+}
+
+impl FragmentCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn learn(&mut self, tenant: Tenant, key: FragmentKey, identity: String) -> Vec<HeldFragment> {
+        match self.entry((tenant, key)) {
+            Some(entry) => {
+                entry.identity = Some(identity);
+                std::mem::take(&mut entry.held)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get(&self, tenant: Tenant, key: FragmentKey) -> Option<String> {
+        self.entries.get(&(tenant, key)).and_then(|entry| entry.identity.clone())
+    }
+
+    pub fn hold(&mut self, tenant: Tenant, key: FragmentKey, payload: Message, frame: HeaderFrame) -> bool {
+        match self.entry((tenant, key)) {
+            Some(entry) if entry.held.len() < MAX_HELD_FRAGMENTS => {
+                entry.held.push((payload, frame));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn expire(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.created.elapsed() < FRAGMENT_TIMEOUT);
+
+        before - self.entries.len()
+    }
+
+    fn entry(&mut self, key: (Tenant, FragmentKey)) -> Option<&mut Entry> {
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_FRAGMENTS && self.expire() == 0 {
+            return None;
+        }
+
+        Some(self.entries.entry(key).or_insert_with(|| Entry {
+            identity: None,
+            held: Vec::new(),
+            created: Instant::now(),
+        }))
+    }
+}
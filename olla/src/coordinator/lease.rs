@@ -0,0 +1,335 @@
+use pnet::ipnetwork::IpNetwork;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use tracing::error;
+
+use super::control::Tenant;
+use super::snat::{host_range, nth_addr};
+
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(3600);
+
+const LEASE_HEADER_SIZE: usize = 22;
+pub const ADDR_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub addr: IpAddr,
+    pub prefix: u8,
+    pub duration: Duration,
+    pub dns: Vec<IpAddr>,
+}
+
+// NOTE(nosiee): the addresses are written as 16 bytes the same way the header does, ipv4 is mapped
+impl Lease {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(LEASE_HEADER_SIZE + self.dns.len() * ADDR_SIZE);
+        buf.extend_from_slice(&encode_addr(self.addr));
+        buf.push(self.prefix);
+        buf.extend_from_slice(&(self.duration.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+        buf.push(self.dns.len().min(u8::MAX as usize) as u8);
+
+        for addr in self.dns.iter().take(u8::MAX as usize) {
+            buf.extend_from_slice(&encode_addr(*addr));
+        }
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let header = buf.get(..LEASE_HEADER_SIZE)?;
+        let dns_count = header[21] as usize;
+
+        let mut dns = Vec::with_capacity(dns_count);
+        for i in 0..dns_count {
+            let offset = LEASE_HEADER_SIZE + i * ADDR_SIZE;
+            dns.push(decode_addr(buf.get(offset..offset + ADDR_SIZE)?));
+        }
+
+        Some(Self {
+            addr: decode_addr(&header[..ADDR_SIZE]),
+            prefix: header[16],
+            duration: Duration::from_secs(u32::from_be_bytes(header[17..21].try_into().unwrap()) as u64),
+            dns,
+        })
+    }
+}
+
+// NOTE(nosiee): a client keeps its address for as long as it renews the lease, the address of an expired lease
+// goes back to the pool. the leases are written to the file on every change and read back on start
+#[derive(Debug)]
+pub struct LeaseTable {
+    pool: IpNetwork,
+    dns: Vec<IpAddr>,
+    duration: Duration,
+    reserved: Vec<IpAddr>,
+    path: Option<PathBuf>,
+    by_tenant: HashMap<Tenant, (IpAddr, SystemTime)>,
+    by_addr: HashMap<IpAddr, Tenant>,
+}
+
+impl LeaseTable {
+    pub fn new(pool: IpNetwork, dns: Vec<IpAddr>, duration: Duration) -> Self {
+        Self {
+            pool,
+            dns,
+            duration,
+            reserved: Vec::new(),
+            path: None,
+            by_tenant: HashMap::new(),
+            by_addr: HashMap::new(),
+        }
+    }
+
+    // NOTE(nosiee): the reserved addresses are never leased, the node's own device address for one
+    pub fn set_reserved(mut self, reserved: Vec<IpAddr>) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    pub fn set_path(mut self, path: PathBuf) -> anyhow::Result<Self> {
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            let (tenant, addr, expires) = parse_line(line).ok_or_else(|| anyhow::anyhow!("malformed lease: {}", line))?;
+            if self.pool.contains(addr) && !self.reserved.contains(&addr) && !self.by_addr.contains_key(&addr) {
+                self.insert(tenant, addr, expires);
+            }
+        }
+
+        self.path = Some(path);
+        self.expire();
+
+        Ok(self)
+    }
+
+    // NOTE(nosiee): the lease of the client is renewed if it has one, none if the pool is exhausted
+    pub fn lease(&mut self, tenant: Tenant) -> Option<Lease> {
+        let addr = match self.by_tenant.get(&tenant) {
+            Some((addr, _)) => *addr,
+            None => self.allocate()?,
+        };
+
+        self.insert(tenant, addr, SystemTime::now() + self.duration);
+        self.persist();
+
+        Some(Lease {
+            addr,
+            prefix: self.pool.prefix(),
+            duration: self.duration,
+            dns: self.dns.clone(),
+        })
+    }
+
+    // NOTE(nosiee): an address out of the pool is none of the table's business, an address of the pool
+    // belongs only to the client that holds a live lease on it
+    pub fn allows(&self, tenant: Tenant, addr: IpAddr) -> bool {
+        if !self.pool.contains(addr) {
+            return true;
+        }
+
+        match self.by_tenant.get(&tenant) {
+            Some((leased, expires)) => *leased == addr && *expires > SystemTime::now(),
+            None => false,
+        }
+    }
+
+    pub fn expire(&mut self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<Tenant> = self
+            .by_tenant
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(tenant, _)| *tenant)
+            .collect();
+
+        for tenant in expired.iter() {
+            if let Some((addr, _)) = self.by_tenant.remove(tenant) {
+                self.by_addr.remove(&addr);
+            }
+        }
+
+        if !expired.is_empty() {
+            self.persist();
+        }
+
+        expired.len()
+    }
+
+    fn allocate(&mut self) -> Option<IpAddr> {
+        self.expire();
+
+        let (first, last) = host_range(&self.pool);
+        (first..=last)
+            .map(|offset| nth_addr(&self.pool, offset))
+            .find(|addr| !self.by_addr.contains_key(addr) && !self.reserved.contains(addr))
+    }
+
+    fn insert(&mut self, tenant: Tenant, addr: IpAddr, expires: SystemTime) {
+        self.by_tenant.insert(tenant, (addr, expires));
+        self.by_addr.insert(addr, tenant);
+    }
+
+    // NOTE(nosiee): the file is replaced as a whole so a crash never leaves it half written
+    fn persist(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut data = String::new();
+        for (tenant, (addr, expires)) in self.by_tenant.iter() {
+            let expires = expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            data.push_str(&format!("{:016x} {} {}\n", tenant, addr, expires));
+        }
+
+        let tmp = path.with_extension("tmp");
+        if let Err(err) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, path)) {
+            error!("failed to persist the leases to {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(Tenant, IpAddr, SystemTime)> {
+    let mut fields = line.split_whitespace();
+    let tenant = Tenant::from_str_radix(fields.next()?, 16).ok()?;
+    let addr = fields.next()?.parse().ok()?;
+    let expires = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+
+    Some((tenant, addr, expires))
+}
+
+pub fn encode_addr(addr: IpAddr) -> [u8; ADDR_SIZE] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+pub fn decode_addr(buf: &[u8]) -> IpAddr {
+    let octets: [u8; ADDR_SIZE] = buf.try_into().unwrap();
+    Ipv6Addr::from(octets).to_canonical()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENANT: Tenant = 1;
+    const OTHER_TENANT: Tenant = 2;
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn table(pool: &str) -> LeaseTable {
+        LeaseTable::new(pool.parse().unwrap(), vec![addr("1.1.1.1")], DEFAULT_LEASE_DURATION)
+    }
+
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("olla-leases-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let lease = Lease {
+            addr: addr("10.0.0.2"),
+            prefix: 24,
+            duration: DEFAULT_LEASE_DURATION,
+            dns: vec![addr("1.1.1.1"), addr("2606:4700:4700::1111")],
+        };
+
+        let buf = lease.encode();
+        assert_eq!(buf.len(), LEASE_HEADER_SIZE + 2 * ADDR_SIZE);
+        assert_eq!(Lease::decode(&buf), Some(lease));
+        assert_eq!(Lease::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(Lease::decode(&buf[..LEASE_HEADER_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn keeps_the_address_of_a_client() {
+        let mut leases = table("10.0.0.0/24").set_reserved(vec![addr("10.0.0.1")]);
+
+        let lease = leases.lease(TENANT).unwrap();
+        assert_eq!((lease.addr, lease.prefix), (addr("10.0.0.2"), 24));
+        assert_eq!(lease.dns, vec![addr("1.1.1.1")]);
+        assert_eq!(leases.lease(OTHER_TENANT).unwrap().addr, addr("10.0.0.3"));
+        assert_eq!(leases.lease(TENANT).unwrap().addr, addr("10.0.0.2"));
+    }
+
+    #[test]
+    fn runs_out_of_addresses() {
+        let mut leases = table("10.0.0.0/30").set_reserved(vec![addr("10.0.0.1")]);
+
+        assert_eq!(leases.lease(TENANT).unwrap().addr, addr("10.0.0.2"));
+        assert_eq!(leases.lease(OTHER_TENANT), None);
+    }
+
+    #[test]
+    fn allows_only_the_leased_address() {
+        let mut leases = table("10.0.0.0/24");
+        leases.lease(TENANT).unwrap();
+
+        assert!(leases.allows(TENANT, addr("10.0.0.1")));
+        assert!(!leases.allows(TENANT, addr("10.0.0.2")));
+        assert!(!leases.allows(OTHER_TENANT, addr("10.0.0.1")));
+        assert!(leases.allows(OTHER_TENANT, addr("192.168.0.1")));
+    }
+
+    #[test]
+    fn frees_expired_addresses() {
+        let mut leases = table("10.0.0.0/30");
+        leases.lease(TENANT).unwrap();
+        leases.by_tenant.get_mut(&TENANT).unwrap().1 = SystemTime::now() - Duration::from_secs(1);
+
+        assert!(!leases.allows(TENANT, addr("10.0.0.1")));
+        assert_eq!(leases.lease(OTHER_TENANT).unwrap().addr, addr("10.0.0.1"));
+        assert!(!leases.by_tenant.contains_key(&TENANT));
+    }
+
+    #[test]
+    fn persists_across_restarts() {
+        let path = path("persists");
+
+        let mut leases = table("10.0.0.0/24").set_path(path.clone()).unwrap();
+        leases.lease(TENANT).unwrap();
+        leases.lease(OTHER_TENANT).unwrap();
+
+        let mut leases = table("10.0.0.0/24").set_path(path.clone()).unwrap();
+        assert_eq!(leases.lease(OTHER_TENANT).unwrap().addr, addr("10.0.0.2"));
+        assert_eq!(leases.lease(TENANT).unwrap().addr, addr("10.0.0.1"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn skips_stale_leases_on_restart() {
+        let path = path("stale");
+        let expires = (SystemTime::now() + DEFAULT_LEASE_DURATION).duration_since(UNIX_EPOCH).unwrap().as_secs();
+        fs::write(
+            &path,
+            format!(
+                "{:016x} 10.0.0.1 {}\n{:016x} 192.168.0.1 {}\n{:016x} 10.0.0.2 0\n",
+                TENANT, expires, OTHER_TENANT, expires, 3
+            ),
+        )
+        .unwrap();
+
+        let leases = table("10.0.0.0/24").set_reserved(vec![addr("10.0.0.1")]).set_path(path.clone()).unwrap();
+        assert!(leases.by_tenant.is_empty());
+
+        fs::write(&path, "olla\n").unwrap();
+        assert!(table("10.0.0.0/24").set_path(path.clone()).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
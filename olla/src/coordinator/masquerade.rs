@@ -0,0 +1,396 @@
+use async_channel::{Receiver, Sender};
+use bytes::BytesMut;
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use socket2::{Domain, Protocol, SockAddr, SockFilter, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, error};
+
+use super::conntrack::SWEEP_INTERVAL;
+use crate::device::{self, DEVICE_BUFFER_SIZE, Message, nat};
+
+pub const MASQUERADE_TCP_TIMEOUT: Duration = Duration::from_secs(7200);
+pub const MASQUERADE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const IPPROTO_RAW: i32 = 255;
+const MAX_PACKET_SIZE: usize = 0xffff;
+
+// NOTE(nosiee): bpf "ret #0", everything that comes to the socket is dropped
+const BPF_RET_K: u16 = 0x06;
+
+#[derive(Debug)]
+struct Mapping {
+    source: Ipv4Addr,
+    port: u16,
+    last_seen: Instant,
+    _reservation: Option<Socket>,
+}
+
+// NOTE(nosiee): a client endpoint is mapped to the same egress port whatever the remote is. the tcp and udp ports
+// are reserved by the kernel sockets bound to them, icmp echo identifiers are allocated here
+#[derive(Debug, Default)]
+struct MasqueradeTable {
+    by_inner: HashMap<(IpNextHeaderProtocol, Ipv4Addr, u16), u16>,
+    by_outer: HashMap<(IpNextHeaderProtocol, u16), Mapping>,
+    next_id: u16,
+}
+
+impl MasqueradeTable {
+    fn map(&mut self, addr: Ipv4Addr, protocol: IpNextHeaderProtocol, source: Ipv4Addr, port: u16) -> io::Result<u16> {
+        if let Some(outer) = self.by_inner.get(&(protocol, source, port)).copied() {
+            if let Some(mapping) = self.by_outer.get_mut(&(protocol, outer)) {
+                mapping.last_seen = Instant::now();
+            }
+
+            return Ok(outer);
+        }
+
+        let (outer, reservation) = match protocol {
+            IpNextHeaderProtocols::Icmp => (self.allocate_id()?, None),
+            _ => {
+                let reservation = reserve(addr, protocol)?;
+                let outer = reservation.local_addr()?.as_socket().map(|addr| addr.port()).unwrap_or_default();
+                (outer, Some(reservation))
+            }
+        };
+
+        let mapping = Mapping {
+            source,
+            port,
+            last_seen: Instant::now(),
+            _reservation: reservation,
+        };
+
+        self.by_inner.insert((protocol, source, port), outer);
+        self.by_outer.insert((protocol, outer), mapping);
+
+        Ok(outer)
+    }
+
+    fn unmap(&mut self, protocol: IpNextHeaderProtocol, outer: u16) -> Option<(Ipv4Addr, u16)> {
+        let mapping = self.by_outer.get_mut(&(protocol, outer))?;
+        mapping.last_seen = Instant::now();
+
+        Some((mapping.source, mapping.port))
+    }
+
+    fn expire(&mut self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> usize {
+        let expired: Vec<(IpNextHeaderProtocol, u16)> = self
+            .by_outer
+            .iter()
+            .filter(|((protocol, _), mapping)| now.saturating_duration_since(mapping.last_seen) >= timeout(*protocol))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired.iter() {
+            if let Some(mapping) = self.by_outer.remove(key) {
+                self.by_inner.remove(&(key.0, mapping.source, mapping.port));
+            }
+        }
+
+        expired.len()
+    }
+
+    fn allocate_id(&mut self) -> io::Result<u16> {
+        for _ in 0..=u16::MAX {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+
+            if !self.by_outer.contains_key(&(IpNextHeaderProtocols::Icmp, id)) {
+                return Ok(id);
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free icmp identifier"))
+    }
+}
+
+// NOTE(nosiee): the userspace masquerade. the packets leaving the node are rewritten to the egress address and port
+// and sent over a raw socket, the replies are taken from the raw sockets and rewritten back, so the node needs
+// neither ip forwarding nor iptables. ipv4 tcp, udp and icmp echo only, the rest is written to the device as is
+#[derive(Debug)]
+pub struct Masquerade {
+    addr: Ipv4Addr,
+    local: Vec<IpNetwork>,
+    sender: AsyncFd<Socket>,
+    receivers: Vec<AsyncFd<Socket>>,
+    table: Mutex<MasqueradeTable>,
+}
+
+impl Masquerade {
+    // NOTE(nosiee): the packets to the local networks, the device network of the node for one, are never masqueraded
+    pub fn new(addr: Ipv4Addr, local: Vec<IpNetwork>) -> anyhow::Result<Self> {
+        let sender = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::from(IPPROTO_RAW)))?;
+        sender.set_nonblocking(true)?;
+
+        let mut receivers = Vec::with_capacity(3);
+        for protocol in [Protocol::TCP, Protocol::UDP, Protocol::ICMPV4] {
+            let receiver = Socket::new(Domain::IPV4, Type::RAW, Some(protocol))?;
+            receiver.set_nonblocking(true)?;
+            receivers.push(AsyncFd::new(receiver)?);
+        }
+
+        Ok(Self {
+            addr,
+            local,
+            sender: AsyncFd::new(sender)?,
+            receivers,
+            table: Mutex::new(MasqueradeTable::default()),
+        })
+    }
+
+    // NOTE(nosiee): sits between the packet coordinator and the device, the replies are merged into the device stream
+    pub fn forward(self: Arc<Self>, tun_dev_tx: Sender<Message>, tun_dev_rx: Receiver<Message>) -> (Sender<Message>, Receiver<Message>) {
+        let (itx, irx): (Sender<Message>, Receiver<Message>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+        let (otx, orx): (Sender<Message>, Receiver<Message>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+
+        self.clone().sweep();
+
+        for i in 0..self.receivers.len() {
+            let self_c = self.clone();
+            let otx = otx.clone();
+
+            tokio::spawn(async move {
+                let mut buffer = vec![0; MAX_PACKET_SIZE];
+
+                loop {
+                    let n = match recv(&self_c.receivers[i], &mut buffer).await {
+                        Ok(n) => n,
+                        Err(err) => {
+                            error!("failed to read from the raw socket: {}", err);
+                            break;
+                        }
+                    };
+
+                    let mut packet = BytesMut::from(&buffer[..n]);
+                    if self_c.restore(&mut packet) {
+                        let _ = otx.send(packet.freeze()).await;
+                    }
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            while let Ok(payload) = tun_dev_rx.recv().await {
+                let _ = otx.send(payload).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Ok(payload) = irx.recv().await {
+                if !self.send(&payload).await {
+                    let _ = tun_dev_tx.send(payload).await;
+                }
+            }
+        });
+
+        (itx, orx)
+    }
+
+    // NOTE(nosiee): false if the packet isn't masqueraded and goes to the device
+    async fn send(&self, payload: &[u8]) -> bool {
+        let (source, destination) = match (device::util::get_source_addr(payload), device::util::get_destination_addr(payload)) {
+            (Some(IpAddr::V4(source)), Some(IpAddr::V4(destination))) => (source, destination),
+            _ => return false,
+        };
+
+        if destination == self.addr || self.local.iter().any(|net| net.contains(IpAddr::V4(destination))) {
+            return false;
+        }
+
+        let (protocol, port) = match nat::get_source_port(payload) {
+            Some((IpNextHeaderProtocols::Icmp, _)) if !is_icmp_echo(payload, 8) => return false,
+            Some((protocol, port)) if protocol != IpNextHeaderProtocols::Icmpv6 => (protocol, port),
+            _ => return false,
+        };
+
+        let outer = match self.table.lock().unwrap().map(self.addr, protocol, source, port) {
+            Ok(outer) => outer,
+            Err(err) => {
+                error!("{} packet omitted, failed to masquerade: {}", hex::encode(payload), err);
+                return true;
+            }
+        };
+
+        let mut packet = BytesMut::from(payload);
+        let _ = nat::rewrite_source(&mut packet, IpAddr::V4(self.addr));
+        let _ = nat::rewrite_source_port(&mut packet, outer);
+
+        let addr = SockAddr::from(SocketAddr::new(IpAddr::V4(destination), 0));
+        match send(&self.sender, &packet, &addr).await {
+            Ok(n) => debug!("{} bytes masqueraded to {}, {}:{} as {}", n, destination, source, port, outer),
+            Err(err) => debug!("{} packet omitted, failed to send: {}", hex::encode(payload), err),
+        }
+
+        true
+    }
+
+    // NOTE(nosiee): a raw socket gets every packet of its protocol, only the replies to the mappings are taken.
+    // an icmp error about a masqueraded packet is rewritten along with the packet it carries
+    fn restore(&self, packet: &mut [u8]) -> bool {
+        if device::util::get_destination_addr(packet) != Some(IpAddr::V4(self.addr)) {
+            return false;
+        }
+
+        let mut table = self.table.lock().unwrap();
+        if let Some((protocol, outer)) = nat::get_destination_port(packet) {
+            if protocol == IpNextHeaderProtocols::Icmp && !is_icmp_echo(packet, 0) {
+                return false;
+            }
+
+            let (source, port) = match table.unmap(protocol, outer) {
+                Some(inner) => inner,
+                None => return false,
+            };
+
+            return nat::rewrite_destination(packet, IpAddr::V4(source)).is_some() && nat::rewrite_destination_port(packet, port).is_some();
+        }
+
+        if let Some((protocol, outer)) = nat::get_embedded_source_port(packet) {
+            let (source, port) = match table.unmap(protocol, outer) {
+                Some(inner) => inner,
+                None => return false,
+            };
+
+            return nat::rewrite_destination(packet, IpAddr::V4(source)).is_some() && nat::rewrite_embedded_source_port(packet, port).is_some();
+        }
+
+        false
+    }
+
+    fn sweep(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+            loop {
+                ticker.tick().await;
+
+                let expired = self.table.lock().unwrap().expire();
+                if expired != 0 {
+                    debug!("{} masquerade mappings expired", expired);
+                }
+            }
+        });
+    }
+}
+
+fn timeout(protocol: IpNextHeaderProtocol) -> Duration {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => MASQUERADE_TCP_TIMEOUT,
+        _ => MASQUERADE_TIMEOUT,
+    }
+}
+
+fn is_icmp_echo(packet: &[u8], kind: u8) -> bool {
+    let header_length = (packet[0] & 0x0f) as usize * 4;
+    packet.get(header_length) == Some(&kind)
+}
+
+// NOTE(nosiee): the kernel socket holds the port, it never sees a packet because of the filter,
+// so the kernel neither resets the masqueraded tcp connections nor answers udp with port unreachable
+fn reserve(addr: Ipv4Addr, protocol: IpNextHeaderProtocol) -> io::Result<Socket> {
+    let socket = match protocol {
+        IpNextHeaderProtocols::Tcp => Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?,
+        _ => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?,
+    };
+
+    socket.attach_filter(&[SockFilter::new(BPF_RET_K, 0, 0, 0)])?;
+    socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V4(addr), 0)))?;
+
+    if protocol == IpNextHeaderProtocols::Tcp {
+        socket.listen(1)?;
+    }
+
+    Ok(socket)
+}
+
+async fn recv(fd: &AsyncFd<Socket>, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+
+        match guard.try_io(|inner| inner.get_ref().read(buffer)) {
+            Ok(result) => return result,
+            Err(_) => continue,
+        }
+    }
+}
+
+async fn send(fd: &AsyncFd<Socket>, packet: &[u8], addr: &SockAddr) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.writable().await?;
+
+        match guard.try_io(|inner| inner.get_ref().send_to(packet, addr)) {
+            Ok(result) => return result,
+            Err(_) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EGRESS: Ipv4Addr = Ipv4Addr::LOCALHOST;
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OTHER_CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+    #[test]
+    fn maps_an_endpoint_to_one_port() {
+        let mut table = MasqueradeTable::default();
+
+        let outer = table.map(EGRESS, IpNextHeaderProtocols::Udp, CLIENT, 40000).unwrap();
+        let other = table.map(EGRESS, IpNextHeaderProtocols::Udp, OTHER_CLIENT, 40000).unwrap();
+
+        assert_ne!(outer, other);
+        assert_eq!(table.map(EGRESS, IpNextHeaderProtocols::Udp, CLIENT, 40000).unwrap(), outer);
+        assert_eq!(table.unmap(IpNextHeaderProtocols::Udp, outer), Some((CLIENT, 40000)));
+        assert_eq!(table.unmap(IpNextHeaderProtocols::Udp, other), Some((OTHER_CLIENT, 40000)));
+        assert_eq!(table.unmap(IpNextHeaderProtocols::Tcp, outer), None);
+    }
+
+    #[test]
+    fn holds_the_port_while_mapped() {
+        let mut table = MasqueradeTable::default();
+        let outer = table.map(EGRESS, IpNextHeaderProtocols::Tcp, CLIENT, 40000).unwrap();
+
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        assert!(socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V4(EGRESS), outer))).is_err());
+    }
+
+    #[test]
+    fn allocates_icmp_identifiers() {
+        let mut table = MasqueradeTable::default();
+
+        assert_eq!(table.map(EGRESS, IpNextHeaderProtocols::Icmp, CLIENT, 7).unwrap(), 0);
+        assert_eq!(table.map(EGRESS, IpNextHeaderProtocols::Icmp, OTHER_CLIENT, 7).unwrap(), 1);
+        assert_eq!(table.map(EGRESS, IpNextHeaderProtocols::Icmp, CLIENT, 7).unwrap(), 0);
+
+        table.next_id = 0;
+        assert_eq!(table.map(EGRESS, IpNextHeaderProtocols::Icmp, CLIENT, 8).unwrap(), 2);
+    }
+
+    #[test]
+    fn expires_by_protocol() {
+        let mut table = MasqueradeTable::default();
+        table.map(EGRESS, IpNextHeaderProtocols::Tcp, CLIENT, 40000).unwrap();
+        let udp = table.map(EGRESS, IpNextHeaderProtocols::Udp, CLIENT, 40000).unwrap();
+        let icmp = table.map(EGRESS, IpNextHeaderProtocols::Icmp, CLIENT, 7).unwrap();
+
+        assert_eq!(table.expire_at(Instant::now()), 0);
+        assert_eq!(table.expire_at(Instant::now() + MASQUERADE_TIMEOUT), 2);
+        assert_eq!(table.unmap(IpNextHeaderProtocols::Udp, udp), None);
+        assert_eq!(table.unmap(IpNextHeaderProtocols::Icmp, icmp), None);
+
+        assert_eq!(table.expire_at(Instant::now() + MASQUERADE_TCP_TIMEOUT), 1);
+        assert!(table.by_inner.is_empty());
+    }
+}
@@ -1,2 +1,12 @@
+pub mod conntrack;
+pub mod control;
+pub mod dedup;
+pub mod fec;
+pub mod fragment;
+pub mod lease;
+pub mod masquerade;
 pub mod node;
 pub mod packet;
+pub mod reorder;
+pub mod resolver;
+pub mod snat;
@@ -0,0 +1,108 @@
+// NOTE(nosiee): Vose's alias method. O(n) to build, O(1) to sample, so it's fine to rebuild
+// the table each time the node set changes and still pick a node for every packet
+#[derive(Debug, Clone)]
+pub struct AliasTable {
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[u32]) -> Option<Self> {
+        let n = weights.len();
+        let total: f64 = weights.iter().map(|w| *w as f64).sum();
+
+        if n == 0 || total == 0.0 {
+            return None;
+        }
+
+        let mut scaled: Vec<f64> = weights.iter().map(|w| *w as f64 * n as f64 / total).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| scaled[i] < 1.0);
+
+        let mut prob = vec![0.0; n];
+        let mut alias = vec![0; n];
+
+        while !small.is_empty() && !large.is_empty() {
+            let s = small.pop().unwrap();
+            let l = large.pop().unwrap();
+
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] = scaled[l] + scaled[s] - 1.0;
+
+            if scaled[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+
+        // NOTE(nosiee): whatever is left is 1.0 give or take the float error
+        for i in large.into_iter().chain(small) {
+            prob[i] = 1.0;
+            alias[i] = i;
+        }
+
+        Some(Self { prob, alias })
+    }
+
+    pub fn sample(&self) -> usize {
+        let i = rand::random_range(0..self.prob.len());
+
+        if rand::random::<f64>() < self.prob[i] { i } else { self.alias[i] }
+    }
+
+    // NOTE(nosiee): the same sampling driven by a hash instead of the rng, the lower half
+    // picks the column and the upper half is the coin, so equal hashes always get the same index
+    pub fn sample_hashed(&self, hash: u64) -> usize {
+        let i = (hash as u32 as usize) % self.prob.len();
+        let coin = (hash >> 32) as f64 / (1u64 << 32) as f64;
+
+        if coin < self.prob[i] { i } else { self.alias[i] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE(nosiee): every column and an evenly spread coin, so the counts are exactly proportional to the weights
+    fn hashed_counts(table: &AliasTable, steps: u64) -> Vec<u64> {
+        let n = table.prob.len() as u64;
+        let mut counts = vec![0; table.prob.len()];
+
+        for column in 0..n {
+            for coin in 0..steps {
+                let hash = ((coin * (1u64 << 32) / steps) << 32) | column;
+                counts[table.sample_hashed(hash)] += 1;
+            }
+        }
+
+        counts
+    }
+
+    #[test]
+    fn needs_a_weight() {
+        assert!(AliasTable::new(&[]).is_none());
+        assert!(AliasTable::new(&[0, 0]).is_none());
+    }
+
+    #[test]
+    fn follows_the_weights() {
+        let table = AliasTable::new(&[1, 2, 5]).unwrap();
+
+        assert_eq!(hashed_counts(&table, 800), vec![300, 600, 1500]);
+    }
+
+    #[test]
+    fn never_picks_zero_weight() {
+        let table = AliasTable::new(&[0, 3, 0, 1]).unwrap();
+
+        let counts = hashed_counts(&table, 400);
+        assert_eq!((counts[0], counts[2]), (0, 0));
+        assert_eq!(counts[1], 3 * counts[3]);
+
+        for _ in 0..1000 {
+            assert!(matches!(table.sample(), 1 | 3));
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use async_channel::{Receiver, Sender};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+use crate::coordinator::conntrack::SWEEP_INTERVAL;
+use crate::device::route::Routes;
+use crate::device::{DEVICE_BUFFER_SIZE, dns, nat, util};
+
+// NOTE(nosiee): the applications may keep an address a little longer than the ttl says, a zero ttl included
+pub const MIN_DOMAIN_TTL: Duration = Duration::from_secs(60);
+
+const MAX_QUERIES: usize = 0x10000;
+
+// NOTE(nosiee): the client endpoint, the resolver endpoint and the id of a query that went out through the device
+type QueryKey = (SocketAddr, SocketAddr, u16);
+
+// NOTE(nosiee): the names every address was resolved from. they are learned from the replies the dns forwarder matched
+// to its own queries and from the replies crossing the device that match a query which went out through it, any other
+// reply is never learned from. a name is forgotten once its ttl is over, the address is forgotten along with its
+// last name. the policy is matched on every packet, so the std locks are fine here, they're never held across an await
+#[derive(Debug)]
+pub struct DomainTable {
+    learned: RwLock<HashMap<IpAddr, HashMap<String, Instant>>>,
+    queries: std::sync::Mutex<HashMap<QueryKey, Instant>>,
+    bypass: Vec<String>,
+    routes: Option<Arc<Mutex<Routes>>>,
+    bypass_tx: Sender<IpAddr>,
+    bypass_rx: Receiver<IpAddr>,
+}
+
+impl DomainTable {
+    pub fn new(bypass: Vec<String>, routes: Option<Arc<Mutex<Routes>>>) -> Self {
+        let (bypass_tx, bypass_rx) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+
+        Self {
+            learned: RwLock::new(HashMap::new()),
+            queries: std::sync::Mutex::new(HashMap::new()),
+            bypass,
+            routes,
+            bypass_tx,
+            bypass_rx,
+        }
+    }
+
+    // NOTE(nosiee): remembers a query the application sends through the device, so its reply can be learned from
+    pub fn track_query(&self, packet: &[u8]) {
+        let (client, resolver) = match udp_endpoints(packet) {
+            Some((client, resolver)) if resolver.port() == dns::DNS_PORT => (client, resolver),
+            _ => return,
+        };
+
+        let id = match nat::get_udp_payload(packet).and_then(|query| dns::message_id(query, false)) {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut queries = self.queries.lock().unwrap();
+        if queries.len() >= MAX_QUERIES {
+            debug!("query {} from {} not tracked, too many queries in flight", id, client);
+            return;
+        }
+
+        queries.insert((client, resolver, id), Instant::now());
+    }
+
+    // NOTE(nosiee): learns from a reply crossing the device if it matches a tracked query, the packet goes on as is
+    pub fn learn_packet(&self, packet: &[u8]) {
+        let (resolver, client) = match udp_endpoints(packet) {
+            Some((resolver, client)) if resolver.port() == dns::DNS_PORT => (resolver, client),
+            _ => return,
+        };
+
+        let reply = match nat::get_udp_payload(packet) {
+            Some(reply) => reply,
+            None => return,
+        };
+
+        let id = match dns::message_id(reply, true) {
+            Some(id) => id,
+            None => return,
+        };
+
+        if self.queries.lock().unwrap().remove(&(client, resolver, id)).is_none() {
+            debug!("reply {} from {} not learned, unknown query", id, resolver);
+            return;
+        }
+
+        self.learn_reply(reply);
+    }
+
+    // NOTE(nosiee): an address of a bypassed domain is handed to the route task, the reply isn't held up by netlink,
+    // so the first packets to the address may still go through the tunnel. the address is routed through the tunnel
+    // again once the ttl is over
+    pub fn learn_reply(&self, reply: &[u8]) {
+        let (question, answers) = match dns::answers(reply) {
+            Some(reply) => reply,
+            None => return,
+        };
+
+        for answer in answers {
+            debug!("{} learned as {}, {}", answer.addr, question, answer.name);
+            self.learn(&question, answer.addr, answer.ttl);
+            self.learn(&answer.name, answer.addr, answer.ttl);
+
+            if self.routes.is_none() || !self.matches(answer.addr, &self.bypass) {
+                continue;
+            }
+
+            if self.bypass_tx.try_send(answer.addr).is_err() {
+                debug!("{} not bypassed, the route queue is full", answer.addr);
+            }
+        }
+    }
+
+    pub fn learn(&self, name: &str, addr: IpAddr, ttl: Duration) {
+        let expires = Instant::now() + ttl.max(MIN_DOMAIN_TTL);
+        let mut learned = self.learned.write().unwrap();
+        let names = learned.entry(addr).or_default();
+
+        match names.get_mut(name) {
+            Some(current) => *current = (*current).max(expires),
+            None => {
+                names.insert(name.to_string(), expires);
+            }
+        }
+    }
+
+    pub fn matches(&self, addr: IpAddr, patterns: &[String]) -> bool {
+        let now = Instant::now();
+        let learned = self.learned.read().unwrap();
+
+        match learned.get(&addr) {
+            Some(names) => names
+                .iter()
+                .any(|(name, expires)| *expires > now && patterns.iter().any(|pattern| matches_domain(pattern, name))),
+            None => false,
+        }
+    }
+
+    pub fn expire(&self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) -> usize {
+        self.queries
+            .lock()
+            .unwrap()
+            .retain(|_, created| now.saturating_duration_since(*created) < dns::DNS_TIMEOUT);
+
+        let mut learned = self.learned.write().unwrap();
+        let before = learned.len();
+
+        learned.retain(|_, names| {
+            names.retain(|_, expires| *expires > now);
+            !names.is_empty()
+        });
+
+        before - learned.len()
+    }
+
+    // NOTE(nosiee): the bypass routes are installed by a single task, one at a time
+    pub fn bypass_routes(self: Arc<Self>) {
+        let routes = match self.routes.clone() {
+            Some(routes) => routes,
+            None => return,
+        };
+
+        tokio::spawn(async move {
+            while let Ok(addr) = self.bypass_rx.recv().await {
+                if let Err(err) = routes.lock().await.bypass(addr).await {
+                    error!("failed to bypass {}: {}", addr, err);
+                }
+            }
+        });
+    }
+
+    pub fn sweep(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+            loop {
+                ticker.tick().await;
+
+                let expired = self.expire();
+                if expired != 0 {
+                    debug!("{} learned addresses expired", expired);
+                }
+
+                let mut routes = match self.routes.as_ref() {
+                    Some(routes) => routes.lock().await,
+                    None => continue,
+                };
+
+                for addr in routes.bypassed() {
+                    if !self.matches(addr, &self.bypass) {
+                        routes.unbypass(addr).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn udp_endpoints(packet: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    match (nat::get_source_port(packet)?, nat::get_destination_port(packet)?) {
+        ((IpNextHeaderProtocols::Udp, source), (IpNextHeaderProtocols::Udp, destination)) => Some((
+            SocketAddr::new(util::get_source_addr(packet)?, source),
+            SocketAddr::new(util::get_destination_addr(packet)?, destination),
+        )),
+        _ => None,
+    }
+}
+
+// NOTE(nosiee): "*.example.com" matches the subdomains of example.com but not example.com itself,
+// anything else matches the name as is. the names are compared case-insensitively, the trailing dot is ignored
+pub fn matches_domain(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.');
+    let name = name.trim_end_matches('.');
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            let (name, suffix) = (name.as_bytes(), suffix.as_bytes());
+            name.len() > suffix.len() + 1
+                && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                && name[name.len() - suffix.len() - 1] == b'.'
+        }
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "10.0.0.2:40000";
+    const RESOLVER: &str = "1.1.1.1:53";
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    // NOTE(nosiee): a query for example.com, the reply carries a single a record with a 300 seconds ttl
+    fn message(id: u16, reply: bool) -> Vec<u8> {
+        let flags: u16 = if reply { 0x8180 } else { 0x0100 };
+        let mut message = id.to_be_bytes().to_vec();
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, reply as u8, 0, 0, 0, 0]);
+        message.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+
+        if reply {
+            message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x01, 0x2c, 0, 4, 93, 184, 216, 34]);
+        }
+
+        message
+    }
+
+    fn packet(source: &str, destination: &str, payload: &[u8]) -> Vec<u8> {
+        let (source, destination): (SocketAddr, SocketAddr) = (source.parse().unwrap(), destination.parse().unwrap());
+        let (IpAddr::V4(source_addr), IpAddr::V4(destination_addr)) = (source.ip(), destination.ip()) else {
+            unreachable!();
+        };
+
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+        packet[2..4].copy_from_slice(&((28 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&source_addr.octets());
+        packet.extend_from_slice(&destination_addr.octets());
+        packet.extend_from_slice(&source.port().to_be_bytes());
+        packet.extend_from_slice(&destination.port().to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+
+        packet
+    }
+
+    fn learned(domains: &DomainTable) -> bool {
+        domains.matches(addr("93.184.216.34"), &patterns(&["example.com"]))
+    }
+
+    #[test]
+    fn matches_domains() {
+        assert!(matches_domain("example.com", "EXAMPLE.com."));
+        assert!(!matches_domain("example.com", "www.example.com"));
+        assert!(matches_domain("*.example.com", "www.example.com"));
+        assert!(matches_domain("*.example.com.", "a.b.example.com"));
+        assert!(!matches_domain("*.example.com", "example.com"));
+        assert!(!matches_domain("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn forgets_names_after_the_ttl() {
+        let domains = DomainTable::new(Vec::new(), None);
+
+        domains.learn("example.com", addr("93.184.216.34"), Duration::ZERO);
+        domains.learn("cdn.example.net", addr("93.184.216.34"), MIN_DOMAIN_TTL * 2);
+        assert!(learned(&domains));
+
+        assert_eq!(domains.expire_at(Instant::now() + MIN_DOMAIN_TTL), 0);
+        assert!(!learned(&domains));
+        assert!(domains.matches(addr("93.184.216.34"), &patterns(&["*.example.net"])));
+
+        assert_eq!(domains.expire_at(Instant::now() + MIN_DOMAIN_TTL * 2), 1);
+        assert!(domains.learned.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn learns_from_replies_to_tracked_queries() {
+        let domains = DomainTable::new(Vec::new(), None);
+
+        domains.track_query(&packet(CLIENT, RESOLVER, &message(7, false)));
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(7, true)));
+        assert!(learned(&domains));
+        assert!(domains.queries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_replies_to_unknown_queries() {
+        let domains = DomainTable::new(Vec::new(), None);
+        domains.track_query(&packet(CLIENT, RESOLVER, &message(7, false)));
+
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(8, true)));
+        domains.learn_packet(&packet("8.8.8.8:53", CLIENT, &message(7, true)));
+        domains.learn_packet(&packet(RESOLVER, "10.0.0.2:40001", &message(7, true)));
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(7, false)));
+        assert!(!learned(&domains));
+
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(7, true)));
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(7, true)));
+        assert!(learned(&domains));
+    }
+
+    #[test]
+    fn forgets_unanswered_queries() {
+        let domains = DomainTable::new(Vec::new(), None);
+
+        domains.track_query(&packet(CLIENT, RESOLVER, &message(7, false)));
+        domains.track_query(&packet(CLIENT, "1.1.1.1:5353", &message(8, false)));
+        domains.track_query(&packet(CLIENT, RESOLVER, &message(9, true)));
+        assert_eq!(domains.queries.lock().unwrap().len(), 1);
+
+        domains.expire_at(Instant::now() + dns::DNS_TIMEOUT);
+        assert!(domains.queries.lock().unwrap().is_empty());
+
+        domains.learn_packet(&packet(RESOLVER, CLIENT, &message(7, true)));
+        assert!(!learned(&domains));
+    }
+
+    #[test]
+    fn queues_no_bypass_without_routes() {
+        let domains = DomainTable::new(patterns(&["example.com"]), None);
+
+        domains.learn_reply(&message(7, true));
+        assert!(learned(&domains));
+        assert!(domains.bypass_rx.is_empty());
+    }
+}
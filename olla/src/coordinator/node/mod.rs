@@ -1,63 +1,387 @@
+pub mod alias;
+pub mod domain;
 pub mod rule;
+pub mod scheduling;
+pub mod sequence;
 
 use async_channel::{Receiver, Sender};
 use bytes::BytesMut;
 use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::RwLock;
-use tracing::{debug, error};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
 
-use super::node::rule::CoodinatorRules;
-use crate::device::{DEVICE_BUFFER_SIZE, Message};
+use super::dedup::DedupTable;
+use super::fec::{FecConfig, FecEncoder, Parity};
+use super::node::rule::{CoodinatorRules, Selection};
+use super::node::scheduling::Scheduler;
+use super::node::sequence::FlowSequencer;
+use crate::device::util::Flow;
+use crate::device::{self, DEVICE_BUFFER_SIZE, Message};
+use crate::tunnels::errors::TunnelError;
+use crate::tunnels::header::{FrameKind, HeaderFrame, MAX_SEQUENCE};
 use crate::tunnels::outgoing::OutgoingTunnel;
 
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+pub const DEFAULT_NODE_WEIGHT: u32 = 1;
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_HEALTH_THRESHOLD: u32 = 3;
+pub const RECV_BACKOFF: Duration = Duration::from_millis(100);
+pub const MAX_RECV_BACKOFF: Duration = Duration::from_secs(5);
+
+type NodeMessage = (Message, HeaderFrame);
+
 #[derive(Debug)]
 pub struct Node {
     pub id: String,
     pub addr: SocketAddr,
+    pub tags: Vec<String>,
+    pub weight: u32,
     pub tunnel: OutgoingTunnel,
     pub max_fragment_size: usize,
 }
 
+impl Node {
+    // NOTE(nosiee): a crypto error is a single bad message, it's skipped. an io error keeps coming back, so the
+    // reader backs off and drops the session, the next frame makes a new connection and the reader follows it
+    pub async fn recv(&self, buffer: &mut [u8]) -> (usize, HeaderFrame) {
+        let mut backoff = RECV_BACKOFF;
+
+        loop {
+            match self.tunnel.recv(buffer).await {
+                Ok(received) => return received,
+                Err(TunnelError::IO(err)) => {
+                    error!(
+                        "failed to read from {}, {} node: {:?}, retrying in {:?}",
+                        self.id,
+                        self.addr.to_string(),
+                        err,
+                        backoff
+                    );
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                    self.tunnel.reset();
+                }
+                Err(err) => debug!("message from {}, {} node omitted, {:?}", self.id, self.addr.to_string(), err),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub threshold: u32,
+}
+
+// NOTE(nosiee): the selections are rebuilt only when a node goes up or down, so the std lock
+// is fine here, it's never held across an await
 #[derive(Debug)]
 pub struct NodeCoordinator {
     nodes: Vec<Arc<Node>>,
+    alive: Vec<AtomicBool>,
+    selection: std::sync::RwLock<Selection>,
     subscribes: RwLock<HashMap<String, ()>>,
-    rules: Option<CoodinatorRules>,
+    rules: Option<std::sync::RwLock<CoodinatorRules>>,
+    health: Option<HealthCheck>,
+    primaries: Vec<usize>,
+    primary: AtomicUsize,
+    scheduler: Scheduler,
+    dedup: Arc<Mutex<DedupTable>>,
+    fec: Option<FecConfig>,
 }
 
 impl NodeCoordinator {
-    pub fn new(nodes: Vec<Arc<Node>>) -> Self {
+    pub fn new(nodes: Vec<Arc<Node>>, rules: Option<CoodinatorRules>, health: Option<HealthCheck>) -> Self {
+        let selection = Selection::new(&nodes, (0..nodes.len()).collect(), None).expect("at least one node must have a non-zero weight");
+
         NodeCoordinator {
+            alive: nodes.iter().map(|_| AtomicBool::new(true)).collect(),
             nodes,
-            rules: None,
+            selection: std::sync::RwLock::new(selection),
+            rules: rules.map(std::sync::RwLock::new),
+            health,
+            primaries: Vec::new(),
+            primary: AtomicUsize::new(0),
+            scheduler: Scheduler::default(),
+            dedup: Arc::new(Mutex::new(DedupTable::new())),
+            fec: None,
             subscribes: RwLock::new(HashMap::new()),
         }
     }
 
+    // NOTE(nosiee): the primaries are indexes into the nodes, in the order of preference.
+    // the first one is active on start, the tunnels are expected to be stamped with it already
+    pub fn set_primaries(mut self, primaries: Vec<usize>) -> Self {
+        if let Some(&primary) = primaries.first() {
+            self.primary = AtomicUsize::new(primary);
+        }
+
+        self.primaries = primaries;
+        self
+    }
+
+    pub fn set_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn set_fec(mut self, fec: FecConfig) -> Self {
+        self.fec = Some(fec);
+        self
+    }
+
     pub fn forward(self: Arc<Self>) -> (Sender<Message>, Receiver<Message>) {
         let (itx, irx): (Sender<Message>, Receiver<Message>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
         let (otx, orx): (Sender<Message>, Receiver<Message>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
         let self_c = self.clone();
 
+        if let Some(health) = self.health {
+            self.clone().health_check(health, itx.clone());
+        }
+
+        let writers: HashMap<String, Sender<NodeMessage>> = self
+            .nodes
+            .iter()
+            .map(|node| (node.id.clone(), self.clone().writer(node.clone(), itx.clone())))
+            .collect();
+
         tokio::spawn(async move {
-            while let Ok(payload) = orx.recv().await {
-                let node = self_c.pick_node();
-                debug!("{}, {} node picked", node.id, node.addr.to_string());
+            let mut sequencer = FlowSequencer::new();
+            let mut encoder = self_c.fec.map(FecEncoder::new);
+            let mut client_sequence: u64 = 0;
+
+            loop {
+                let deadline = encoder.as_ref().and_then(|encoder| encoder.deadline());
+                let message = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline.into(), orx.recv()).await {
+                        Ok(message) => message,
+                        Err(_) => {
+                            if let Some(encoder) = encoder.as_mut() {
+                                self_c.send_parity(encoder.expired(), &writers);
+                            }
+
+                            continue;
+                        }
+                    },
+                    None => orx.recv().await,
+                };
 
-                if let Err(err) = node.tunnel.send(&payload).await {
-                    error!("failed to send payload to {}: {:?}", node.addr.to_string(), err);
+                let payload = match message {
+                    Ok(payload) => payload,
+                    Err(_) => break,
+                };
+
+                if device::util::is_link_scoped(&payload) {
+                    debug!("{} packet omitted, link scoped destination", hex::encode(&payload));
                     continue;
                 }
 
-                debug!("{} bytes written to {}", payload.len(), node.addr.to_string());
-                self_c.subscribe_to_node(node, itx.clone()).await;
+                let flow = device::util::get_flow(&payload);
+                let nodes = self_c.pick_nodes(flow.as_ref());
+
+                client_sequence = (client_sequence % MAX_SEQUENCE) + 1;
+                let flow_sequence = sequencer.next(flow.as_ref());
+                let mut frame = HeaderFrame::new(FrameKind::Data)
+                    .set_flow_sequence(flow_sequence)
+                    .set_redundancy(u8::try_from(nodes.len()).unwrap_or(u8::MAX))
+                    .set_client_sequence(client_sequence);
+
+                if let Some((group_id, group_index)) = encoder.as_mut().and_then(|encoder| encoder.push(&payload, flow_sequence)) {
+                    frame = frame.set_group(group_id, group_index);
+                }
+
+                for node in nodes {
+                    enqueue(&writers, &node, payload.clone(), frame.clone());
+                }
+
+                if let Some(encoder) = encoder.as_mut() {
+                    self_c.send_parity(encoder.ready(), &writers);
+                }
             }
         });
 
         (otx, irx)
     }
 
+    // NOTE(nosiee): every node is written from its own task, so a node that doesn't answer the handshake
+    // holds up only its own packets. the order of the packets to a node is kept
+    fn writer(self: Arc<Self>, node: Arc<Node>, itx: Sender<Message>) -> Sender<NodeMessage> {
+        let (tx, rx): (Sender<NodeMessage>, Receiver<NodeMessage>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            while let Ok((payload, frame)) = rx.recv().await {
+                self.send_to_node(node.clone(), &payload, frame, &itx).await;
+            }
+        });
+
+        tx
+    }
+
+    async fn send_to_node(&self, node: Arc<Node>, payload: &[u8], frame: HeaderFrame, itx: &Sender<Message>) {
+        debug!("{}, {} node picked", node.id, node.addr.to_string());
+
+        if let Err(err) = node.tunnel.send(payload, frame).await {
+            error!("failed to send payload to {}: {:?}", node.addr.to_string(), err);
+            return;
+        }
+
+        debug!("{} bytes written to {}", payload.len(), node.addr.to_string());
+        self.subscribe_to_node(node, itx.clone()).await;
+    }
+
+    // NOTE(nosiee): the parity packets of a group go through the distinct nodes of the default selection,
+    // so a single dead path doesn't take the parity along with the data
+    fn send_parity(&self, parities: Vec<Parity>, writers: &HashMap<String, Sender<NodeMessage>>) {
+        if parities.is_empty() {
+            return;
+        }
+
+        let picked = self.selection.read().unwrap().pick_distinct(Vec::new(), parities.len());
+        for (i, parity) in parities.into_iter().enumerate() {
+            let node = &self.nodes[picked[i % picked.len()]];
+            let frame = HeaderFrame::new(FrameKind::Parity)
+                .set_group(parity.group_id, parity.index)
+                .set_group_size(parity.data, parity.parity);
+
+            enqueue(writers, node, parity.payload, frame);
+        }
+    }
+
+    // NOTE(nosiee): every node gets keepalives, even the one the random pick skips for a while,
+    // otherwise the NAT mapping expires and the node responses never reach us
+    pub fn keepalive(self: Arc<Self>, interval: Duration) {
+        for node in self.nodes.iter().cloned() {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+
+                loop {
+                    ticker.tick().await;
+
+                    if let Err(err) = node.tunnel.send_keepalive().await {
+                        error!("failed to send keepalive to {}: {:?}", node.addr.to_string(), err);
+                        continue;
+                    }
+
+                    debug!("keepalive sent to {}, {} node", node.id, node.addr.to_string());
+                }
+            });
+        }
+    }
+
+    // NOTE(nosiee): a probe is answered by the node itself, so a missed reply means either the node or
+    // the path is dead. after `threshold` missed probes in a row the node is removed from all selections
+    // and its session is dropped, the next probe makes a new handshake, so a restarted node is picked up
+    fn health_check(self: Arc<Self>, health: HealthCheck, itx: Sender<Message>) {
+        for i in 0..self.nodes.len() {
+            let self_c = self.clone();
+            let itx = itx.clone();
+
+            tokio::spawn(async move {
+                let node = self_c.nodes[i].clone();
+                let mut ticker = tokio::time::interval(health.interval);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                let mut probe_id: u64 = 0;
+                let mut missed: u32 = 0;
+
+                loop {
+                    ticker.tick().await;
+
+                    if probe_id != 0 {
+                        if node.tunnel.probe_ack() >= probe_id {
+                            missed = 0;
+                            self_c.set_alive(i, true);
+                        } else {
+                            missed = missed.saturating_add(1);
+                            debug!(
+                                "probe {} to {}, {} node missed, {} in a row",
+                                probe_id,
+                                node.id,
+                                node.addr.to_string(),
+                                missed
+                            );
+
+                            if missed >= health.threshold {
+                                self_c.set_alive(i, false);
+                                node.tunnel.reset();
+                            }
+                        }
+                    }
+
+                    probe_id += 1;
+                    if let Err(err) = node.tunnel.send_probe(probe_id).await {
+                        error!("failed to send probe to {}: {:?}", node.addr.to_string(), err);
+                        continue;
+                    }
+
+                    self_c.subscribe_to_node(node.clone(), itx.clone()).await;
+                }
+            });
+        }
+    }
+
+    fn set_alive(&self, i: usize, alive: bool) {
+        if self.alive[i].swap(alive, Ordering::AcqRel) == alive {
+            return;
+        }
+
+        let node = &self.nodes[i];
+        if alive {
+            info!("{}, {} node is up, added back to the selection", node.id, node.addr.to_string());
+        } else {
+            warn!("{}, {} node is down, removed from the selection", node.id, node.addr.to_string());
+        }
+
+        // NOTE(nosiee): the snapshot is taken under the lock, so concurrent transitions can't
+        // leave the selections built from an older state
+        let mut selection = self.selection.write().unwrap();
+        let alive: Vec<bool> = self.alive.iter().map(|alive| alive.load(Ordering::Acquire)).collect();
+        selection.rebuild(&self.nodes, &alive);
+
+        if let Some(rules) = self.rules.as_ref() {
+            rules.write().unwrap().rebuild(&self.nodes, &alive);
+        }
+
+        self.failover(&alive);
+    }
+
+    // NOTE(nosiee): the most preferred alive primary is always the active one, so the client
+    // switches back once a higher primary recovers. if all of them are down nothing changes
+    fn failover(&self, alive: &[bool]) {
+        let next = match self.primaries.iter().find(|&&i| alive[i]) {
+            Some(&next) => next,
+            None => return,
+        };
+
+        let current = self.primary.swap(next, Ordering::AcqRel);
+        if current == next {
+            return;
+        }
+
+        let (current, next) = (&self.nodes[current], &self.nodes[next]);
+        warn!(
+            "primary node switched from {}, {} to {}, {}",
+            current.id,
+            current.addr.to_string(),
+            next.id,
+            next.addr.to_string()
+        );
+
+        // NOTE(nosiee): the responses are numbered by the primary node, the new one starts its own sequences
+        // for the client. the old windows would drop its responses as the copies until they caught up
+        self.dedup.lock().unwrap().clear();
+
+        for node in self.nodes.iter() {
+            node.tunnel.switch_primary_node(next.addr);
+        }
+    }
+
     async fn subscribe_to_node(&self, node: Arc<Node>, itx: Sender<Message>) {
         let r_guard = self.subscribes.read().await;
         let node_id = node.id.clone();
@@ -65,43 +389,70 @@ impl NodeCoordinator {
         if r_guard.get(&node_id).is_none() {
             drop(r_guard);
 
+            // NOTE(nosiee): the writer and the health check subscribe concurrently, the one that
+            // inserts first spawns the reader
             let mut w_guard = self.subscribes.write().await;
-            w_guard.insert(node_id, ());
+            if w_guard.insert(node_id, ()).is_some() {
+                return;
+            }
+
             debug!("subscribed to {}, {} node", node.id, node.addr.to_string());
+            let dedup = self.dedup.clone();
 
             tokio::spawn(async move {
                 loop {
                     let mut buffer = BytesMut::zeroed(node.max_fragment_size);
 
-                    if let Ok(n) = node.tunnel.recv(&mut buffer).await {
-                        buffer.truncate(n);
-                        debug!("{} bytes read from {}", n, node.addr.to_string());
+                    let (n, frame) = node.recv(&mut buffer).await;
+                    buffer.truncate(n);
+                    debug!("{} bytes read from {}", n, node.addr.to_string());
 
-                        if let Err(err) = itx.send(buffer.freeze()).await {
-                            panic!("{}", err);
-                        }
+                    if !accept_response(&dedup, &buffer, frame.client_sequence) {
+                        debug!(
+                            "{} packet omitted, {} client sequence duplicated",
+                            hex::encode(&buffer),
+                            frame.client_sequence
+                        );
+                        continue;
+                    }
+
+                    if let Err(err) = itx.send(buffer.freeze()).await {
+                        panic!("{}", err);
                     }
                 }
             });
         }
     }
 
-    fn pick_node(&self) -> Arc<Node> {
-        if self.rules.is_some() {
-            return self.pick_policy_node();
-        }
+    fn pick_nodes(&self, flow: Option<&Flow>) -> Vec<Arc<Node>> {
+        let picked = match self.rules.as_ref() {
+            Some(rules) => Self::pick_from(&self.scheduler, rules.read().unwrap().select(flow), flow),
+            None => Self::pick_from(&self.scheduler, &self.selection.read().unwrap(), flow),
+        };
 
-        self.pick_random_node()
+        picked.into_iter().map(|i| self.nodes[i].clone()).collect()
     }
 
-    fn pick_random_node(&self) -> Arc<Node> {
-        let n = self.nodes.len();
-        let rand_n = rand::random_range(0..n);
+    fn pick_from(scheduler: &Scheduler, selection: &Selection, flow: Option<&Flow>) -> Vec<usize> {
+        selection.pick_redundant(scheduler.schedule(selection, flow))
+    }
+}
 
-        self.nodes[rand_n].clone()
+fn accept_response(dedup: &Mutex<DedupTable>, payload: &[u8], sequence: u64) -> bool {
+    match device::util::get_destination_addr(payload) {
+        Some(destination) if sequence != 0 => dedup.lock().unwrap().accept(destination, sequence),
+        _ => true,
     }
+}
 
-    fn pick_policy_node(&self) -> Arc<Node> {
-        self.nodes[0].clone()
+// NOTE(nosiee): the packet is dropped if the node falls behind, the other nodes go on
+fn enqueue(writers: &HashMap<String, Sender<NodeMessage>>, node: &Node, payload: Message, frame: HeaderFrame) {
+    if let Err(err) = writers[&node.id].try_send((payload, frame)) {
+        debug!(
+            "{} packet omitted, the queue of {}, {} node is full",
+            hex::encode(&err.into_inner().0),
+            node.id,
+            node.addr.to_string()
+        );
     }
 }
@@ -1,2 +1,387 @@
+use anyhow::anyhow;
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::sync::Arc;
+
+use super::Node;
+use super::alias::AliasTable;
+use super::domain::DomainTable;
+use crate::config::{ClientRules, PolicyRule};
+use crate::device::util::Flow;
+
+// NOTE(nosiee): the candidates are all nodes matched by the rule, the first `count` alive of them
+// are used. if all of them are down we still use them, it's better than dropping everything
 #[derive(Debug, Clone)]
-pub struct CoodinatorRules;
+pub struct Selection {
+    pub nodes: Vec<usize>,
+    candidates: Vec<usize>,
+    count: Option<usize>,
+    redundancy: usize,
+    table: AliasTable,
+}
+
+impl Selection {
+    pub fn new(nodes: &[Arc<Node>], candidates: Vec<usize>, count: Option<usize>) -> Option<Self> {
+        let alive = vec![true; nodes.len()];
+        let (selected, table) = Self::build(nodes, &candidates, count, &alive)?;
+
+        Some(Self {
+            nodes: selected,
+            candidates,
+            count,
+            redundancy: 1,
+            table,
+        })
+    }
+
+    pub fn redundancy(&self) -> Option<u8> {
+        u8::try_from(self.redundancy).ok()
+    }
+
+    pub fn set_redundancy(mut self, redundancy: Option<u8>) -> Self {
+        self.redundancy = redundancy.unwrap_or(1).max(1) as usize;
+        self
+    }
+
+    pub fn pick(&self) -> usize {
+        self.nodes[self.table.sample()]
+    }
+
+    pub fn pick_hashed(&self, hash: u64) -> usize {
+        self.nodes[self.table.sample_hashed(hash)]
+    }
+
+    // NOTE(nosiee): the first node is already picked by the scheduler, the rest of the `redundancy`
+    // nodes are distinct weighted picks
+    pub fn pick_redundant(&self, first: usize) -> Vec<usize> {
+        self.pick_distinct(vec![first], self.redundancy)
+    }
+
+    // NOTE(nosiee): if the rng keeps hitting the same nodes, the rest are taken in order
+    pub fn pick_distinct(&self, mut picked: Vec<usize>, count: usize) -> Vec<usize> {
+        let count = count.min(self.nodes.len()).max(1);
+
+        for _ in 0..count * 4 {
+            if picked.len() >= count {
+                break;
+            }
+
+            let i = self.pick();
+            if !picked.contains(&i) {
+                picked.push(i);
+            }
+        }
+
+        for &i in self.nodes.iter() {
+            if picked.len() >= count {
+                break;
+            }
+
+            if !picked.contains(&i) {
+                picked.push(i);
+            }
+        }
+
+        picked
+    }
+
+    pub fn rebuild(&mut self, nodes: &[Arc<Node>], alive: &[bool]) {
+        let all_alive = vec![true; nodes.len()];
+        let rebuilt =
+            Self::build(nodes, &self.candidates, self.count, alive).or_else(|| Self::build(nodes, &self.candidates, self.count, &all_alive));
+
+        if let Some((selected, table)) = rebuilt {
+            self.nodes = selected;
+            self.table = table;
+        }
+    }
+
+    fn build(nodes: &[Arc<Node>], candidates: &[usize], count: Option<usize>, alive: &[bool]) -> Option<(Vec<usize>, AliasTable)> {
+        let selected: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&i| alive[i])
+            .take(count.unwrap_or(usize::MAX))
+            .collect();
+
+        let weights: Vec<u32> = selected.iter().map(|&i| nodes[i].weight).collect();
+        let table = AliasTable::new(&weights)?;
+
+        Some((selected, table))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    destination: Vec<IpNetwork>,
+    domains: Vec<String>,
+    ports: Vec<u16>,
+    protocol: Option<IpNextHeaderProtocol>,
+    selection: Selection,
+}
+
+// NOTE(nosiee): the rules are checked in the config order, the first matched rule wins.
+// a packet that doesn't match any rule is sprayed over the default selection. the bypass rules
+// are routed around the device, they never get here
+#[derive(Debug, Clone)]
+pub struct CoodinatorRules {
+    rules: Vec<Rule>,
+    default: Selection,
+    domains: Arc<DomainTable>,
+}
+
+impl CoodinatorRules {
+    pub fn new(config: &ClientRules, nodes: &[Arc<Node>]) -> anyhow::Result<Self> {
+        let all: Vec<usize> = (0..nodes.len()).collect();
+        let default = select_nodes(nodes, &all, None, config.tunnels.as_deref(), config.nodes)?.set_redundancy(config.redundancy);
+
+        let mut rules = Vec::new();
+        for policy in config.policy.iter().flatten().filter(|policy| !policy.bypass.unwrap_or_default()) {
+            rules.push(Rule::new(policy, nodes, &default)?);
+        }
+
+        Ok(Self {
+            rules,
+            default,
+            domains: Arc::new(DomainTable::new(Vec::new(), None)),
+        })
+    }
+
+    pub fn set_domains(mut self, domains: Arc<DomainTable>) -> Self {
+        self.domains = domains;
+        self
+    }
+
+    pub fn rebuild(&mut self, nodes: &[Arc<Node>], alive: &[bool]) {
+        self.default.rebuild(nodes, alive);
+
+        for rule in self.rules.iter_mut() {
+            rule.selection.rebuild(nodes, alive);
+        }
+    }
+
+    pub fn select(&self, flow: Option<&Flow>) -> &Selection {
+        let flow = match flow {
+            Some(flow) => flow,
+            None => return &self.default,
+        };
+
+        match self.rules.iter().find(|rule| rule.matches(flow, &self.domains)) {
+            Some(rule) => &rule.selection,
+            None => &self.default,
+        }
+    }
+}
+
+impl Rule {
+    fn new(policy: &PolicyRule, nodes: &[Arc<Node>], default: &Selection) -> anyhow::Result<Self> {
+        let mut destination = Vec::new();
+        for net in policy.destination.iter().flatten() {
+            destination.push(net.parse()?);
+        }
+
+        let protocol = match policy.protocol.as_deref() {
+            Some(protocol) => Some(parse_protocol(protocol)?),
+            None => None,
+        };
+
+        let selection = if policy.tags.is_none() && policy.tunnels.is_none() {
+            select_nodes(nodes, &default.candidates, None, None, policy.nodes)?
+        } else {
+            let all: Vec<usize> = (0..nodes.len()).collect();
+            select_nodes(nodes, &all, policy.tags.as_deref(), policy.tunnels.as_deref(), policy.nodes)?
+        };
+        let selection = selection.set_redundancy(policy.redundancy.or(default.redundancy()));
+
+        Ok(Self {
+            destination,
+            domains: policy.domains.clone().unwrap_or_default(),
+            ports: policy.ports.clone().unwrap_or_default(),
+            protocol,
+            selection,
+        })
+    }
+
+    // NOTE(nosiee): the domains match the destinations they were resolved to, only while the ttl lasts
+    fn matches(&self, flow: &Flow, domains: &DomainTable) -> bool {
+        if !self.destination.is_empty() && !self.destination.iter().any(|net| net.contains(flow.destination)) {
+            return false;
+        }
+
+        if !self.domains.is_empty() && !domains.matches(flow.destination, &self.domains) {
+            return false;
+        }
+
+        if let Some(protocol) = self.protocol
+            && protocol != flow.protocol
+        {
+            return false;
+        }
+
+        if !self.ports.is_empty() {
+            return match flow.destination_port {
+                Some(port) => self.ports.contains(&port),
+                None => false,
+            };
+        }
+
+        true
+    }
+}
+
+fn select_nodes(
+    nodes: &[Arc<Node>],
+    candidates: &[usize],
+    tags: Option<&[String]>,
+    tunnels: Option<&[String]>,
+    count: Option<u64>,
+) -> anyhow::Result<Selection> {
+    let selected: Vec<usize> = candidates
+        .iter()
+        .copied()
+        .filter(|&i| tunnels.is_none_or(|tunnels| tunnels.contains(&nodes[i].id)))
+        .filter(|&i| tags.is_none_or(|tags| nodes[i].tags.iter().any(|tag| tags.contains(tag))))
+        .collect();
+
+    Selection::new(nodes, selected, count.map(|count| count as usize))
+        .ok_or_else(|| anyhow!("no nodes with non-zero weight match the rule, tags: {:?}, tunnels: {:?}", tags, tunnels))
+}
+
+fn parse_protocol(protocol: &str) -> anyhow::Result<IpNextHeaderProtocol> {
+    let protocol = match protocol.to_lowercase().as_str() {
+        "tcp" => IpNextHeaderProtocols::Tcp,
+        "udp" => IpNextHeaderProtocols::Udp,
+        "icmp" => IpNextHeaderProtocols::Icmp,
+        "icmpv6" => IpNextHeaderProtocols::Icmpv6,
+        n => IpNextHeaderProtocol(n.parse().map_err(|_| anyhow!("unknown protocol: {}", protocol))?),
+    };
+
+    Ok(protocol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnels::outgoing::OutgoingTunnel;
+    use std::net::IpAddr;
+
+    fn nodes() -> Vec<Arc<Node>> {
+        [("a", "eu", 1), ("b", "eu", 1), ("c", "us", 1), ("d", "us", 0)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (id, tag, weight))| {
+                Arc::new(Node {
+                    id: id.to_string(),
+                    addr: format!("192.0.2.{}:9000", i + 1).parse().unwrap(),
+                    tags: vec![tag.to_string()],
+                    weight,
+                    tunnel: OutgoingTunnel::new(),
+                    max_fragment_size: 1500,
+                })
+            })
+            .collect()
+    }
+
+    fn rules(config: &str) -> anyhow::Result<CoodinatorRules> {
+        CoodinatorRules::new(&toml::from_str(config).unwrap(), &nodes())
+    }
+
+    fn flow(destination: &str, protocol: IpNextHeaderProtocol, port: Option<u16>) -> Flow {
+        Flow {
+            source: "10.0.0.2".parse().unwrap(),
+            destination: destination.parse::<IpAddr>().unwrap(),
+            protocol,
+            source_port: port.map(|_| 40000),
+            destination_port: port,
+        }
+    }
+
+    const CONFIG: &str = r#"
+        [[policy]]
+        destination = ["10.1.0.0/16"]
+        bypass = true
+
+        [[policy]]
+        destination = ["198.51.100.0/24"]
+        protocol = "tcp"
+        ports = [443]
+        tags = ["us"]
+
+        [[policy]]
+        destination = ["198.51.100.0/24"]
+        tunnels = ["b"]
+
+        [[policy]]
+        protocol = "udp"
+        nodes = 1
+    "#;
+
+    #[test]
+    fn picks_the_first_matched_rule() {
+        let rules = rules(CONFIG).unwrap();
+
+        let https = flow("198.51.100.1", IpNextHeaderProtocols::Tcp, Some(443));
+        assert_eq!(rules.select(Some(&https)).nodes, vec![2, 3]);
+        assert!((0..100).all(|_| rules.select(Some(&https)).pick() == 2));
+
+        let http = flow("198.51.100.1", IpNextHeaderProtocols::Tcp, Some(80));
+        assert_eq!(rules.select(Some(&http)).nodes, vec![1]);
+
+        let dns = flow("1.1.1.1", IpNextHeaderProtocols::Udp, Some(53));
+        assert_eq!(rules.select(Some(&dns)).nodes, vec![0]);
+    }
+
+    #[test]
+    fn falls_back_to_the_default() {
+        let rules = rules(CONFIG).unwrap();
+
+        let bypassed = flow("10.1.0.1", IpNextHeaderProtocols::Tcp, Some(443));
+        assert_eq!(rules.select(Some(&bypassed)).nodes, vec![0, 1, 2, 3]);
+
+        let icmp = flow("198.51.100.1", IpNextHeaderProtocols::Icmp, None);
+        assert_eq!(rules.select(Some(&icmp)).nodes, vec![1]);
+
+        assert_eq!(rules.select(None).nodes, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn needs_a_node_with_weight() {
+        assert!(rules("[[policy]]\ntunnels = [\"d\"]").is_err());
+        assert!(rules("[[policy]]\ntags = [\"asia\"]").is_err());
+        assert!(rules("[[policy]]\nprotocol = \"olla\"").is_err());
+    }
+
+    #[test]
+    fn parses_protocols() {
+        assert_eq!(parse_protocol("TCP").unwrap(), IpNextHeaderProtocols::Tcp);
+        assert_eq!(parse_protocol("icmpv6").unwrap(), IpNextHeaderProtocols::Icmpv6);
+        assert_eq!(parse_protocol("132").unwrap(), IpNextHeaderProtocol(132));
+        assert!(parse_protocol("256").is_err());
+    }
+
+    #[test]
+    fn evicts_dead_nodes() {
+        let nodes = nodes();
+        let mut selection = Selection::new(&nodes, vec![0, 1, 2], Some(2)).unwrap();
+        assert_eq!(selection.nodes, vec![0, 1]);
+
+        selection.rebuild(&nodes, &[false, true, true, true]);
+        assert_eq!(selection.nodes, vec![1, 2]);
+        assert!((0..100).all(|_| selection.pick() != 0));
+
+        selection.rebuild(&nodes, &[true, true, true, true]);
+        assert_eq!(selection.nodes, vec![0, 1]);
+    }
+
+    #[test]
+    fn keeps_the_nodes_when_all_are_dead() {
+        let nodes = nodes();
+        let mut selection = Selection::new(&nodes, vec![0, 1, 3], None).unwrap();
+
+        selection.rebuild(&nodes, &[false, false, true, true]);
+        assert_eq!(selection.nodes, vec![0, 1, 3]);
+
+        selection.rebuild(&nodes, &[false, false, false, false]);
+        assert_eq!(selection.nodes, vec![0, 1, 3]);
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::rule::Selection;
+use crate::config::SchedulingConfig;
+use crate::device::util::Flow;
+
+pub const DEFAULT_FLOWLET_GAP: Duration = Duration::from_millis(50);
+const FLOWLET_TABLE_SIZE: usize = 0x10000;
+
+// NOTE(nosiee): per packet spraying is the fastest for udp, but the paths have different latencies
+// and tcp sees the segments reordered. a flow pinned to a single node never reorders, a flowlet
+// may move to another node only after an idle gap longer than the latency difference of the paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Packet,
+    Flow,
+    Flowlet(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Flowlet {
+    node: usize,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    mode: Mode,
+    flowlets: Mutex<HashMap<Flow, Flowlet>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(Mode::Packet)
+    }
+}
+
+impl Scheduler {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            flowlets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &SchedulingConfig) -> anyhow::Result<Self> {
+        let mode = match config.mode.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("packet") => Mode::Packet,
+            Some("flow") => Mode::Flow,
+            Some("flowlet") => Mode::Flowlet(config.flowlet_gap.map(Duration::from_millis).unwrap_or(DEFAULT_FLOWLET_GAP)),
+            Some(mode) => return Err(anyhow!("unknown scheduling mode: {}", mode)),
+        };
+
+        Ok(Self::new(mode))
+    }
+
+    // NOTE(nosiee): the packets without a flow (fragments, unknown protocols) are always sprayed
+    pub fn schedule(&self, selection: &Selection, flow: Option<&Flow>) -> usize {
+        let flow = match flow {
+            Some(flow) => flow,
+            None => return selection.pick(),
+        };
+
+        match self.mode {
+            Mode::Packet => selection.pick(),
+            Mode::Flow => selection.pick_hashed(flow_hash(flow)),
+            Mode::Flowlet(gap) => self.schedule_flowlet(selection, flow, gap),
+        }
+    }
+
+    fn schedule_flowlet(&self, selection: &Selection, flow: &Flow, gap: Duration) -> usize {
+        let now = Instant::now();
+        let mut flowlets = self.flowlets.lock().unwrap();
+
+        if let Some(flowlet) = flowlets.get_mut(flow)
+            && now.duration_since(flowlet.last_seen) < gap
+            && selection.nodes.contains(&flowlet.node)
+        {
+            flowlet.last_seen = now;
+            return flowlet.node;
+        }
+
+        if flowlets.len() >= FLOWLET_TABLE_SIZE {
+            flowlets.retain(|_, flowlet| now.duration_since(flowlet.last_seen) < gap);
+        }
+
+        let node = selection.pick();
+        flowlets.insert(*flow, Flowlet { node, last_seen: now });
+
+        node
+    }
+}
+
+fn flow_hash(flow: &Flow) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    flow.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::node::Node;
+    use crate::tunnels::outgoing::OutgoingTunnel;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use std::sync::Arc;
+
+    fn selection(count: usize) -> (Vec<Arc<Node>>, Selection) {
+        let nodes: Vec<Arc<Node>> = (0..count)
+            .map(|i| {
+                Arc::new(Node {
+                    id: i.to_string(),
+                    addr: format!("192.0.2.{}:9000", i + 1).parse().unwrap(),
+                    tags: Vec::new(),
+                    weight: 1,
+                    tunnel: OutgoingTunnel::new(),
+                    max_fragment_size: 1500,
+                })
+            })
+            .collect();
+        let selection = Selection::new(&nodes, (0..count).collect(), None).unwrap();
+
+        (nodes, selection)
+    }
+
+    fn flow(port: u16) -> Flow {
+        Flow {
+            source: "10.0.0.2".parse().unwrap(),
+            destination: "198.51.100.1".parse().unwrap(),
+            protocol: IpNextHeaderProtocols::Tcp,
+            source_port: Some(port),
+            destination_port: Some(443),
+        }
+    }
+
+    fn config(config: &str) -> anyhow::Result<Scheduler> {
+        Scheduler::from_config(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(config("").unwrap().mode, Mode::Packet);
+        assert_eq!(config("mode = \"Flow\"").unwrap().mode, Mode::Flow);
+        assert_eq!(config("mode = \"flowlet\"").unwrap().mode, Mode::Flowlet(DEFAULT_FLOWLET_GAP));
+        assert_eq!(
+            config("mode = \"flowlet\"\nflowlet_gap = 10").unwrap().mode,
+            Mode::Flowlet(Duration::from_millis(10))
+        );
+        assert!(config("mode = \"olla\"").is_err());
+    }
+
+    #[test]
+    fn pins_a_flow() {
+        let (_, selection) = selection(8);
+        let scheduler = Scheduler::new(Mode::Flow);
+
+        for port in 40000..40100 {
+            let node = scheduler.schedule(&selection, Some(&flow(port)));
+            assert!((0..10).all(|_| scheduler.schedule(&selection, Some(&flow(port))) == node));
+        }
+
+        let spread: std::collections::HashSet<usize> = (40000..40100).map(|port| scheduler.schedule(&selection, Some(&flow(port)))).collect();
+        assert!(spread.len() > 1);
+    }
+
+    #[test]
+    fn keeps_a_flowlet_until_the_gap() {
+        let (_, selection) = selection(8);
+        let scheduler = Scheduler::new(Mode::Flowlet(DEFAULT_FLOWLET_GAP));
+
+        let node = scheduler.schedule(&selection, Some(&flow(40000)));
+        assert!((0..100).all(|_| scheduler.schedule(&selection, Some(&flow(40000))) == node));
+
+        let mut moved = false;
+        for _ in 0..100 {
+            let last_seen = Instant::now().checked_sub(DEFAULT_FLOWLET_GAP).unwrap();
+            scheduler.flowlets.lock().unwrap().get_mut(&flow(40000)).unwrap().last_seen = last_seen;
+            moved |= scheduler.schedule(&selection, Some(&flow(40000))) != node;
+        }
+
+        assert!(moved);
+    }
+
+    #[test]
+    fn moves_a_flowlet_off_a_dead_node() {
+        let (nodes, mut selection) = selection(2);
+        let scheduler = Scheduler::new(Mode::Flowlet(Duration::from_secs(60)));
+
+        let node = scheduler.schedule(&selection, Some(&flow(40000)));
+        let mut alive = vec![true; 2];
+        alive[node] = false;
+        selection.rebuild(&nodes, &alive);
+
+        assert_eq!(scheduler.schedule(&selection, Some(&flow(40000))), 1 - node);
+    }
+}
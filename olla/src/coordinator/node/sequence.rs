@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::device::util::Flow;
+
+const FLOW_TABLE_SIZE: usize = 0x10000;
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct FlowState {
+    sequence: u32,
+    last_seen: Instant,
+}
+
+// NOTE(nosiee): numbers the packets of every flow, the primary node uses it to restore the order.
+// it's owned by the single forwarding task, so no locking here. 0 is reserved for the packets without a flow
+#[derive(Debug, Default)]
+pub struct FlowSequencer {
+    flows: HashMap<Flow, FlowState>,
+}
+
+impl FlowSequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next(&mut self, flow: Option<&Flow>) -> u32 {
+        let flow = match flow {
+            Some(flow) => flow,
+            None => return 0,
+        };
+
+        let now = Instant::now();
+        if self.flows.len() >= FLOW_TABLE_SIZE && !self.flows.contains_key(flow) {
+            self.flows.retain(|_, state| now.duration_since(state.last_seen) < FLOW_IDLE_TIMEOUT);
+        }
+
+        let state = self.flows.entry(*flow).or_insert(FlowState { sequence: 0, last_seen: now });
+        state.sequence = match state.sequence.wrapping_add(1) {
+            0 => 1,
+            sequence => sequence,
+        };
+        state.last_seen = now;
+
+        state.sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn flow(port: u16) -> Flow {
+        Flow {
+            source: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            destination: IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+            protocol: IpNextHeaderProtocols::Udp,
+            source_port: Some(port),
+            destination_port: Some(53),
+        }
+    }
+
+    #[test]
+    fn numbers_every_flow() {
+        let mut sequencer = FlowSequencer::new();
+
+        assert_eq!(sequencer.next(Some(&flow(40000))), 1);
+        assert_eq!(sequencer.next(Some(&flow(40000))), 2);
+        assert_eq!(sequencer.next(Some(&flow(40001))), 1);
+        assert_eq!(sequencer.next(None), 0);
+    }
+
+    #[test]
+    fn skips_zero_on_wraparound() {
+        let mut sequencer = FlowSequencer::new();
+        sequencer.flows.insert(
+            flow(40000),
+            FlowState {
+                sequence: u32::MAX,
+                last_seen: Instant::now(),
+            },
+        );
+
+        assert_eq!(sequencer.next(Some(&flow(40000))), 1);
+    }
+}
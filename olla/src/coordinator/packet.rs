@@ -1,47 +1,130 @@
 use async_channel::{Receiver, Sender};
 use bytes::BytesMut;
+use pnet::packet::ip::IpNextHeaderProtocols;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tracing::{debug, error};
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info};
 
-use crate::device::{DEVICE_BUFFER_SIZE, Message};
+use crate::device::{DEVICE_BUFFER_SIZE, Message, nat};
 use crate::tunnels::errors::{NO_PEER_FOUND, TunnelError};
-use crate::tunnels::header::{self, HEADER_SIZE};
-
+use crate::tunnels::header::{self, FrameKind, HEADER_SIZE, HeaderFrame, MAX_SEQUENCE};
+use crate::tunnels::incoming::Peer;
+use crate::tunnels::noise;
+use crate::tunnels::replay::ReplayWindow;
+
+use super::conntrack::{ConntrackConfig, CoordinationTable, SWEEP_INTERVAL};
+use super::control::{Authority, ControlTable, Tenant};
+use super::dedup::DedupWindow;
+use super::fec::{FecDecoder, RebuiltMessage};
+use super::fragment::FragmentCache;
+use super::lease::LeaseTable;
+use super::masquerade::Masquerade;
 use super::node::Node;
+use super::reorder::{ReorderBuffer, ReorderConfig, ReorderMessage};
+use super::resolver;
+use super::snat::SnatTable;
 use crate::device;
+use crate::device::util::Fragment;
 
 pub type PacketCoordinatorMessage = (String, Message);
+pub type PeerMessage = (Peer, Message);
+
+const PRIMARY_ROUTE_TIMEOUT: Duration = Duration::from_secs(300);
+// NOTE(nosiee): the state kept for a client address, its owner and its sequences, is forgotten once it's idle that long
+const TENANT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// NOTE(nosiee): the receive loop of the primary node is stopped once nothing was routed through it for a while
+#[derive(Debug)]
+struct PrimaryRoute {
+    last_used: std::sync::Mutex<Instant>,
+    task: AbortHandle,
+}
 
 #[derive(Debug)]
 pub struct PacketCoordinator {
-    coordination_table: RwLock<HashMap<String, String>>,
+    coordination_table: RwLock<CoordinationTable>,
+    control_table: Arc<ControlTable>,
+    replay_table: RwLock<HashMap<String, (u32, ReplayWindow, Instant)>>,
+    dedup_table: RwLock<HashMap<(Tenant, IpAddr), (DedupWindow, Instant)>>,
+    client_sequences: RwLock<HashMap<(Tenant, IpAddr), (u64, Instant)>>,
+    tenants: RwLock<HashMap<IpAddr, (Tenant, Instant)>>,
+    snat: Option<RwLock<SnatTable>>,
+    leases: Option<RwLock<LeaseTable>>,
+    resolvers: Vec<IpAddr>,
+    masquerade: Option<Arc<Masquerade>>,
+    fec_decoder: RwLock<FecDecoder>,
+    fragments: RwLock<FragmentCache>,
     nodes: Vec<Arc<Node>>,
-    primary_nodes: RwLock<HashMap<String, ()>>,
+    primary_nodes: RwLock<HashMap<String, PrimaryRoute>>,
+    reorder: Option<ReorderConfig>,
 
-    machine_addr: Ipv4Addr,
+    machine_addrs: Vec<IpAddr>,
 }
 
 impl PacketCoordinator {
-    pub fn new(machine_addr: Ipv4Addr, nodes: Vec<Arc<Node>>) -> Self {
+    pub fn new(machine_addrs: Vec<IpAddr>, nodes: Vec<Arc<Node>>, control_table: Arc<ControlTable>) -> Self {
         Self {
-            coordination_table: RwLock::new(HashMap::new()),
+            coordination_table: RwLock::new(CoordinationTable::new(ConntrackConfig::default())),
+            control_table,
+            replay_table: RwLock::new(HashMap::new()),
+            dedup_table: RwLock::new(HashMap::new()),
+            client_sequences: RwLock::new(HashMap::new()),
+            tenants: RwLock::new(HashMap::new()),
+            snat: None,
+            leases: None,
+            resolvers: Vec::new(),
+            masquerade: None,
+            fec_decoder: RwLock::new(FecDecoder::new()),
+            fragments: RwLock::new(FragmentCache::new()),
             nodes,
             primary_nodes: RwLock::new(HashMap::new()),
+            reorder: None,
 
-            machine_addr,
+            machine_addrs,
         }
     }
 
+    pub fn set_conntrack(mut self, conntrack: ConntrackConfig) -> Self {
+        self.coordination_table = RwLock::new(CoordinationTable::new(conntrack));
+        self
+    }
+
+    pub fn set_reorder(mut self, reorder: ReorderConfig) -> Self {
+        self.reorder = Some(reorder);
+        self
+    }
+
+    pub fn set_snat(mut self, snat: SnatTable) -> Self {
+        self.snat = Some(RwLock::new(snat));
+        self
+    }
+
+    pub fn set_leases(mut self, leases: LeaseTable) -> Self {
+        self.leases = Some(RwLock::new(leases));
+        self
+    }
+
+    pub fn set_resolvers(mut self, resolvers: Vec<IpAddr>) -> Self {
+        self.resolvers = resolvers;
+        self
+    }
+
+    pub fn set_masquerade(mut self, masquerade: Masquerade) -> Self {
+        self.masquerade = Some(Arc::new(masquerade));
+        self
+    }
+
     pub fn forward(
         self: Arc<Self>,
         tun_dev_tx: Sender<Message>,
         tun_dev_rx: Receiver<Message>,
-    ) -> (Sender<PacketCoordinatorMessage>, Receiver<PacketCoordinatorMessage>) {
+    ) -> (Sender<PeerMessage>, Receiver<PacketCoordinatorMessage>) {
         let (itx, irx): (Sender<PacketCoordinatorMessage>, Receiver<PacketCoordinatorMessage>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
-        let (otx, orx): (Sender<PacketCoordinatorMessage>, Receiver<PacketCoordinatorMessage>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+        let (otx, orx): (Sender<PeerMessage>, Receiver<PeerMessage>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
 
         let cpu_cores = match std::thread::available_parallelism() {
             Ok(n) => n.get(),
@@ -51,15 +134,24 @@ impl PacketCoordinator {
             }
         };
 
+        let (tun_dev_tx, tun_dev_rx) = match self.masquerade.as_ref() {
+            Some(masquerade) => masquerade.clone().forward(tun_dev_tx, tun_dev_rx),
+            None => (tun_dev_tx, tun_dev_rx),
+        };
+
+        self.clone().sweep();
+        let reorder_tx = self.reorder.map(|config| ReorderBuffer::new(config).forward(tun_dev_tx.clone()));
+
         for _ in 0..cpu_cores {
             let self_c = self.clone();
             let itx_c = itx.clone();
             let orx = orx.clone();
             let tun_dev_tx = tun_dev_tx.clone();
+            let reorder_tx = reorder_tx.clone();
 
             tokio::spawn(async move {
                 while let Ok((peer, mut payload)) = orx.recv().await {
-                    if payload.len() <= HEADER_SIZE {
+                    if payload.len() < HEADER_SIZE {
                         debug!("{} packet omitted, unusual size: {}b", hex::encode(&payload), payload.len());
                         continue;
                     }
@@ -68,8 +160,73 @@ impl PacketCoordinator {
                     let header_buffer: [u8; HEADER_SIZE] = header_buffer[..].try_into().unwrap();
                     let header_frame = header::decode(header_buffer);
 
-                    let identity = match device::util::get_source_identity(&payload) {
-                        Some(identity) => identity,
+                    match header_frame.kind {
+                        FrameKind::Data if payload.is_empty() => {
+                            debug!("empty data frame omitted, peer: {}", peer.addr);
+                            continue;
+                        }
+                        FrameKind::Data => (),
+                        FrameKind::Keepalive => {
+                            debug!("keepalive received from {}", peer.addr);
+                            continue;
+                        }
+                        FrameKind::Probe => {
+                            let reply = header::extend_payload(&payload, &HeaderFrame::new(FrameKind::ProbeReply));
+                            let _ = itx_c.send((peer.addr, reply)).await;
+                            continue;
+                        }
+                        FrameKind::Parity => {
+                            self_c
+                                .clone()
+                                .forward_parity(&peer, &header_frame, &payload, itx_c.clone(), &tun_dev_tx, reorder_tx.as_ref())
+                                .await;
+                            continue;
+                        }
+                        FrameKind::ProbeReply => {
+                            debug!("{} packet omitted, unexpected probe reply from {}", hex::encode(&payload), peer.addr);
+                            continue;
+                        }
+                        FrameKind::LeaseRequest => {
+                            self_c.lease(&peer, &itx_c).await;
+                            continue;
+                        }
+                        FrameKind::LeaseReply => {
+                            debug!("{} packet omitted, unexpected lease reply from {}", hex::encode(&payload), peer.addr);
+                            continue;
+                        }
+                        FrameKind::ResolverRequest => {
+                            self_c.resolvers(&peer, &itx_c).await;
+                            continue;
+                        }
+                        FrameKind::ResolverReply => {
+                            debug!("{} packet omitted, unexpected resolver reply from {}", hex::encode(&payload), peer.addr);
+                            continue;
+                        }
+                        FrameKind::Unknown(kind) => {
+                            debug!("{} packet omitted, unknown frame kind: {}", hex::encode(&payload), kind);
+                            continue;
+                        }
+                    }
+
+                    let tenant = match self_c.authorize(&peer, &payload, header_frame.tenant).await {
+                        Some(tenant) => tenant,
+                        None => {
+                            debug!("{} packet omitted, {} is not allowed to send it", hex::encode(&payload), peer.addr);
+                            continue;
+                        }
+                    };
+
+                    if !self_c.accept_sequence(&peer, header_frame.sequence).await {
+                        debug!("{} packet omitted, {} sequence replayed", hex::encode(&payload), header_frame.sequence);
+                        continue;
+                    }
+
+                    // NOTE(nosiee): a fragment that came before the first one goes on without a coordination,
+                    // the first fragment coordinates the packet once it arrives
+                    let fragment = device::util::get_fragment(&payload);
+                    let identity = match self_c.source_identity(tenant, &payload, fragment.as_ref()).await {
+                        Some(identity) => Some(identity),
+                        None if fragment.is_some() => None,
                         None => {
                             debug!("{} packet omitted, source identity not found", hex::encode(&payload));
                             continue;
@@ -77,116 +234,582 @@ impl PacketCoordinator {
                     };
 
                     debug!(
-                        "{} bytes read from {}, identity: {}, header frame: {:?}",
+                        "{} bytes read from {}, tenant: {:016x}, identity: {:?}, header frame: {:?}",
                         payload.len() + HEADER_SIZE,
-                        peer,
+                        peer.addr,
+                        tenant,
                         identity,
                         header_frame
                     );
 
-                    if !header_frame.primary_node_ip.is_unspecified() && (header_frame.primary_node_ip != self_c.machine_addr) {
-                        let primary_node_addr = SocketAddr::new(IpAddr::V4(header_frame.primary_node_ip), header_frame.primary_node_port);
+                    if let Some(primary_node_addr) = self_c.primary_node(&header_frame) {
                         let self_c = self_c.clone();
-
-                        self_c.route_to(primary_node_addr, &payload, itx_c.clone()).await.unwrap();
+                        if let Err(err) = self_c
+                            .route_to(primary_node_addr, &payload, relay_frame(&header_frame).set_tenant(tenant), itx_c.clone())
+                            .await
+                        {
+                            error!(
+                                "{} packet omitted, failed to route to {}: {:?}",
+                                hex::encode(&payload),
+                                primary_node_addr,
+                                err
+                            );
+                        }
+                    } else if !self_c.accept_client_sequence(tenant, &payload, header_frame.client_sequence).await {
+                        debug!(
+                            "{} packet omitted, {} client sequence duplicated",
+                            hex::encode(&payload),
+                            header_frame.client_sequence
+                        );
                     } else {
-                        let _ = tun_dev_tx.send(payload).await;
+                        let rebuilt = self_c.fec_decoder.write().await.push_data(tenant, &header_frame, &payload);
+
+                        if let Some(rebuilt) = rebuilt {
+                            if let Some(translated) = self_c.translate(tenant, payload.clone()).await {
+                                write_local(translated, header_frame.flow_sequence, &tun_dev_tx, reorder_tx.as_ref()).await;
+                            }
+                            self_c
+                                .write_rebuilt(&peer, tenant, rebuilt, header_frame.redundancy, &tun_dev_tx, reorder_tx.as_ref())
+                                .await;
+                        }
                     }
 
-                    // FIXME(nosiee): we need to use, let's call it a contolling table
-                    // something that wireguard do. each client has an id and allowed ip from
-                    // private network. the range is unique for each client
-                    // after the packet from client arrived here and we validate ip/client_id
-                    // we can create the peers mapping and to be sure that there is no other
-                    // way to overwrite it only if an attacker have client_id and ip range
-                    self_c.add_coordination(identity, peer).await;
+                    if let Some(identity) = identity {
+                        self_c
+                            .add_coordination(tenant, identity, peer.addr, header_frame.redundancy, &payload)
+                            .await;
+                    }
                 }
             });
         }
 
         tokio::spawn(async move {
             while let Ok(payload) = tun_dev_rx.recv().await {
-                let identity = match device::util::get_destination_identity(&payload) {
-                    Some(identity) => identity,
-                    None => {
-                        debug!("{} packet omitted, destination identity not found", hex::encode(&payload));
-                        continue;
-                    }
-                };
-
-                match self.get_coordination(&identity).await {
-                    Some(peer) => {
-                        itx.send((peer, payload)).await.unwrap();
-                    }
-                    None => debug!("{} packet omitted, coordination not found", hex::encode(&payload)),
-                }
+                let (tenant, payload) = self.restore(payload).await;
+                let frame = HeaderFrame::new(FrameKind::Data).set_client_sequence(self.next_client_sequence(tenant, &payload).await);
+                self.write_response(tenant, payload, frame, &itx).await;
             }
         });
 
         (otx, irx)
     }
 
-    async fn route_to(self: Arc<Self>, addr: SocketAddr, payload: &[u8], itx: Sender<PacketCoordinatorMessage>) -> anyhow::Result<(), TunnelError> {
+    fn primary_node(&self, frame: &HeaderFrame) -> Option<SocketAddr> {
+        if frame.primary_node_ip.is_unspecified() || self.machine_addrs.contains(&frame.primary_node_ip) {
+            return None;
+        }
+
+        Some(SocketAddr::new(frame.primary_node_ip, frame.primary_node_port))
+    }
+
+    // NOTE(nosiee): the parity carries no ip packet, so only the peer key is checked. the rebuilt packets
+    // are authorized the same way as the received ones, against the peer that completed the group
+    async fn forward_parity(
+        self: Arc<Self>,
+        peer: &Peer,
+        frame: &HeaderFrame,
+        payload: &[u8],
+        itx: Sender<PacketCoordinatorMessage>,
+        tun_dev_tx: &Sender<Message>,
+        reorder_tx: Option<&Sender<ReorderMessage>>,
+    ) {
+        let tenant = match self.tenant(peer, frame) {
+            Some(tenant) => tenant,
+            None => {
+                debug!("{} parity omitted, {} is not allowed to send it", hex::encode(payload), peer.addr);
+                return;
+            }
+        };
+
+        if !self.accept_sequence(peer, frame.sequence).await {
+            debug!("{} parity omitted, {} sequence replayed", hex::encode(payload), frame.sequence);
+            return;
+        }
+
+        if let Some(primary_node_addr) = self.primary_node(frame) {
+            if let Err(err) = self
+                .route_to(primary_node_addr, payload, relay_frame(frame).set_tenant(tenant), itx)
+                .await
+            {
+                error!(
+                    "{} parity omitted, failed to route to {}: {:?}",
+                    hex::encode(payload),
+                    primary_node_addr,
+                    err
+                );
+            }
+            return;
+        }
+
+        let rebuilt = self.fec_decoder.write().await.push_parity(tenant, frame, payload);
+        self.write_rebuilt(peer, tenant, rebuilt, frame.redundancy, tun_dev_tx, reorder_tx).await;
+    }
+
+    async fn write_rebuilt(
+        &self,
+        peer: &Peer,
+        tenant: Tenant,
+        rebuilt: Vec<RebuiltMessage>,
+        redundancy: u8,
+        tun_dev_tx: &Sender<Message>,
+        reorder_tx: Option<&Sender<ReorderMessage>>,
+    ) {
+        for (flow_sequence, payload) in rebuilt {
+            if self.authorize(peer, &payload, tenant).await.is_none() {
+                debug!(
+                    "{} rebuilt packet omitted, {} is not allowed to send it",
+                    hex::encode(&payload),
+                    peer.addr
+                );
+                continue;
+            }
+
+            if let Some(identity) = device::util::get_source_identity(&payload) {
+                self.add_coordination(tenant, identity, peer.addr.clone(), redundancy, &payload).await;
+            }
+
+            if let Some(translated) = self.translate(tenant, payload).await {
+                write_local(translated, flow_sequence, tun_dev_tx, reorder_tx).await;
+            }
+        }
+    }
+
+    async fn route_to(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        payload: &[u8],
+        frame: HeaderFrame,
+        itx: Sender<PacketCoordinatorMessage>,
+    ) -> anyhow::Result<(), TunnelError> {
         let node = match self.nodes.iter().find(|n| n.addr == addr) {
             Some(node) => node.clone(),
             None => return Err(TunnelError::Connection(("no such node".into(), NO_PEER_FOUND))),
         };
 
-        debug!(
-            "{} route the packet to the {} primary node",
-            self.machine_addr.to_string(),
-            addr.to_string()
-        );
+        debug!("route the packet to the {} primary node", addr.to_string());
 
         let primary_nodes_guard = self.primary_nodes.read().await;
         let self_c = self.clone();
 
-        if primary_nodes_guard.get(&addr.to_string()).is_some() {
-            let _ = node.tunnel.send(payload).await.unwrap();
+        if let Some(route) = primary_nodes_guard.get(&addr.to_string()) {
+            *route.last_used.lock().unwrap() = Instant::now();
+            drop(primary_nodes_guard);
+
+            node.tunnel.send(payload, frame).await?;
         } else {
             drop(primary_nodes_guard);
-            let _ = node.tunnel.send(payload).await.unwrap();
+            // NOTE(nosiee): the route is kept only once the node is reached, the next packet tries the handshake again
+            node.tunnel.send(payload, frame).await?;
 
-            tokio::spawn(async move {
+            let task = tokio::spawn(async move {
                 loop {
                     let mut buffer = BytesMut::zeroed(node.max_fragment_size);
 
-                    if let Ok(n) = node.tunnel.recv(&mut buffer).await {
-                        buffer.truncate(n);
-                        debug!("{} bytes read from {}", n, node.addr.to_string());
+                    let (n, frame) = node.recv(&mut buffer).await;
+                    buffer.truncate(n);
+                    debug!("{} bytes read from {}", n, node.addr.to_string());
 
-                        let identity = match device::util::get_destination_identity(&buffer) {
-                            Some(identity) => identity,
-                            None => {
-                                debug!("{} packet omitted, destination identity not found", hex::encode(&buffer));
-                                continue;
-                            }
-                        };
+                    self_c.touch_primary_route(&addr).await;
 
-                        match self_c.get_coordination(&identity).await {
-                            Some(peer) => {
-                                itx.send((peer, buffer.freeze())).await.unwrap();
-                            }
-                            None => debug!("{} packet omitted, coordination not found", hex::encode(&buffer)),
-                        }
-                    }
+                    let tenant = frame.tenant;
+                    let frame = HeaderFrame::new(FrameKind::Data).set_client_sequence(frame.client_sequence);
+                    self_c.write_response(tenant, buffer.freeze(), frame, &itx).await;
                 }
             });
 
+            let route = PrimaryRoute {
+                last_used: std::sync::Mutex::new(Instant::now()),
+                task: task.abort_handle(),
+            };
+
             let mut primary_nodes_guard = self.primary_nodes.write().await;
-            primary_nodes_guard.insert(addr.to_string(), ());
+            if let Some(previous) = primary_nodes_guard.insert(addr.to_string(), route) {
+                previous.task.abort();
+            }
         }
 
         Ok(())
     }
 
-    async fn add_coordination(&self, identity: String, peer: String) {
+    async fn touch_primary_route(&self, addr: &SocketAddr) {
+        if let Some(route) = self.primary_nodes.read().await.get(&addr.to_string()) {
+            *route.last_used.lock().unwrap() = Instant::now();
+        }
+    }
+
+    // NOTE(nosiee): the coordinations expire by the idle timeouts of their protocols,
+    // the primary routes with their receive loops once nothing goes through them
+    fn sweep(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+            loop {
+                ticker.tick().await;
+
+                let expired = self.coordination_table.write().await.expire();
+                if expired != 0 {
+                    debug!("{} coordinations expired", expired);
+                }
+
+                let expired = self.fragments.write().await.expire();
+                if expired != 0 {
+                    debug!("{} fragmented packets expired", expired);
+                }
+
+                if let Some(snat) = self.snat.as_ref() {
+                    let expired = snat.write().await.expire();
+                    if expired != 0 {
+                        debug!("{} snat mappings expired", expired);
+                    }
+                }
+
+                if let Some(leases) = self.leases.as_ref() {
+                    let expired = leases.write().await.expire();
+                    if expired != 0 {
+                        debug!("{} leases expired", expired);
+                    }
+                }
+
+                let mut tenants_guard = self.tenants.write().await;
+                let before = tenants_guard.len();
+                tenants_guard.retain(|_, (_, last_seen)| last_seen.elapsed() < TENANT_TIMEOUT);
+                if tenants_guard.len() != before {
+                    debug!("{} client addresses expired", before - tenants_guard.len());
+                }
+                drop(tenants_guard);
+
+                self.replay_table
+                    .write()
+                    .await
+                    .retain(|_, (_, _, last_seen)| last_seen.elapsed() < noise::SESSION_TIMEOUT);
+                self.dedup_table
+                    .write()
+                    .await
+                    .retain(|_, (_, last_seen)| last_seen.elapsed() < TENANT_TIMEOUT);
+                self.client_sequences
+                    .write()
+                    .await
+                    .retain(|_, (_, last_seen)| last_seen.elapsed() < TENANT_TIMEOUT);
+
+                let mut primary_nodes_guard = self.primary_nodes.write().await;
+                primary_nodes_guard.retain(|addr, route| {
+                    if route.last_used.lock().unwrap().elapsed() < PRIMARY_ROUTE_TIMEOUT {
+                        return true;
+                    }
+
+                    debug!("{} primary route expired", addr);
+                    route.task.abort();
+                    false
+                });
+            }
+        });
+    }
+
+    // NOTE(nosiee): the tenant of the authorized peer. a client is known by its own entry, a relayed packet
+    // carries the tenant of the client the relay authorized, only a known node is trusted with it
+    async fn authorize(&self, peer: &Peer, payload: &[u8], relayed: Tenant) -> Option<Tenant> {
+        let source = device::util::get_source_addr(payload)?;
+
+        let tenant = match self.control_table.authorize(&peer.public_key, source)? {
+            Authority::Client(client) => {
+                debug!("{} authorized as {} client", peer.addr, client.id);
+                client.tenant
+            }
+            Authority::Node(id) => {
+                debug!("{} authorized as {} node", peer.addr, id);
+                relayed
+            }
+        };
+
+        // NOTE(nosiee): the allowed ips of a leasing client cover the whole pool, it may use only its own lease
+        match self.leases.as_ref() {
+            Some(leases) if !leases.read().await.allows(tenant, source) => {
+                debug!("{} is not leased to {:016x}", source, tenant);
+                None
+            }
+            _ => Some(tenant),
+        }
+    }
+
+    // NOTE(nosiee): only a client is given a lease, the reply goes straight back to it
+    async fn lease(&self, peer: &Peer, itx: &Sender<PacketCoordinatorMessage>) {
+        let leases = match self.leases.as_ref() {
+            Some(leases) => leases,
+            None => {
+                debug!("lease request omitted, no lease pool, peer: {}", peer.addr);
+                return;
+            }
+        };
+
+        let client = match self.control_table.client(&peer.public_key) {
+            Some(client) => client,
+            None => {
+                debug!("lease request omitted, {} is not a client", peer.addr);
+                return;
+            }
+        };
+
+        let lease = match leases.write().await.lease(client.tenant) {
+            Some(lease) => lease,
+            None => {
+                error!("lease request omitted, the pool is exhausted, client: {}", client.id);
+                return;
+            }
+        };
+
+        info!("{}/{} leased to {} client", lease.addr, lease.prefix, client.id);
+        let reply = header::extend_payload(&lease.encode(), &HeaderFrame::new(FrameKind::LeaseReply));
+        let _ = itx.send((peer.addr.clone(), reply)).await;
+    }
+
+    async fn resolvers(&self, peer: &Peer, itx: &Sender<PacketCoordinatorMessage>) {
+        if self.resolvers.is_empty() {
+            debug!("resolver request omitted, no resolvers, peer: {}", peer.addr);
+            return;
+        }
+
+        let client = match self.control_table.client(&peer.public_key) {
+            Some(client) => client,
+            None => {
+                debug!("resolver request omitted, {} is not a client", peer.addr);
+                return;
+            }
+        };
+
+        debug!("resolvers {:?} sent to {} client", self.resolvers, client.id);
+        let reply = header::extend_payload(&resolver::encode(&self.resolvers), &HeaderFrame::new(FrameKind::ResolverReply));
+        let _ = itx.send((peer.addr.clone(), reply)).await;
+    }
+
+    async fn accept_sequence(&self, peer: &Peer, sequence: u64) -> bool {
+        let mut replay_guard = self.replay_table.write().await;
+        let (session, window, last_seen) = replay_guard
+            .entry(peer.addr.clone())
+            .or_insert_with(|| (peer.session, ReplayWindow::new(), Instant::now()));
+        *last_seen = Instant::now();
+
+        // NOTE(nosiee): the peer did a new handshake, its sequence starts over
+        if *session != peer.session {
+            *session = peer.session;
+            *window = ReplayWindow::new();
+        }
+
+        window.update(sequence)
+    }
+
+    // NOTE(nosiee): the state of the node is kept apart for every client, the clients may use the same private range.
+    // a client is known by its key, a relayed frame carries the tenant of the client the relay authorized.
+    // an unknown peer has no tenant
+    fn tenant(&self, peer: &Peer, frame: &HeaderFrame) -> Option<Tenant> {
+        if let Some(client) = self.control_table.client(&peer.public_key) {
+            return Some(client.tenant);
+        }
+
+        self.control_table.is_known(&peer.public_key).then_some(frame.tenant)
+    }
+
+    // NOTE(nosiee): with a snat pool every client address is written to the device as its own pool address,
+    // otherwise as is, and the responses are matched to the client that used the address last
+    // NOTE(nosiee): without snat the client address is used as is, it's kept by the first client that uses it
+    // until it goes idle. the packet of any other client with the same address is dropped
+    async fn translate(&self, tenant: Tenant, payload: Message) -> Option<Message> {
+        let source = match device::util::get_source_addr(&payload) {
+            Some(source) => source,
+            None => return Some(payload),
+        };
+
+        let outer = match self.snat.as_ref() {
+            Some(snat) => snat.write().await.map(tenant, source),
+            None => None,
+        };
+
+        if let Some(outer) = outer {
+            let mut packet = BytesMut::from(&payload[..]);
+            nat::rewrite_source(&mut packet, outer);
+            return Some(packet.freeze());
+        }
+
+        let mut tenants_guard = self.tenants.write().await;
+        match tenants_guard.get(&source) {
+            Some((owner, last_seen)) if *owner != tenant && last_seen.elapsed() < TENANT_TIMEOUT => {
+                debug!("{} packet omitted, {} is used by {:016x}", hex::encode(&payload), source, owner);
+                None
+            }
+            _ => {
+                tenants_guard.insert(source, (tenant, Instant::now()));
+                Some(payload)
+            }
+        }
+    }
+
+    async fn restore(&self, payload: Message) -> (Tenant, Message) {
+        let destination = match device::util::get_destination_addr(&payload) {
+            Some(destination) => destination,
+            None => return (0, payload),
+        };
+
+        let inner = match self.snat.as_ref() {
+            Some(snat) => snat.write().await.unmap(destination),
+            None => None,
+        };
+
+        match inner {
+            Some((tenant, inner)) => {
+                let mut packet = BytesMut::from(&payload[..]);
+                nat::rewrite_destination(&mut packet, inner);
+                (tenant, packet.freeze())
+            }
+            None => {
+                let tenant = self.tenants.read().await.get(&destination).map(|(tenant, _)| *tenant);
+                (tenant.unwrap_or_default(), payload)
+            }
+        }
+    }
+
+    // NOTE(nosiee): the primary node drops the copies of a redundant packet by the sequence of the client
+    async fn accept_client_sequence(&self, tenant: Tenant, payload: &[u8], sequence: u64) -> bool {
+        let source = match device::util::get_source_addr(payload) {
+            Some(source) if sequence != 0 => source,
+            _ => return true,
+        };
+
+        let mut dedup_guard = self.dedup_table.write().await;
+        let (window, last_seen) = dedup_guard
+            .entry((tenant, source))
+            .or_insert_with(|| (DedupWindow::new(), Instant::now()));
+        *last_seen = Instant::now();
+
+        window.accept(sequence)
+    }
+
+    async fn next_client_sequence(&self, tenant: Tenant, payload: &[u8]) -> u64 {
+        let destination = match device::util::get_destination_addr(payload) {
+            Some(destination) => destination,
+            None => return 0,
+        };
+
+        let mut sequences_guard = self.client_sequences.write().await;
+        // NOTE(nosiee): a forgotten client starts at a random sequence, its dedup window may still hold the old ones
+        let (sequence, last_seen) = sequences_guard
+            .entry((tenant, destination))
+            .or_insert_with(|| (rand::random::<u64>() % MAX_SEQUENCE, Instant::now()));
+        *sequence = (*sequence % MAX_SEQUENCE) + 1;
+        *last_seen = Instant::now();
+
+        *sequence
+    }
+
+    async fn source_identity(&self, tenant: Tenant, payload: &[u8], fragment: Option<&Fragment>) -> Option<String> {
+        match fragment {
+            Some(fragment) if !fragment.first => self.fragments.read().await.get(tenant, fragment.key),
+            Some(fragment) => {
+                let identity = device::util::get_source_identity(payload)?;
+                self.fragments.write().await.learn(tenant, fragment.key, identity.clone());
+
+                Some(identity)
+            }
+            None => device::util::get_source_identity(payload),
+        }
+    }
+
+    // NOTE(nosiee): a fragment that came before the first one is held until the first one tells its identity
+    async fn write_response(&self, tenant: Tenant, payload: Message, frame: HeaderFrame, itx: &Sender<PacketCoordinatorMessage>) {
+        let fragment = device::util::get_fragment(&payload);
+
+        let identity = match fragment {
+            Some(fragment) if !fragment.first => {
+                let mut fragments_guard = self.fragments.write().await;
+                if let Some(identity) = fragments_guard.get(tenant, fragment.key) {
+                    identity
+                } else {
+                    if !fragments_guard.hold(tenant, fragment.key, payload.clone(), frame) {
+                        debug!("{} packet omitted, too many fragments held", hex::encode(&payload));
+                    }
+
+                    return;
+                }
+            }
+            _ => match device::util::get_destination_identity(&payload) {
+                Some(identity) => identity,
+                None => {
+                    debug!("{} packet omitted, destination identity not found", hex::encode(&payload));
+                    return;
+                }
+            },
+        };
+
+        let held = match fragment {
+            Some(fragment) if fragment.first => self.fragments.write().await.learn(tenant, fragment.key, identity.clone()),
+            _ => Vec::new(),
+        };
+
+        self.write_back(tenant, &identity, &payload, frame, itx).await;
+        for (payload, frame) in held {
+            self.write_back(tenant, &identity, &payload, frame, itx).await;
+        }
+    }
+
+    // NOTE(nosiee): the response goes back through every peer the request came from, up to the client's redundancy
+    async fn write_back(&self, tenant: Tenant, identity: &str, payload: &[u8], frame: HeaderFrame, itx: &Sender<PacketCoordinatorMessage>) {
+        let peers = match self.get_coordination(tenant, identity, payload).await {
+            Some(peers) => peers,
+            None => {
+                debug!("{} packet omitted, coordination not found", hex::encode(payload));
+                return;
+            }
+        };
+
+        let payload = header::extend_payload(payload, &frame.set_tenant(tenant));
+        for peer in peers {
+            itx.send((peer, payload.clone())).await.unwrap();
+        }
+    }
+
+    // NOTE(nosiee): the esp replies come with the spi of the other direction, so the esp coordination
+    // is kept by the address tuple as well, the replies are found by it
+    async fn add_coordination(&self, tenant: Tenant, identity: String, peer: String, redundancy: u8, payload: &[u8]) {
         let mut table_guard = self.coordination_table.write().await;
-        table_guard.insert(identity, peer);
+
+        let is_esp = device::util::get_flow(payload).is_some_and(|flow| flow.protocol == IpNextHeaderProtocols::Esp);
+        if let Some(tuple) = device::util::get_tuple_identity(payload, true).filter(|_| is_esp) {
+            table_guard.insert(tenant_identity(tenant, &tuple), peer.clone(), redundancy, payload);
+        }
+
+        table_guard.insert(tenant_identity(tenant, &identity), peer, redundancy, payload);
     }
 
-    async fn get_coordination(&self, identity: &String) -> Option<String> {
-        let table_guard = self.coordination_table.read().await;
-        table_guard.get(identity).cloned()
+    async fn get_coordination(&self, tenant: Tenant, identity: &str, payload: &[u8]) -> Option<Vec<String>> {
+        let mut table_guard = self.coordination_table.write().await;
+
+        match table_guard.get(&tenant_identity(tenant, identity), payload) {
+            Some(peers) => Some(peers),
+            None => table_guard.get(&tenant_identity(tenant, &device::util::get_tuple_identity(payload, false)?), payload),
+        }
+    }
+}
+
+fn tenant_identity(tenant: Tenant, identity: &str) -> String {
+    format!("{:016x}/{}", tenant, identity)
+}
+
+// NOTE(nosiee): the relay keeps the client's fields as is, the primary node reorders the flow,
+// drops the copies sent through the other nodes and rebuilds the lost packets
+fn relay_frame(frame: &HeaderFrame) -> HeaderFrame {
+    HeaderFrame::new(frame.kind)
+        .set_flow_sequence(frame.flow_sequence)
+        .set_redundancy(frame.redundancy)
+        .set_client_sequence(frame.client_sequence)
+        .set_group(frame.group_id, frame.group_index)
+        .set_group_size(frame.group_data, frame.group_parity)
+}
+
+async fn write_local(payload: Message, flow_sequence: u32, tun_dev_tx: &Sender<Message>, reorder_tx: Option<&Sender<ReorderMessage>>) {
+    match reorder_tx {
+        Some(reorder_tx) => {
+            let _ = reorder_tx.send((flow_sequence, payload)).await;
+        }
+        None => {
+            let _ = tun_dev_tx.send(payload).await;
+        }
     }
 }
@@ -0,0 +1,297 @@
+use async_channel::{Receiver, Sender};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::device::util::Flow;
+use crate::device::{self, DEVICE_BUFFER_SIZE, Message};
+
+pub const DEFAULT_REORDER_TIMEOUT: Duration = Duration::from_millis(30);
+pub const DEFAULT_REORDER_CAPACITY: usize = 0x40;
+
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// NOTE(nosiee): a sequence this far behind means the client started the flow over, not a late packet
+const RESET_DISTANCE: u32 = 0x10000;
+
+pub type ReorderMessage = (u32, Message);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReorderConfig {
+    pub timeout: Duration,
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+struct FlowBuffer {
+    next: u32,
+    pending: BTreeMap<u32, (Instant, Message)>,
+    last_seen: Instant,
+}
+
+// NOTE(nosiee): the packets of a sprayed flow come through the different nodes and arrive out of order.
+// the buffer holds a packet until the previous ones arrive, at most `capacity` packets per flow and at most
+// `timeout` for a single packet, then the gap is skipped. late and duplicated packets are released as is,
+// the destination deals with them. a single task owns the buffer, so the release order is the tun write order
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    config: ReorderConfig,
+    flows: HashMap<Flow, FlowBuffer>,
+    ready: Vec<Message>,
+}
+
+impl ReorderBuffer {
+    pub fn new(config: ReorderConfig) -> Self {
+        Self {
+            config,
+            flows: HashMap::new(),
+            ready: Vec::new(),
+        }
+    }
+
+    pub fn forward(mut self, tun_dev_tx: Sender<Message>) -> Sender<ReorderMessage> {
+        let (tx, rx): (Sender<ReorderMessage>, Receiver<ReorderMessage>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.config.timeout / 2);
+
+            loop {
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Ok((sequence, payload)) => self.push(sequence, payload),
+                        Err(_) => break,
+                    },
+                    _ = ticker.tick() => self.expire(),
+                }
+
+                for payload in self.ready.drain(..) {
+                    let _ = tun_dev_tx.send(payload).await;
+                }
+            }
+        });
+
+        tx
+    }
+
+    fn push(&mut self, sequence: u32, payload: Message) {
+        let flow = match device::util::get_flow(&payload) {
+            Some(flow) if sequence != 0 => flow,
+            _ => {
+                self.ready.push(payload);
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let buffer = self.flows.entry(flow).or_insert_with(|| FlowBuffer {
+            next: sequence,
+            pending: BTreeMap::new(),
+            last_seen: now,
+        });
+        buffer.last_seen = now;
+
+        let distance = sequence.wrapping_sub(buffer.next) as i32;
+        if distance < 0 {
+            if distance.unsigned_abs() < RESET_DISTANCE {
+                debug!("{} flow sequence is late, expected {}", sequence, buffer.next);
+                self.ready.push(payload);
+                return;
+            }
+
+            Self::release_all(buffer, &mut self.ready);
+            buffer.next = sequence;
+        }
+
+        buffer.pending.insert(sequence, (now, payload));
+        Self::release_ready(buffer, &mut self.ready);
+
+        while buffer.pending.len() > self.config.capacity {
+            Self::skip_gap(buffer, &mut self.ready);
+        }
+    }
+
+    fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        for buffer in self.flows.values_mut() {
+            while Self::oldest(buffer).is_some_and(|oldest| now.duration_since(oldest) >= self.config.timeout) {
+                Self::skip_gap(buffer, &mut self.ready);
+            }
+        }
+
+        self.flows
+            .retain(|_, buffer| !buffer.pending.is_empty() || now.duration_since(buffer.last_seen) < FLOW_IDLE_TIMEOUT);
+    }
+
+    fn oldest(buffer: &FlowBuffer) -> Option<Instant> {
+        buffer.pending.values().map(|(received, _)| *received).min()
+    }
+
+    fn release_ready(buffer: &mut FlowBuffer, ready: &mut Vec<Message>) {
+        while let Some((_, payload)) = buffer.pending.remove(&buffer.next) {
+            ready.push(payload);
+            buffer.next = next_sequence(buffer.next);
+        }
+    }
+
+    // NOTE(nosiee): gives up on the missing packets before the closest pending one
+    fn skip_gap(buffer: &mut FlowBuffer, ready: &mut Vec<Message>) {
+        let next = buffer.next;
+        if let Some(&closest) = buffer.pending.keys().min_by_key(|sequence| sequence.wrapping_sub(next)) {
+            debug!("{}..{} flow sequences skipped", next, closest);
+            buffer.next = closest;
+            Self::release_ready(buffer, ready);
+        }
+    }
+
+    fn release_all(buffer: &mut FlowBuffer, ready: &mut Vec<Message>) {
+        while !buffer.pending.is_empty() {
+            Self::skip_gap(buffer, ready);
+        }
+    }
+}
+
+// NOTE(nosiee): the client never numbers a flow packet with 0, it's skipped on wraparound
+fn next_sequence(sequence: u32) -> u32 {
+    match sequence.wrapping_add(1) {
+        0 => 1,
+        sequence => sequence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    const CONFIG: ReorderConfig = ReorderConfig {
+        timeout: DEFAULT_REORDER_TIMEOUT,
+        capacity: 4,
+    };
+
+    // NOTE(nosiee): the sequence is carried in the udp payload, so the released order can be read back
+    fn packet(port: u16, sequence: u32) -> Message {
+        let mut packet = vec![0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 2, 198, 51, 100, 1];
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&[0, 53, 0, 12, 0, 0]);
+        packet.extend_from_slice(&sequence.to_be_bytes());
+
+        Bytes::from(packet)
+    }
+
+    fn released(buffer: &mut ReorderBuffer) -> Vec<u32> {
+        buffer
+            .ready
+            .drain(..)
+            .map(|payload| u32::from_be_bytes(payload[28..32].try_into().unwrap()))
+            .collect()
+    }
+
+    fn push(buffer: &mut ReorderBuffer, port: u16, sequence: u32) {
+        buffer.push(sequence, packet(port, sequence));
+    }
+
+    #[test]
+    fn releases_in_order() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 10);
+        push(&mut buffer, 40000, 12);
+        push(&mut buffer, 40000, 13);
+        assert_eq!(released(&mut buffer), vec![10]);
+
+        push(&mut buffer, 40000, 11);
+        assert_eq!(released(&mut buffer), vec![11, 12, 13]);
+    }
+
+    #[test]
+    fn keeps_flows_apart() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 10);
+        push(&mut buffer, 40001, 100);
+        push(&mut buffer, 40000, 12);
+        push(&mut buffer, 40001, 101);
+        assert_eq!(released(&mut buffer), vec![10, 100, 101]);
+    }
+
+    #[test]
+    fn releases_late_and_unsequenced_packets_as_is() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 10);
+        push(&mut buffer, 40000, 11);
+        push(&mut buffer, 40000, 10);
+        push(&mut buffer, 40000, 0);
+        assert_eq!(released(&mut buffer), vec![10, 11, 10, 0]);
+    }
+
+    #[test]
+    fn skips_the_gap_over_capacity() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 1);
+        for sequence in 3..7 {
+            push(&mut buffer, 40000, sequence);
+        }
+        assert_eq!(released(&mut buffer), vec![1]);
+
+        push(&mut buffer, 40000, 8);
+        assert_eq!(released(&mut buffer), vec![3, 4, 5, 6]);
+
+        push(&mut buffer, 40000, 2);
+        push(&mut buffer, 40000, 7);
+        assert_eq!(released(&mut buffer), vec![2, 7, 8]);
+    }
+
+    #[test]
+    fn skips_the_gap_on_timeout() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 1);
+        push(&mut buffer, 40000, 3);
+        push(&mut buffer, 40000, 5);
+        buffer.expire_at(Instant::now());
+        assert_eq!(released(&mut buffer), vec![1]);
+
+        buffer.expire_at(Instant::now() + CONFIG.timeout);
+        assert_eq!(released(&mut buffer), vec![3, 5]);
+    }
+
+    #[test]
+    fn starts_over_far_behind() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 1_000_000);
+        push(&mut buffer, 40000, 1_000_002);
+        push(&mut buffer, 40000, 5);
+        push(&mut buffer, 40000, 6);
+        assert_eq!(released(&mut buffer), vec![1_000_000, 1_000_002, 5, 6]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, u32::MAX - 1);
+        push(&mut buffer, 40000, 2);
+        push(&mut buffer, 40000, u32::MAX);
+        assert_eq!(released(&mut buffer), vec![u32::MAX - 1, u32::MAX]);
+
+        push(&mut buffer, 40000, 1);
+        assert_eq!(released(&mut buffer), vec![1, 2]);
+    }
+
+    #[test]
+    fn forgets_idle_flows() {
+        let mut buffer = ReorderBuffer::new(CONFIG);
+
+        push(&mut buffer, 40000, 10);
+        buffer.expire_at(Instant::now() + FLOW_IDLE_TIMEOUT);
+        assert!(buffer.flows.is_empty());
+
+        push(&mut buffer, 40000, 3);
+        assert_eq!(released(&mut buffer), vec![10, 3]);
+    }
+}
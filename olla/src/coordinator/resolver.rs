@@ -0,0 +1,28 @@
+use std::net::IpAddr;
+
+use super::lease::{ADDR_SIZE, decode_addr, encode_addr};
+
+// NOTE(nosiee): the resolvers the primary node has chosen for its clients, written the same way the lease addresses are
+pub fn encode(resolvers: &[IpAddr]) -> Vec<u8> {
+    let count = resolvers.len().min(u8::MAX as usize);
+    let mut buf = Vec::with_capacity(1 + count * ADDR_SIZE);
+    buf.push(count as u8);
+
+    for addr in resolvers.iter().take(count) {
+        buf.extend_from_slice(&encode_addr(*addr));
+    }
+
+    buf
+}
+
+pub fn decode(buf: &[u8]) -> Option<Vec<IpAddr>> {
+    let count = *buf.first()? as usize;
+
+    let mut resolvers = Vec::with_capacity(count);
+    for i in 0..count {
+        let offset = 1 + i * ADDR_SIZE;
+        resolvers.push(decode_addr(buf.get(offset..offset + ADDR_SIZE)?));
+    }
+
+    Some(resolvers)
+}
@@ -0,0 +1,204 @@
+use pnet::ipnetwork::IpNetwork;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use super::control::Tenant;
+
+pub const SNAT_TIMEOUT: Duration = Duration::from_secs(7200);
+
+#[derive(Debug)]
+struct Mapping {
+    tenant: Tenant,
+    inner: IpAddr,
+    last_seen: Instant,
+}
+
+// NOTE(nosiee): every client address is mapped to its own address from the pool before the packet is written
+// to the device, so the clients with the same private range don't clash in the kernel conntrack either.
+// a mapping is released once the address has been idle for SNAT_TIMEOUT
+#[derive(Debug)]
+pub struct SnatTable {
+    pools: Vec<IpNetwork>,
+    cursors: Vec<u128>,
+    by_inner: HashMap<(Tenant, IpAddr), IpAddr>,
+    by_outer: HashMap<IpAddr, Mapping>,
+}
+
+impl SnatTable {
+    pub fn new(pools: Vec<IpNetwork>) -> Self {
+        Self {
+            cursors: vec![0; pools.len()],
+            pools,
+            by_inner: HashMap::new(),
+            by_outer: HashMap::new(),
+        }
+    }
+
+    // NOTE(nosiee): none if there is no pool of the address family or the pool is exhausted
+    pub fn map(&mut self, tenant: Tenant, inner: IpAddr) -> Option<IpAddr> {
+        if let Some(outer) = self.by_inner.get(&(tenant, inner)).copied() {
+            if let Some(mapping) = self.by_outer.get_mut(&outer) {
+                mapping.last_seen = Instant::now();
+            }
+
+            return Some(outer);
+        }
+
+        let outer = self.allocate(inner)?;
+        let mapping = Mapping {
+            tenant,
+            inner,
+            last_seen: Instant::now(),
+        };
+
+        self.by_inner.insert((tenant, inner), outer);
+        self.by_outer.insert(outer, mapping);
+
+        Some(outer)
+    }
+
+    pub fn unmap(&mut self, outer: IpAddr) -> Option<(Tenant, IpAddr)> {
+        let mapping = self.by_outer.get_mut(&outer)?;
+        mapping.last_seen = Instant::now();
+
+        Some((mapping.tenant, mapping.inner))
+    }
+
+    pub fn expire(&mut self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> usize {
+        let expired: Vec<IpAddr> = self
+            .by_outer
+            .iter()
+            .filter(|(_, mapping)| now.saturating_duration_since(mapping.last_seen) >= SNAT_TIMEOUT)
+            .map(|(outer, _)| *outer)
+            .collect();
+
+        for outer in expired.iter() {
+            if let Some(mapping) = self.by_outer.remove(outer) {
+                self.by_inner.remove(&(mapping.tenant, mapping.inner));
+            }
+        }
+
+        expired.len()
+    }
+
+    // NOTE(nosiee): the pool is walked from the last allocated address, at most one address more than
+    // there are mappings, one of them is free for sure if the pool isn't exhausted
+    fn allocate(&mut self, inner: IpAddr) -> Option<IpAddr> {
+        for (i, pool) in self.pools.iter().enumerate() {
+            if pool.is_ipv4() != inner.is_ipv4() {
+                continue;
+            }
+
+            let (first, last) = host_range(pool);
+            let size = last - first + 1;
+
+            for _ in 0..size.min(self.by_outer.len() as u128 + 1) {
+                let outer = nth_addr(pool, first + self.cursors[i]);
+                self.cursors[i] = (self.cursors[i] + 1) % size;
+
+                if !self.by_outer.contains_key(&outer) {
+                    return Some(outer);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// NOTE(nosiee): the first and the last host offsets, the network and the broadcast addresses are skipped
+pub fn host_range(pool: &IpNetwork) -> (u128, u128) {
+    let bits = if pool.is_ipv4() { 32 } else { 128 };
+    let host_bits = bits - pool.prefix() as u32;
+    let last = 1u128.checked_shl(host_bits).map(|size| size - 1).unwrap_or(u128::MAX);
+
+    match host_bits {
+        0 | 1 => (0, last),
+        _ if pool.is_ipv4() => (1, last - 1),
+        _ => (1, last),
+    }
+}
+
+pub fn nth_addr(pool: &IpNetwork, offset: u128) -> IpAddr {
+    match pool.network() {
+        IpAddr::V4(network) => IpAddr::V4(Ipv4Addr::from(u32::from(network).wrapping_add(offset as u32))),
+        IpAddr::V6(network) => IpAddr::V6(Ipv6Addr::from(u128::from(network).wrapping_add(offset))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENANT: Tenant = 1;
+    const OTHER_TENANT: Tenant = 2;
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn table(pools: &[&str]) -> SnatTable {
+        SnatTable::new(pools.iter().map(|pool| pool.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn maps_tenants_apart() {
+        let mut table = table(&["100.64.0.0/24"]);
+
+        let outer = table.map(TENANT, addr("10.0.0.2")).unwrap();
+        let other = table.map(OTHER_TENANT, addr("10.0.0.2")).unwrap();
+
+        assert_ne!(outer, other);
+        assert_eq!(table.map(TENANT, addr("10.0.0.2")), Some(outer));
+        assert_eq!(table.unmap(outer), Some((TENANT, addr("10.0.0.2"))));
+        assert_eq!(table.unmap(other), Some((OTHER_TENANT, addr("10.0.0.2"))));
+        assert_eq!(table.unmap(addr("100.64.0.200")), None);
+    }
+
+    #[test]
+    fn maps_by_address_family() {
+        let mut table = table(&["100.64.0.0/24", "fd08::/112"]);
+
+        assert!(table.map(TENANT, addr("10.0.0.2")).unwrap().is_ipv4());
+        assert!(table.map(TENANT, addr("fd00::2")).unwrap().is_ipv6());
+
+        let mut table = self::table(&["100.64.0.0/24"]);
+        assert_eq!(table.map(TENANT, addr("fd00::2")), None);
+    }
+
+    #[test]
+    fn skips_network_and_broadcast() {
+        let mut table = table(&["100.64.0.0/30"]);
+
+        assert_eq!(table.map(TENANT, addr("10.0.0.2")), Some(addr("100.64.0.1")));
+        assert_eq!(table.map(TENANT, addr("10.0.0.3")), Some(addr("100.64.0.2")));
+        assert_eq!(table.map(TENANT, addr("10.0.0.4")), None);
+    }
+
+    #[test]
+    fn reuses_expired_addresses() {
+        let mut table = table(&["100.64.0.0/30"]);
+
+        let first = table.map(TENANT, addr("10.0.0.2")).unwrap();
+        table.map(TENANT, addr("10.0.0.3")).unwrap();
+        assert_eq!(table.expire_at(Instant::now()), 0);
+
+        assert_eq!(table.expire_at(Instant::now() + SNAT_TIMEOUT), 2);
+        assert_eq!(table.unmap(first), None);
+        assert!(table.map(OTHER_TENANT, addr("10.0.0.4")).is_some());
+    }
+
+    #[test]
+    fn host_ranges() {
+        assert_eq!(host_range(&"10.0.0.0/24".parse().unwrap()), (1, 254));
+        assert_eq!(host_range(&"10.0.0.0/31".parse().unwrap()), (0, 1));
+        assert_eq!(host_range(&"10.0.0.1/32".parse().unwrap()), (0, 0));
+        assert_eq!(host_range(&"fd08::/112".parse().unwrap()), (1, 0xffff));
+        assert_eq!(nth_addr(&"fd08::/112".parse().unwrap(), 0xffff), addr("fd08::ffff"));
+    }
+}
@@ -1,8 +1,12 @@
+pub const DEFAULT_IPV6_PREFIX: u8 = 64;
+
 #[derive(Debug, Clone)]
 pub struct DeviceConfig {
     pub name: String,
     pub mtu: u16,
     pub addr: String,
     pub mask: String,
+    pub ipv6_addr: Option<String>,
+    pub ipv6_prefix: u8,
     pub disable_on_exit: bool,
 }
@@ -0,0 +1,540 @@
+use async_channel::{Receiver, Sender};
+use bytes::BytesMut;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet::packet::udp::{self, MutableUdpPacket};
+use std::collections::{HashMap, hash_map::Entry};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use tokio::net::UdpSocket;
+use tracing::{debug, error};
+
+use super::{DEVICE_BUFFER_SIZE, Message, nat};
+use crate::coordinator::node::domain::DomainTable;
+
+pub const DNS_PORT: u16 = 53;
+pub const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+pub const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.olla";
+
+const DNS_HEADER_SIZE: usize = 12;
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+const MAX_DNS_SIZE: usize = 0xffff - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+const DEFAULT_TTL: u8 = 64;
+
+const RECORD_A: u16 = 1;
+const RECORD_AAAA: u16 = 28;
+const RECORD_HEADER_SIZE: usize = 10;
+const MAX_NAME_STEPS: usize = 0xff;
+
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub name: String,
+    pub addr: IpAddr,
+    pub ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Query {
+    client: SocketAddr,
+    id: u16,
+    created: Instant,
+}
+
+// NOTE(nosiee): the queries in flight by the id sent upstream, the original id goes back to the client with the reply.
+// the upstream ids are random, so a spoofed reply has to guess one of the ids in flight
+#[derive(Debug, Default)]
+struct QueryTable {
+    queries: HashMap<u16, Query>,
+}
+
+impl QueryTable {
+    fn insert(&mut self, client: SocketAddr, id: u16) -> Option<u16> {
+        if self.queries.len() > u16::MAX as usize {
+            return None;
+        }
+
+        loop {
+            let upstream_id: u16 = rand::random();
+
+            if let Entry::Vacant(entry) = self.queries.entry(upstream_id) {
+                let created = Instant::now();
+                entry.insert(Query { client, id, created });
+                return Some(upstream_id);
+            }
+        }
+    }
+
+    fn remove(&mut self, upstream_id: u16) -> Option<Query> {
+        self.queries.remove(&upstream_id)
+    }
+
+    fn expire(&mut self) -> usize {
+        let before = self.queries.len();
+        self.queries.retain(|_, query| query.created.elapsed() < DNS_TIMEOUT);
+
+        before - self.queries.len()
+    }
+}
+
+// NOTE(nosiee): the local dns forwarder. it listens on the device address, the queries are written as udp packets
+// straight to the node coordinator, so they never leave through the system routes, and the replies are taken
+// from the node coordinator before they reach the device. the upstream port is held by a socket that reads nothing
+#[derive(Debug)]
+pub struct DnsForwarder {
+    addr: Ipv4Addr,
+    port: u16,
+    resolvers: Vec<Ipv4Addr>,
+    listener: UdpSocket,
+    _reservation: std::net::UdpSocket,
+    table: Mutex<QueryTable>,
+    domains: Option<Arc<DomainTable>>,
+}
+
+impl DnsForwarder {
+    pub async fn bind(addr: Ipv4Addr, resolvers: Vec<Ipv4Addr>) -> anyhow::Result<Self> {
+        if resolvers.is_empty() {
+            return Err(anyhow::anyhow!("no ipv4 resolver to forward to"));
+        }
+
+        let listener = UdpSocket::bind(SocketAddr::new(IpAddr::V4(addr), DNS_PORT)).await?;
+        let reservation = std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(addr), 0))?;
+        let port = reservation.local_addr()?.port();
+
+        Ok(Self {
+            addr,
+            port,
+            resolvers,
+            listener,
+            _reservation: reservation,
+            table: Mutex::new(QueryTable::default()),
+            domains: None,
+        })
+    }
+
+    // NOTE(nosiee): the domains are learned only from the replies to the forwarder's own queries
+    pub fn set_domains(mut self, domains: Arc<DomainTable>) -> Self {
+        self.domains = Some(domains);
+        self
+    }
+
+    // NOTE(nosiee): sits between the node coordinator and the device, the replies are cut out of the device stream
+    pub fn forward(self: Arc<Self>, nc_tx: Sender<Message>, nc_rx: Receiver<Message>) -> Receiver<Message> {
+        let (otx, orx): (Sender<Message>, Receiver<Message>) = async_channel::bounded(DEVICE_BUFFER_SIZE);
+
+        self.clone().sweep();
+
+        let self_c = self.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_DNS_SIZE];
+
+            loop {
+                let (n, client) = match self_c.listener.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("failed to read from the dns socket: {}", err);
+                        break;
+                    }
+                };
+
+                if let Some(packet) = self_c.query(&buffer[..n], client) {
+                    let _ = nc_tx.send(packet).await;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Ok(payload) = nc_rx.recv().await {
+                if !self.reply(&payload).await {
+                    let _ = otx.send(payload).await;
+                }
+            }
+        });
+
+        orx
+    }
+
+    fn query(&self, query: &[u8], client: SocketAddr) -> Option<Message> {
+        if query.len() < DNS_HEADER_SIZE {
+            debug!("{} query omitted, unusual size: {}b", hex::encode(query), query.len());
+            return None;
+        }
+
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        let upstream_id = match self.table.lock().unwrap().insert(client, id) {
+            Some(upstream_id) => upstream_id,
+            None => {
+                error!("{} query omitted, too many queries in flight", hex::encode(query));
+                return None;
+            }
+        };
+
+        let resolver = self.resolvers[upstream_id as usize % self.resolvers.len()];
+        debug!("query {} from {} forwarded to {} as {}", id, client, resolver, upstream_id);
+
+        let mut payload = query.to_vec();
+        payload[..2].copy_from_slice(&upstream_id.to_be_bytes());
+
+        Some(self.packet(resolver, &payload))
+    }
+
+    // NOTE(nosiee): false if the packet isn't a reply to the forwarder and goes to the device
+    async fn reply(&self, packet: &[u8]) -> bool {
+        let source = match (super::util::get_source_addr(packet), super::util::get_destination_addr(packet)) {
+            (Some(IpAddr::V4(source)), Some(IpAddr::V4(destination))) if destination == self.addr => source,
+            _ => return false,
+        };
+
+        match (nat::get_source_port(packet), nat::get_destination_port(packet)) {
+            (Some((IpNextHeaderProtocols::Udp, DNS_PORT)), Some((IpNextHeaderProtocols::Udp, port))) if port == self.port => (),
+            _ => return false,
+        }
+
+        if !self.resolvers.contains(&source) {
+            return false;
+        }
+
+        let reply = match nat::get_udp_payload(packet) {
+            Some(reply) if reply.len() >= DNS_HEADER_SIZE => reply,
+            _ => {
+                debug!("{} reply omitted, malformed", hex::encode(packet));
+                return true;
+            }
+        };
+
+        let upstream_id = u16::from_be_bytes([reply[0], reply[1]]);
+        let query = match self.table.lock().unwrap().remove(upstream_id) {
+            Some(query) => query,
+            None => {
+                debug!("{} reply omitted, unknown query {}", hex::encode(reply), upstream_id);
+                return true;
+            }
+        };
+
+        if let Some(domains) = self.domains.as_ref() {
+            domains.learn_reply(reply);
+        }
+
+        let mut reply = reply.to_vec();
+        reply[..2].copy_from_slice(&query.id.to_be_bytes());
+
+        match self.listener.send_to(&reply, query.client).await {
+            Ok(_) => debug!("reply to {} from {} relayed to {}", query.id, source, query.client),
+            Err(err) => debug!("{} reply omitted, failed to send: {}", hex::encode(&reply), err),
+        }
+
+        true
+    }
+
+    fn packet(&self, resolver: Ipv4Addr, payload: &[u8]) -> Message {
+        let total_length = IPV4_HEADER_SIZE + UDP_HEADER_SIZE + payload.len();
+        let mut buffer = BytesMut::zeroed(total_length);
+
+        {
+            let mut udp_packet = MutableUdpPacket::new(&mut buffer[IPV4_HEADER_SIZE..]).unwrap();
+            udp_packet.set_source(self.port);
+            udp_packet.set_destination(DNS_PORT);
+            udp_packet.set_length((UDP_HEADER_SIZE + payload.len()) as u16);
+            udp_packet.set_payload(payload);
+
+            let checksum = udp::ipv4_checksum(&udp_packet.to_immutable(), &self.addr, &resolver);
+            udp_packet.set_checksum(checksum);
+        }
+
+        let mut ip_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ip_packet.set_version(4);
+        ip_packet.set_header_length((IPV4_HEADER_SIZE / 4) as u8);
+        ip_packet.set_total_length(total_length as u16);
+        ip_packet.set_flags(Ipv4Flags::DontFragment);
+        ip_packet.set_ttl(DEFAULT_TTL);
+        ip_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_packet.set_source(self.addr);
+        ip_packet.set_destination(resolver);
+
+        let checksum = ipv4::checksum(&ip_packet.to_immutable());
+        ip_packet.set_checksum(checksum);
+
+        buffer.freeze()
+    }
+
+    fn sweep(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(DNS_TIMEOUT);
+
+            loop {
+                ticker.tick().await;
+
+                let expired = self.table.lock().unwrap().expire();
+                if expired != 0 {
+                    debug!("{} dns queries expired", expired);
+                }
+            }
+        });
+    }
+}
+
+// NOTE(nosiee): the id of a query or a reply, none if the message is the other kind or too short
+pub fn message_id(message: &[u8], reply: bool) -> Option<u16> {
+    let header = message.get(..DNS_HEADER_SIZE)?;
+    if (header[2] & 0x80 != 0) != reply {
+        return None;
+    }
+
+    Some(u16::from_be_bytes([header[0], header[1]]))
+}
+
+// NOTE(nosiee): the question name and the a and aaaa records of a successful reply, the rest of the records are skipped.
+// the names are lowercase without the trailing dot
+pub fn answers(reply: &[u8]) -> Option<(String, Vec<Answer>)> {
+    let header = reply.get(..DNS_HEADER_SIZE)?;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let records = u16::from_be_bytes([header[6], header[7]]);
+
+    // NOTE(nosiee): a query, an error or more than one question
+    if header[2] & 0x80 == 0 || header[3] & 0x0f != 0 || questions != 1 {
+        return None;
+    }
+
+    let (question, offset) = read_name(reply, DNS_HEADER_SIZE)?;
+    let mut offset = offset + 4;
+
+    let mut answers = Vec::new();
+    for _ in 0..records {
+        let (name, at) = read_name(reply, offset)?;
+        let record = reply.get(at..at + RECORD_HEADER_SIZE)?;
+        let kind = u16::from_be_bytes([record[0], record[1]]);
+        let ttl = u32::from_be_bytes(record[4..8].try_into().unwrap());
+        let length = u16::from_be_bytes([record[8], record[9]]) as usize;
+
+        let data = reply.get(at + RECORD_HEADER_SIZE..at + RECORD_HEADER_SIZE + length)?;
+        offset = at + RECORD_HEADER_SIZE + length;
+
+        let addr = match (kind, length) {
+            (RECORD_A, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (RECORD_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => continue,
+        };
+
+        answers.push(Answer {
+            name,
+            addr,
+            ttl: Duration::from_secs(ttl as u64),
+        });
+    }
+
+    Some((question, answers))
+}
+
+// NOTE(nosiee): the name and the offset right after it where it's written, a compressed name ends with the pointer.
+// the steps are limited, so a pointer loop ends as well
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+
+    for _ in 0..MAX_NAME_STEPS {
+        let length = *message.get(offset)? as usize;
+
+        match length & 0xc0 {
+            0 if length == 0 => return Some((labels.join(".").to_lowercase(), end.unwrap_or(offset + 1))),
+            0 => {
+                let label = message.get(offset + 1..offset + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + length;
+            }
+            0xc0 => {
+                let pointer = u16::from_be_bytes([length as u8 & 0x3f, *message.get(offset + 1)?]) as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+// NOTE(nosiee): the system resolver is pointed to the forwarder or to the leased dns servers, the original file
+// is moved aside. a backup left by a client that didn't exit cleanly is kept, it's the one that was there before olla
+pub fn set_system_resolver(addrs: &[IpAddr]) -> io::Result<()> {
+    if fs::symlink_metadata(RESOLV_CONF_BACKUP).is_err() {
+        fs::rename(RESOLV_CONF, RESOLV_CONF_BACKUP)?;
+    } else {
+        let _ = fs::remove_file(RESOLV_CONF);
+    }
+
+    let mut conf = format!("# written by olla, the original is {}\n", RESOLV_CONF_BACKUP);
+    for addr in addrs {
+        conf.push_str(&format!("nameserver {}\n", addr));
+    }
+
+    fs::write(RESOLV_CONF, conf)
+}
+
+// NOTE(nosiee): false if there was nothing to restore
+pub fn restore_system_resolver() -> io::Result<bool> {
+    if fs::symlink_metadata(RESOLV_CONF_BACKUP).is_err() {
+        return Ok(false);
+    }
+
+    fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD_CNAME: u16 = 5;
+    const QUESTION: usize = DNS_HEADER_SIZE;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+
+        buf.push(0);
+        buf
+    }
+
+    fn pointer(offset: usize) -> Vec<u8> {
+        vec![0xc0 | (offset >> 8) as u8, offset as u8]
+    }
+
+    fn reply(flags: u16, questions: u16, records: u16, question: &str) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34];
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&questions.to_be_bytes());
+        buf.extend_from_slice(&records.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+
+        buf.extend_from_slice(&name(question));
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        buf
+    }
+
+    fn record(buf: &mut Vec<u8>, name: &[u8], kind: u16, ttl: u32, data: &[u8]) {
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(&kind.to_be_bytes());
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    #[test]
+    fn reads_compressed_answers() {
+        let mut buf = reply(0x8180, 1, 2, "Example.COM");
+        record(&mut buf, &pointer(QUESTION), RECORD_A, 300, &[93, 184, 216, 34]);
+        record(&mut buf, &pointer(QUESTION), RECORD_AAAA, 60, &Ipv6Addr::LOCALHOST.octets());
+
+        let (question, answers) = answers(&buf).unwrap();
+        assert_eq!(question, "example.com");
+        assert_eq!(answers.len(), 2);
+
+        assert_eq!(answers[0].name, "example.com");
+        assert_eq!(answers[0].addr, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(answers[0].ttl, Duration::from_secs(300));
+        assert_eq!(answers[1].addr, IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn follows_cname_chain() {
+        let mut buf = reply(0x8180, 1, 2, "www.example.com");
+
+        // NOTE(nosiee): cdn.<example.com of the question>, then the a record points into the cname data
+        let cname_data = buf.len() + 2 + RECORD_HEADER_SIZE;
+        let mut target = vec![3];
+        target.extend_from_slice(b"cdn");
+        target.extend_from_slice(&pointer(QUESTION + 4));
+
+        record(&mut buf, &pointer(QUESTION), RECORD_CNAME, 300, &target);
+        record(&mut buf, &pointer(cname_data), RECORD_A, 30, &[192, 0, 2, 1]);
+
+        let (question, answers) = answers(&buf).unwrap();
+        assert_eq!(question, "www.example.com");
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].name, "cdn.example.com");
+        assert_eq!(answers[0].addr, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn skips_records_of_unexpected_size() {
+        let mut buf = reply(0x8180, 1, 2, "example.com");
+        record(&mut buf, &pointer(QUESTION), RECORD_A, 300, &[192, 0, 2, 1, 0]);
+        record(&mut buf, &pointer(QUESTION), RECORD_AAAA, 300, &[192, 0, 2, 1]);
+
+        assert!(answers(&buf).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn rejects_queries_and_errors() {
+        assert!(answers(&reply(0x0100, 1, 0, "example.com")).is_none());
+        assert!(answers(&reply(0x8183, 1, 0, "example.com")).is_none());
+        assert!(answers(&reply(0x8180, 2, 0, "example.com")).is_none());
+        assert!(answers(&[0x12, 0x34, 0x81, 0x80]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_replies() {
+        let mut buf = reply(0x8180, 1, 1, "example.com");
+        record(&mut buf, &pointer(QUESTION), RECORD_A, 300, &[192, 0, 2, 1]);
+
+        for n in QUESTION..buf.len() {
+            assert!(answers(&buf[..n]).is_none(), "truncated at {}", n);
+        }
+
+        let mut buf = reply(0x8180, 1, 2, "example.com");
+        record(&mut buf, &pointer(QUESTION), RECORD_A, 300, &[192, 0, 2, 1]);
+        assert!(answers(&buf).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        let mut buf = reply(0x8180, 1, 1, "example.com");
+        let at = buf.len();
+        record(&mut buf, &pointer(at), RECORD_A, 300, &[192, 0, 2, 1]);
+        assert!(answers(&buf).is_none());
+
+        let mut buf = reply(0x8180, 1, 1, "example.com");
+        record(&mut buf, &[0x40, 0], RECORD_A, 300, &[192, 0, 2, 1]);
+        assert!(answers(&buf).is_none());
+
+        let mut buf = reply(0x8180, 1, 1, "example.com");
+        record(&mut buf, &pointer(0x3fff), RECORD_A, 300, &[192, 0, 2, 1]);
+        assert!(answers(&buf).is_none());
+    }
+
+    #[test]
+    fn query_ids_are_unique() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut table = QueryTable::default();
+
+        let ids: Vec<u16> = (0..0x100).map(|id| table.insert(client, id).unwrap()).collect();
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+
+        let query = table.remove(ids[7]).unwrap();
+        assert_eq!((query.client, query.id), (client, 7));
+        assert!(table.remove(ids[7]).is_none());
+    }
+
+    #[test]
+    fn reads_message_ids() {
+        let query = reply(0x0100, 1, 0, "example.com");
+        let answer = reply(0x8180, 1, 0, "example.com");
+
+        assert_eq!(message_id(&query, false), Some(0x1234));
+        assert_eq!(message_id(&query, true), None);
+        assert_eq!(message_id(&answer, true), Some(0x1234));
+        assert_eq!(message_id(&answer, false), None);
+        assert_eq!(message_id(&answer[..DNS_HEADER_SIZE - 1], true), None);
+    }
+}
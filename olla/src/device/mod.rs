@@ -1,5 +1,8 @@
 pub mod config;
+pub mod dns;
+pub mod nat;
 pub mod packet;
+pub mod route;
 pub mod util;
 
 use async_channel::{Receiver, Sender};
@@ -99,12 +102,17 @@ impl Device {
     }
 
     fn build_dev(config: DeviceConfig, layer: Layer) -> anyhow::Result<AsyncDevice> {
-        Ok(DeviceBuilder::new()
+        let mut builder = DeviceBuilder::new()
             .name(config.name)
             .mtu(config.mtu)
             .ipv4(config.addr, config.mask, None)
-            .layer(layer)
-            .build_async()?)
+            .layer(layer);
+
+        if let Some(ipv6_addr) = config.ipv6_addr {
+            builder = builder.ipv6(ipv6_addr, config.ipv6_prefix);
+        }
+
+        Ok(builder.build_async()?)
     }
 }
 
@@ -0,0 +1,586 @@
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::net::IpAddr;
+use std::ops::Range;
+
+const IPV4_CHECKSUM: Range<usize> = 10..12;
+const IPV6_HEADER_SIZE: usize = 40;
+const IPV6_FRAGMENT_HEADER_SIZE: usize = 8;
+const ICMP_HEADER_SIZE: usize = 8;
+const UDP_HEADER_SIZE: usize = 8;
+
+const SOURCE_PORT_OFFSET: usize = 0;
+const DESTINATION_PORT_OFFSET: usize = 2;
+const ECHO_ID_OFFSET: usize = 4;
+
+const TCP_CHECKSUM_OFFSET: usize = 16;
+const UDP_CHECKSUM_OFFSET: usize = 6;
+const ICMP_CHECKSUM_OFFSET: usize = 2;
+
+// NOTE(nosiee): the addresses are rewritten in place, the checksums are updated incrementally (rfc 1624).
+// the address must be of the same family as the packet
+pub fn rewrite_source(packet: &mut [u8], addr: IpAddr) -> Option<()> {
+    rewrite(packet, addr, true)
+}
+
+pub fn rewrite_destination(packet: &mut [u8], addr: IpAddr) -> Option<()> {
+    rewrite(packet, addr, false)
+}
+
+fn rewrite(packet: &mut [u8], addr: IpAddr, source: bool) -> Option<()> {
+    let (range, new) = match (packet.first()? >> 4, addr) {
+        (4, IpAddr::V4(addr)) => (if source { 12..16 } else { 16..20 }, addr.octets().to_vec()),
+        (6, IpAddr::V6(addr)) => (if source { 8..24 } else { 24..40 }, addr.octets().to_vec()),
+        _ => return None,
+    };
+
+    let old = packet.get(range.clone())?.to_vec();
+    let (protocol, offset) = get_transport_offset(packet)?;
+
+    packet[range].copy_from_slice(&new);
+    if packet[0] >> 4 == 4 {
+        update_checksum(packet, IPV4_CHECKSUM.start, &old, &new);
+    }
+
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Some(()),
+    };
+
+    match protocol {
+        IpNextHeaderProtocols::Tcp => update_checksum(packet, offset + TCP_CHECKSUM_OFFSET, &old, &new),
+        IpNextHeaderProtocols::Udp => update_udp_checksum(packet, offset + UDP_CHECKSUM_OFFSET, &old, &new),
+        IpNextHeaderProtocols::Icmpv6 => update_checksum(packet, offset + ICMP_CHECKSUM_OFFSET, &old, &new),
+        _ => (),
+    }
+
+    // NOTE(nosiee): an icmp error carries the packet it's about, that packet was sent from the address being restored
+    if !source && matches!(protocol, IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6) {
+        rewrite_embedded(packet, protocol, offset, &old, &new);
+    }
+
+    Some(())
+}
+
+// NOTE(nosiee): the port of tcp and udp, the identifier of an icmp echo whatever the direction is
+pub fn get_source_port(packet: &[u8]) -> Option<(IpNextHeaderProtocol, u16)> {
+    let (protocol, at, _) = get_port_field(packet, true)?;
+    Some((protocol, u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?)))
+}
+
+pub fn get_destination_port(packet: &[u8]) -> Option<(IpNextHeaderProtocol, u16)> {
+    let (protocol, at, _) = get_port_field(packet, false)?;
+    Some((protocol, u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?)))
+}
+
+pub fn rewrite_source_port(packet: &mut [u8], port: u16) -> Option<()> {
+    rewrite_port(packet, port, true)
+}
+
+pub fn rewrite_destination_port(packet: &mut [u8], port: u16) -> Option<()> {
+    rewrite_port(packet, port, false)
+}
+
+// NOTE(nosiee): the protocol and the source port of the packet an icmpv4 error carries
+pub fn get_embedded_source_port(packet: &[u8]) -> Option<(IpNextHeaderProtocol, u16)> {
+    let (_, at) = get_embedded_port_field(packet)?;
+    let embedded = get_icmp_error_offset(packet)?;
+
+    Some((
+        IpNextHeaderProtocol(packet[embedded + 9]),
+        u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?),
+    ))
+}
+
+// NOTE(nosiee): the udp payload as long as the udp header says, none for a non-first fragment
+pub fn get_udp_payload(packet: &[u8]) -> Option<&[u8]> {
+    let (protocol, offset) = get_transport_offset(packet)?;
+    if protocol != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+
+    let offset = offset?;
+    let length = u16::from_be_bytes(packet.get(offset + 4..offset + 6)?.try_into().ok()?) as usize;
+
+    packet.get(offset + UDP_HEADER_SIZE..offset + length)
+}
+
+// NOTE(nosiee): only the icmp checksum is updated, the embedded packet is truncated more often than not
+pub fn rewrite_embedded_source_port(packet: &mut [u8], port: u16) -> Option<()> {
+    let (checksum, at) = get_embedded_port_field(packet)?;
+    let old = packet.get(at..at + 2)?.to_vec();
+    let new = port.to_be_bytes();
+
+    packet[at..at + 2].copy_from_slice(&new);
+    update_checksum(packet, checksum, &old, &new);
+
+    Some(())
+}
+
+fn rewrite_port(packet: &mut [u8], port: u16, source: bool) -> Option<()> {
+    let (protocol, at, checksum) = get_port_field(packet, source)?;
+    let old = packet.get(at..at + 2)?.to_vec();
+    let new = port.to_be_bytes();
+
+    packet[at..at + 2].copy_from_slice(&new);
+    match protocol {
+        IpNextHeaderProtocols::Udp => update_udp_checksum(packet, checksum, &old, &new),
+        _ => update_checksum(packet, checksum, &old, &new),
+    }
+
+    Some(())
+}
+
+// NOTE(nosiee): the offsets of the port and of the checksum that covers it, none for a non-first fragment
+fn get_port_field(packet: &[u8], source: bool) -> Option<(IpNextHeaderProtocol, usize, usize)> {
+    let (protocol, offset) = get_transport_offset(packet)?;
+    let offset = offset?;
+    let port = if source { SOURCE_PORT_OFFSET } else { DESTINATION_PORT_OFFSET };
+
+    match protocol {
+        IpNextHeaderProtocols::Tcp => Some((protocol, offset + port, offset + TCP_CHECKSUM_OFFSET)),
+        IpNextHeaderProtocols::Udp => Some((protocol, offset + port, offset + UDP_CHECKSUM_OFFSET)),
+        IpNextHeaderProtocols::Icmp if matches!(packet.get(offset)?, 0 | 8) => {
+            Some((protocol, offset + ECHO_ID_OFFSET, offset + ICMP_CHECKSUM_OFFSET))
+        }
+        IpNextHeaderProtocols::Icmpv6 if matches!(packet.get(offset)?, 128 | 129) => {
+            Some((protocol, offset + ECHO_ID_OFFSET, offset + ICMP_CHECKSUM_OFFSET))
+        }
+        _ => None,
+    }
+}
+
+fn get_embedded_port_field(packet: &[u8]) -> Option<(usize, usize)> {
+    let embedded = get_icmp_error_offset(packet)?;
+    let header_length = (*packet.get(embedded)? & 0x0f) as usize * 4;
+    let transport = embedded + header_length;
+
+    let at = match IpNextHeaderProtocol(*packet.get(embedded + 9)?) {
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => transport + SOURCE_PORT_OFFSET,
+        IpNextHeaderProtocols::Icmp => transport + ECHO_ID_OFFSET,
+        _ => return None,
+    };
+
+    packet.get(at..at + 2)?;
+    Some((embedded - ICMP_HEADER_SIZE + ICMP_CHECKSUM_OFFSET, at))
+}
+
+fn get_icmp_error_offset(packet: &[u8]) -> Option<usize> {
+    let (protocol, offset) = get_transport_offset(packet)?;
+    let offset = offset?;
+
+    if protocol != IpNextHeaderProtocols::Icmp || !matches!(packet.get(offset)?, 3 | 4 | 11 | 12) {
+        return None;
+    }
+
+    let embedded = offset + ICMP_HEADER_SIZE;
+    (*packet.get(embedded)? >> 4 == 4).then_some(embedded)
+}
+
+fn rewrite_embedded(packet: &mut [u8], protocol: IpNextHeaderProtocol, offset: usize, old: &[u8], new: &[u8]) {
+    let is_error = match (protocol, packet.get(offset)) {
+        (IpNextHeaderProtocols::Icmp, Some(kind)) => matches!(kind, 3 | 4 | 11 | 12),
+        (IpNextHeaderProtocols::Icmpv6, Some(kind)) => matches!(kind, 1..=4),
+        _ => false,
+    };
+
+    let embedded = offset + ICMP_HEADER_SIZE;
+    if !is_error || packet.len() <= embedded {
+        return;
+    }
+
+    let range = match packet[embedded] >> 4 {
+        4 => embedded + 12..embedded + 16,
+        6 => embedded + 8..embedded + 24,
+        _ => return,
+    };
+
+    if packet.get(range.clone()) != Some(old) {
+        return;
+    }
+
+    packet[range].copy_from_slice(new);
+    update_checksum(packet, offset + ICMP_CHECKSUM_OFFSET, old, new);
+
+    if packet[embedded] >> 4 == 4 && packet.len() >= embedded + IPV4_CHECKSUM.end {
+        let old_checksum = packet[embedded + IPV4_CHECKSUM.start..embedded + IPV4_CHECKSUM.end].to_vec();
+        update_checksum(packet, embedded + IPV4_CHECKSUM.start, old, new);
+
+        let new_checksum = packet[embedded + IPV4_CHECKSUM.start..embedded + IPV4_CHECKSUM.end].to_vec();
+        update_checksum(packet, offset + ICMP_CHECKSUM_OFFSET, &old_checksum, &new_checksum);
+    }
+}
+
+// NOTE(nosiee): the transport header offset is none for a non-first fragment, it has no transport header
+fn get_transport_offset(packet: &[u8]) -> Option<(IpNextHeaderProtocol, Option<usize>)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_length = (packet[0] & 0x0f) as usize * 4;
+            let fragment_offset = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?) & 0x1fff;
+            let protocol = IpNextHeaderProtocol(*packet.get(9)?);
+
+            Some((protocol, (fragment_offset == 0).then_some(header_length)))
+        }
+        6 => {
+            let protocol = IpNextHeaderProtocol(*packet.get(6)?);
+            if protocol != IpNextHeaderProtocols::Ipv6Frag {
+                return Some((protocol, Some(IPV6_HEADER_SIZE)));
+            }
+
+            let fragment_header = packet.get(IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + IPV6_FRAGMENT_HEADER_SIZE)?;
+            let fragment_offset = u16::from_be_bytes([fragment_header[2], fragment_header[3]]) >> 3;
+
+            Some((
+                IpNextHeaderProtocol(fragment_header[0]),
+                (fragment_offset == 0).then_some(IPV6_HEADER_SIZE + IPV6_FRAGMENT_HEADER_SIZE),
+            ))
+        }
+        _ => None,
+    }
+}
+
+// NOTE(nosiee): zero udp checksum over ipv4 means no checksum, a computed zero is sent as 0xffff
+fn update_udp_checksum(packet: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let is_ipv4 = packet[0] >> 4 == 4;
+    if packet.get(at..at + 2).is_none_or(|field| is_ipv4 && field == [0, 0]) {
+        return;
+    }
+
+    update_checksum(packet, at, old, new);
+    if packet[at..at + 2] == [0, 0] {
+        packet[at..at + 2].copy_from_slice(&[0xff, 0xff]);
+    }
+}
+
+fn update_checksum(packet: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let field = match packet.get_mut(at..at + 2) {
+        Some(field) => field,
+        None => return,
+    };
+
+    let word = |chunk: &[u8]| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or_default()]) as u32;
+    let mut sum = !u16::from_be_bytes([field[0], field[1]]) as u32;
+
+    sum += old.chunks(2).map(|chunk| !word(chunk) & 0xffff).sum::<u32>();
+    sum += new.chunks(2).map(word).sum::<u32>();
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    field.copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::icmp::{self, IcmpPacket};
+    use pnet::packet::icmpv6::{self, Icmpv6Packet};
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::tcp::{self, TcpPacket};
+    use pnet::packet::udp::{self, UdpPacket};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OUTER: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    fn ipv4(protocol: IpNextHeaderProtocol, source: Ipv4Addr, destination: Ipv4Addr, transport: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0x12, 0x34, 0x40, 0, 64, protocol.0, 0, 0];
+        packet[2..4].copy_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(transport);
+
+        packet
+    }
+
+    fn ipv6(protocol: IpNextHeaderProtocol, source: Ipv6Addr, destination: Ipv6Addr, transport: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, protocol.0, 64];
+        packet[4..6].copy_from_slice(&(transport.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(transport);
+
+        packet
+    }
+
+    fn tcp(source: u16, destination: u16) -> Vec<u8> {
+        let mut segment = vec![0; 20];
+        segment[..2].copy_from_slice(&source.to_be_bytes());
+        segment[2..4].copy_from_slice(&destination.to_be_bytes());
+        segment[12] = 0x50;
+        segment.extend_from_slice(b"olla tcp payload");
+
+        segment
+    }
+
+    fn udp(source: u16, destination: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; 8];
+        datagram[..2].copy_from_slice(&source.to_be_bytes());
+        datagram[2..4].copy_from_slice(&destination.to_be_bytes());
+        datagram[4..6].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(payload);
+
+        datagram
+    }
+
+    fn echo(kind: u8, id: u16) -> Vec<u8> {
+        let mut message = vec![kind, 0, 0, 0, 0, 0, 0, 1];
+        message[4..6].copy_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(b"ping");
+
+        message
+    }
+
+    // NOTE(nosiee): a port unreachable for a udp packet the node sent, only 8 bytes of its udp header are quoted
+    fn icmp_error() -> Vec<u8> {
+        let mut embedded = ipv4(IpNextHeaderProtocols::Udp, OUTER, REMOTE, &udp(1024, 53, b"olla udp"));
+        embedded.truncate(28);
+        let checksum = ipv4::checksum(&Ipv4Packet::new(&embedded).unwrap());
+        embedded[IPV4_CHECKSUM].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        error.extend_from_slice(&embedded);
+
+        error
+    }
+
+    // NOTE(nosiee): the checksums computed from scratch, a rewritten packet must come out the same
+    fn checksummed(mut packet: Vec<u8>) -> Vec<u8> {
+        let set = |packet: &mut [u8], at: usize, checksum: u16| packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        match packet[0] >> 4 {
+            4 => {
+                let (source, destination) = {
+                    let ip_packet = Ipv4Packet::new(&packet).unwrap();
+                    (ip_packet.get_source(), ip_packet.get_destination())
+                };
+
+                packet[IPV4_CHECKSUM].fill(0);
+                let checksum = ipv4::checksum(&Ipv4Packet::new(&packet).unwrap());
+                set(&mut packet, IPV4_CHECKSUM.start, checksum);
+
+                let transport = &packet[20..];
+                match IpNextHeaderProtocol(packet[9]) {
+                    IpNextHeaderProtocols::Tcp => {
+                        let checksum = tcp::ipv4_checksum(&TcpPacket::new(transport).unwrap(), &source, &destination);
+                        set(&mut packet, 20 + TCP_CHECKSUM_OFFSET, checksum);
+                    }
+                    IpNextHeaderProtocols::Udp => {
+                        let checksum = udp::ipv4_checksum(&UdpPacket::new(transport).unwrap(), &source, &destination);
+                        set(&mut packet, 20 + UDP_CHECKSUM_OFFSET, checksum);
+                    }
+                    IpNextHeaderProtocols::Icmp => {
+                        let checksum = icmp::checksum(&IcmpPacket::new(transport).unwrap());
+                        set(&mut packet, 20 + ICMP_CHECKSUM_OFFSET, checksum);
+                    }
+                    _ => (),
+                }
+            }
+            6 => {
+                let source = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+                let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+
+                let transport = &packet[IPV6_HEADER_SIZE..];
+                match IpNextHeaderProtocol(packet[6]) {
+                    IpNextHeaderProtocols::Tcp => {
+                        let checksum = tcp::ipv6_checksum(&TcpPacket::new(transport).unwrap(), &source, &destination);
+                        set(&mut packet, IPV6_HEADER_SIZE + TCP_CHECKSUM_OFFSET, checksum);
+                    }
+                    IpNextHeaderProtocols::Udp => {
+                        let checksum = udp::ipv6_checksum(&UdpPacket::new(transport).unwrap(), &source, &destination);
+                        set(&mut packet, IPV6_HEADER_SIZE + UDP_CHECKSUM_OFFSET, checksum);
+                    }
+                    IpNextHeaderProtocols::Icmpv6 => {
+                        let checksum = icmpv6::checksum(&Icmpv6Packet::new(transport).unwrap(), &source, &destination);
+                        set(&mut packet, IPV6_HEADER_SIZE + ICMP_CHECKSUM_OFFSET, checksum);
+                    }
+                    _ => (),
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        packet
+    }
+
+    #[test]
+    fn rewrites_ipv4_tcp_source() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Tcp, CLIENT, REMOTE, &tcp(40000, 443)));
+
+        rewrite_source(&mut packet, IpAddr::V4(OUTER)).unwrap();
+
+        assert_eq!(Ipv4Packet::new(&packet).unwrap().get_source(), OUTER);
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn rewrites_ipv4_udp_destination() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Udp, REMOTE, OUTER, &udp(53, 40000, b"olla udp")));
+
+        rewrite_destination(&mut packet, IpAddr::V4(CLIENT)).unwrap();
+
+        assert_eq!(Ipv4Packet::new(&packet).unwrap().get_destination(), CLIENT);
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn keeps_zero_ipv4_udp_checksum() {
+        let mut packet = ipv4(IpNextHeaderProtocols::Udp, CLIENT, REMOTE, &udp(40000, 53, b"olla udp"));
+        let checksum = ipv4::checksum(&Ipv4Packet::new(&packet).unwrap());
+        packet[IPV4_CHECKSUM].copy_from_slice(&checksum.to_be_bytes());
+
+        rewrite_source(&mut packet, IpAddr::V4(OUTER)).unwrap();
+
+        assert_eq!(packet[20 + UDP_CHECKSUM_OFFSET..20 + UDP_CHECKSUM_OFFSET + 2], [0, 0]);
+        assert_eq!(packet[IPV4_CHECKSUM], checksummed(packet.clone())[IPV4_CHECKSUM]);
+    }
+
+    #[test]
+    fn rewrites_ipv6_udp_and_icmpv6() {
+        let (client, outer, remote): (Ipv6Addr, Ipv6Addr, Ipv6Addr) =
+            ("fd00::2".parse().unwrap(), "fd08::1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+
+        let mut packet = checksummed(ipv6(IpNextHeaderProtocols::Udp, client, remote, &udp(40000, 53, b"olla udp")));
+        rewrite_source(&mut packet, IpAddr::V6(outer)).unwrap();
+        assert_eq!(packet, checksummed(packet.clone()));
+
+        let mut packet = checksummed(ipv6(IpNextHeaderProtocols::Icmpv6, remote, outer, &echo(129, 7)));
+        rewrite_destination(&mut packet, IpAddr::V6(client)).unwrap();
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn restores_icmp_error_embedded_address() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Icmp, Ipv4Addr::new(203, 0, 113, 1), OUTER, &icmp_error()));
+
+        rewrite_destination(&mut packet, IpAddr::V4(CLIENT)).unwrap();
+
+        let embedded = &packet[28..];
+        assert_eq!(Ipv4Packet::new(embedded).unwrap().get_source(), CLIENT);
+        assert_eq!(
+            Ipv4Packet::new(embedded).unwrap().get_checksum(),
+            ipv4::checksum(&Ipv4Packet::new(embedded).unwrap())
+        );
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn rewrites_only_the_header_of_non_first_fragment() {
+        let mut packet = ipv4(IpNextHeaderProtocols::Udp, CLIENT, REMOTE, &udp(40000, 53, b"olla udp"));
+        packet[6..8].copy_from_slice(&0x0010u16.to_be_bytes());
+        let packet = checksummed(packet);
+
+        let mut rewritten = packet.clone();
+        rewrite_source(&mut rewritten, IpAddr::V4(OUTER)).unwrap();
+        assert_eq!(rewritten[20..], packet[20..]);
+        assert_eq!(rewritten[IPV4_CHECKSUM], checksummed(rewritten.clone())[IPV4_CHECKSUM]);
+    }
+
+    #[test]
+    fn rewrites_ipv4_tcp_ports() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Tcp, CLIENT, REMOTE, &tcp(40000, 443)));
+
+        rewrite_source(&mut packet, IpAddr::V4(OUTER)).unwrap();
+        rewrite_source_port(&mut packet, 1024).unwrap();
+
+        assert_eq!(get_source_port(&packet), Some((IpNextHeaderProtocols::Tcp, 1024)));
+        assert_eq!(get_destination_port(&packet), Some((IpNextHeaderProtocols::Tcp, 443)));
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn rewrites_ipv4_udp_ports() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Udp, REMOTE, OUTER, &udp(53, 1024, b"olla udp")));
+
+        rewrite_destination(&mut packet, IpAddr::V4(CLIENT)).unwrap();
+        rewrite_destination_port(&mut packet, 40000).unwrap();
+
+        assert_eq!(get_destination_port(&packet), Some((IpNextHeaderProtocols::Udp, 40000)));
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn keeps_zero_ipv4_udp_checksum_with_ports() {
+        let mut packet = ipv4(IpNextHeaderProtocols::Udp, CLIENT, REMOTE, &udp(40000, 53, b"olla udp"));
+        let checksum = ipv4::checksum(&Ipv4Packet::new(&packet).unwrap());
+        packet[IPV4_CHECKSUM].copy_from_slice(&checksum.to_be_bytes());
+
+        rewrite_source_port(&mut packet, 1024).unwrap();
+
+        assert_eq!(packet[20 + UDP_CHECKSUM_OFFSET..20 + UDP_CHECKSUM_OFFSET + 2], [0, 0]);
+    }
+
+    #[test]
+    fn rewrites_ipv4_icmp_echo_identifier() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Icmp, CLIENT, REMOTE, &echo(8, 7)));
+
+        rewrite_source(&mut packet, IpAddr::V4(OUTER)).unwrap();
+        rewrite_source_port(&mut packet, 1024).unwrap();
+
+        assert_eq!(get_source_port(&packet), Some((IpNextHeaderProtocols::Icmp, 1024)));
+        assert_eq!(get_destination_port(&packet), Some((IpNextHeaderProtocols::Icmp, 1024)));
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn rewrites_ipv6_ports_and_icmpv6_identifier() {
+        let (client, outer, remote): (Ipv6Addr, Ipv6Addr, Ipv6Addr) =
+            ("fd00::2".parse().unwrap(), "fd08::1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+
+        let mut packet = checksummed(ipv6(IpNextHeaderProtocols::Udp, client, remote, &udp(40000, 53, b"olla udp")));
+        rewrite_source(&mut packet, IpAddr::V6(outer)).unwrap();
+        rewrite_source_port(&mut packet, 1024).unwrap();
+        assert_eq!(get_source_port(&packet), Some((IpNextHeaderProtocols::Udp, 1024)));
+        assert_eq!(packet, checksummed(packet.clone()));
+
+        let mut packet = checksummed(ipv6(IpNextHeaderProtocols::Icmpv6, remote, outer, &echo(129, 1024)));
+        rewrite_destination(&mut packet, IpAddr::V6(client)).unwrap();
+        rewrite_destination_port(&mut packet, 7).unwrap();
+        assert_eq!(get_destination_port(&packet), Some((IpNextHeaderProtocols::Icmpv6, 7)));
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn restores_icmp_error_embedded_port() {
+        let mut packet = checksummed(ipv4(IpNextHeaderProtocols::Icmp, Ipv4Addr::new(203, 0, 113, 1), OUTER, &icmp_error()));
+
+        rewrite_destination(&mut packet, IpAddr::V4(CLIENT)).unwrap();
+        assert_eq!(get_embedded_source_port(&packet), Some((IpNextHeaderProtocols::Udp, 1024)));
+        rewrite_embedded_source_port(&mut packet, 40000).unwrap();
+
+        assert_eq!(get_embedded_source_port(&packet), Some((IpNextHeaderProtocols::Udp, 40000)));
+        assert_eq!(packet, checksummed(packet.clone()));
+    }
+
+    #[test]
+    fn has_no_embedded_port_outside_icmp_errors() {
+        let packet = checksummed(ipv4(IpNextHeaderProtocols::Icmp, REMOTE, OUTER, &echo(0, 1024)));
+
+        assert_eq!(get_embedded_source_port(&packet), None);
+    }
+
+    #[test]
+    fn has_no_ports_in_non_first_fragment() {
+        let mut packet = ipv4(IpNextHeaderProtocols::Udp, CLIENT, REMOTE, &udp(40000, 53, b"olla udp"));
+        packet[6..8].copy_from_slice(&0x0010u16.to_be_bytes());
+        let packet = checksummed(packet);
+
+        assert_eq!(get_source_port(&packet), None);
+        assert_eq!(get_destination_port(&packet), None);
+        assert!(rewrite_source_port(&mut packet.clone(), 1024).is_none());
+    }
+
+    #[test]
+    fn reads_the_udp_payload() {
+        let packet = checksummed(ipv4(IpNextHeaderProtocols::Udp, REMOTE, CLIENT, &udp(53, 40000, b"olla udp")));
+        assert_eq!(get_udp_payload(&packet), Some(&b"olla udp"[..]));
+
+        let packet = checksummed(ipv4(IpNextHeaderProtocols::Tcp, REMOTE, CLIENT, &tcp(443, 40000)));
+        assert_eq!(get_udp_payload(&packet), None);
+
+        let mut packet = ipv4(IpNextHeaderProtocols::Udp, REMOTE, CLIENT, &udp(53, 40000, b"olla udp"));
+        packet[6..8].copy_from_slice(&0x0010u16.to_be_bytes());
+        assert_eq!(get_udp_payload(&checksummed(packet)), None);
+    }
+}
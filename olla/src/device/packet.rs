@@ -2,6 +2,8 @@ use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::packet::icmp::echo_reply::EchoReplyPacket;
 use pnet::packet::icmp::echo_request::EchoRequestPacket;
 use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::echo_reply::EchoReplyPacket as Icmpv6EchoReplyPacket;
+use pnet::packet::icmpv6::echo_request::EchoRequestPacket as Icmpv6EchoRequestPacket;
 use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
@@ -59,3 +61,11 @@ pub fn to_icmp_request<'a>(packet: &'a [u8]) -> Option<EchoRequestPacket<'a>> {
 pub fn to_icmp_reply<'a>(packet: &'a [u8]) -> Option<EchoReplyPacket<'a>> {
     EchoReplyPacket::new(packet)
 }
+
+pub fn to_icmpv6_request<'a>(packet: &'a [u8]) -> Option<Icmpv6EchoRequestPacket<'a>> {
+    Icmpv6EchoRequestPacket::new(packet)
+}
+
+pub fn to_icmpv6_reply<'a>(packet: &'a [u8]) -> Option<Icmpv6EchoReplyPacket<'a>> {
+    Icmpv6EchoReplyPacket::new(packet)
+}
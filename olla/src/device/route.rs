@@ -0,0 +1,295 @@
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::{route, rule};
+use netlink_packet_route::{AF_INET, AF_INET6, FIB_RULE_INVERT, FR_ACT_TO_TBL, RT_TABLE_MAIN, RTN_THROW, RTN_UNREACHABLE, RouteMessage, RuleMessage};
+use pnet::datalink;
+use pnet::ipnetwork::IpNetwork;
+use rtnetlink::{Handle, IpVersion};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::{debug, error};
+
+pub const DEFAULT_ROUTE_TABLE: u32 = 0x6f6c;
+pub const DEFAULT_FWMARK: u32 = 0x6f6c;
+
+// NOTE(nosiee): the main table lookup goes right before the tunnel table, the same as wg-quick does
+const RULE_PRIORITY: u32 = 0x7d00;
+
+// NOTE(nosiee): the kill switch route loses to any route to the device, the default one included
+const KILL_SWITCH_METRIC: u32 = 0x7fffffff;
+
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    pub device: String,
+    pub destinations: Vec<IpNetwork>,
+    pub excluded: Vec<IpAddr>,
+    pub table: u32,
+    pub fwmark: u32,
+    pub kill_switch: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Device(u32),
+    Throw,
+    Unreachable,
+}
+
+// NOTE(nosiee): the destinations are routed to the device in a table of their own, everything without
+// the fwmark looks it up. the tunnel sockets carry the fwmark, so they use the main table even with a default route,
+// the node addresses are thrown out of the table for everything else. only what was installed is removed on down.
+// the bypassed addresses are thrown out of the table the same way while the client is running
+#[derive(Debug)]
+pub struct Routes {
+    handle: Handle,
+    table: u32,
+    routes: Vec<RouteMessage>,
+    rules: Vec<RuleMessage>,
+    bypassed: HashMap<IpAddr, RouteMessage>,
+}
+
+impl Routes {
+    pub async fn up(config: RouteConfig) -> anyhow::Result<Self> {
+        let mut routes = Self::connect(config.table)?;
+
+        if let Err(err) = routes.install(&config).await {
+            routes.down().await;
+            return Err(err);
+        }
+
+        Ok(routes)
+    }
+
+    pub async fn down(&mut self) {
+        for (_, route) in self.bypassed.drain() {
+            if let Err(err) = self.handle.route().del(route).execute().await {
+                error!("failed to delete the bypass route: {}", err);
+            }
+        }
+
+        for rule in std::mem::take(&mut self.rules).into_iter().rev() {
+            if let Err(err) = self.handle.rule().del(rule).execute().await {
+                error!("failed to delete the rule: {}", err);
+            }
+        }
+
+        for route in std::mem::take(&mut self.routes).into_iter().rev() {
+            if let Err(err) = self.handle.route().del(route).execute().await {
+                error!("failed to delete the route: {}", err);
+            }
+        }
+    }
+
+    pub async fn bypass(&mut self, addr: IpAddr) -> anyhow::Result<()> {
+        if self.bypassed.contains_key(&addr) {
+            return Ok(());
+        }
+
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let route = self.route(IpNetwork::new(addr, prefix)?, self.table, Target::Throw);
+
+        let mut request = self.handle.route().add().replace();
+        *request.message_mut() = route.clone();
+        request.execute().await?;
+
+        debug!("{} bypassed", addr);
+        self.bypassed.insert(addr, route);
+
+        Ok(())
+    }
+
+    pub async fn unbypass(&mut self, addr: IpAddr) {
+        let route = match self.bypassed.remove(&addr) {
+            Some(route) => route,
+            None => return,
+        };
+
+        match self.handle.route().del(route).execute().await {
+            Ok(_) => debug!("{} no longer bypassed", addr),
+            Err(err) => error!("failed to delete the bypass route: {}", err),
+        }
+    }
+
+    pub fn bypassed(&self) -> Vec<IpAddr> {
+        self.bypassed.keys().copied().collect()
+    }
+
+    // NOTE(nosiee): the routes to the device are gone along with the device, the rest is left by a client that didn't
+    // exit cleanly, the kill switch for one. it's removed here, what isn't there is skipped
+    pub async fn clear(config: RouteConfig) -> anyhow::Result<()> {
+        let routes = Self::connect(config.table)?;
+        let (planned_routes, planned_rules) = routes.plan(&config, None)?;
+
+        for rule in planned_rules {
+            if routes.handle.rule().del(rule.clone()).execute().await.is_ok() {
+                debug!("rule deleted: {:?}", rule);
+            }
+        }
+
+        for route in planned_routes.into_iter().chain(routes.leftover_bypass().await?) {
+            if routes.handle.route().del(route.clone()).execute().await.is_ok() {
+                debug!("route deleted: {:?}", route);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect(table: u32) -> anyhow::Result<Self> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        Ok(Self {
+            handle,
+            table,
+            routes: Vec::new(),
+            rules: Vec::new(),
+            bypassed: HashMap::new(),
+        })
+    }
+
+    // NOTE(nosiee): the host throw routes in the table, the bypassed addresses of a client that didn't exit cleanly
+    async fn leftover_bypass(&self) -> anyhow::Result<Vec<RouteMessage>> {
+        let mut leftover = Vec::new();
+
+        for version in [IpVersion::V4, IpVersion::V6] {
+            let prefix = if version == IpVersion::V4 { 32 } else { 128 };
+            let mut dump = self.handle.route().get(version).execute();
+
+            while let Some(route) = dump.try_next().await? {
+                let table = route.nlas.iter().find_map(|nla| match nla {
+                    route::Nla::Table(table) => Some(*table),
+                    _ => None,
+                });
+
+                if table.unwrap_or(route.header.table as u32) == self.table
+                    && route.header.kind == RTN_THROW
+                    && route.header.destination_prefix_length == prefix
+                {
+                    leftover.push(route);
+                }
+            }
+        }
+
+        Ok(leftover)
+    }
+
+    async fn install(&mut self, config: &RouteConfig) -> anyhow::Result<()> {
+        let index = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == config.device)
+            .map(|iface| iface.index)
+            .ok_or_else(|| anyhow::anyhow!("no such device: {}", config.device))?;
+
+        let (routes, rules) = self.plan(config, Some(index))?;
+        for route in routes {
+            self.add_route(route).await?;
+        }
+
+        for rule in rules {
+            self.add_rule(rule).await?;
+        }
+
+        Ok(())
+    }
+
+    // NOTE(nosiee): with the kill switch both families are looked up in the table whatever the destinations are,
+    // what isn't routed to the device or thrown out is unreachable. without the device index the device routes are skipped
+    fn plan(&self, config: &RouteConfig, index: Option<u32>) -> anyhow::Result<(Vec<RouteMessage>, Vec<RuleMessage>)> {
+        let mut routes = Vec::new();
+        let mut rules = Vec::new();
+
+        for ipv4 in [true, false] {
+            let destinations: Vec<&IpNetwork> = config.destinations.iter().filter(|net| net.is_ipv4() == ipv4).collect();
+            if destinations.is_empty() && !config.kill_switch {
+                continue;
+            }
+
+            if let Some(index) = index {
+                for net in destinations.iter() {
+                    routes.push(self.route(**net, config.table, Target::Device(index)));
+                }
+            }
+
+            for addr in config.excluded.iter().filter(|addr| addr.is_ipv4() == ipv4) {
+                let prefix = if ipv4 { 32 } else { 128 };
+                routes.push(self.route(IpNetwork::new(*addr, prefix)?, config.table, Target::Throw));
+            }
+
+            if config.kill_switch {
+                let any = if ipv4 {
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                } else {
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                };
+                routes.push(self.route(IpNetwork::new(any, 0)?, config.table, Target::Unreachable));
+            }
+
+            if destinations.iter().any(|net| net.prefix() == 0) {
+                let mut rule = self.rule(ipv4, RT_TABLE_MAIN as u32, RULE_PRIORITY - 1);
+                rule.nlas.push(rule::Nla::SuppressPrefixLen(0));
+                rules.push(rule);
+            }
+
+            let mut rule = self.rule(ipv4, config.table, RULE_PRIORITY);
+            rule.header.flags |= FIB_RULE_INVERT;
+            rule.nlas.push(rule::Nla::FwMark(config.fwmark));
+            rules.push(rule);
+        }
+
+        Ok((routes, rules))
+    }
+
+    async fn add_route(&mut self, route: RouteMessage) -> anyhow::Result<()> {
+        let mut request = self.handle.route().add().replace();
+        *request.message_mut() = route.clone();
+        request.execute().await?;
+
+        debug!("route added: {:?}", route);
+        self.routes.push(route);
+
+        Ok(())
+    }
+
+    // NOTE(nosiee): the same rule may be left by a run that didn't exit cleanly, it's deleted first
+    async fn add_rule(&mut self, rule: RuleMessage) -> anyhow::Result<()> {
+        let _ = self.handle.rule().del(rule.clone()).execute().await;
+
+        let mut request = self.handle.rule().add();
+        *request.message_mut() = rule.clone();
+        request.execute().await?;
+
+        debug!("rule added: {:?}", rule);
+        self.rules.push(rule);
+
+        Ok(())
+    }
+
+    // NOTE(nosiee): a throw route ends the lookup in the table, it goes on with the next rule
+    fn route(&self, destination: IpNetwork, table: u32, target: Target) -> RouteMessage {
+        let request = self.handle.route().add().table_id(table);
+        let request = match target {
+            Target::Device(index) => request.output_interface(index),
+            Target::Throw => request.kind(RTN_THROW),
+            Target::Unreachable => request.kind(RTN_UNREACHABLE),
+        };
+
+        let mut route = match destination {
+            IpNetwork::V4(net) => request.v4().destination_prefix(net.network(), net.prefix()).message_mut().clone(),
+            IpNetwork::V6(net) => request.v6().destination_prefix(net.network(), net.prefix()).message_mut().clone(),
+        };
+
+        if let Target::Unreachable = target {
+            route.nlas.push(route::Nla::Priority(KILL_SWITCH_METRIC));
+        }
+
+        route
+    }
+
+    fn rule(&self, ipv4: bool, table: u32, priority: u32) -> RuleMessage {
+        let mut request = self.handle.rule().add().table_id(table).action(FR_ACT_TO_TBL).priority(priority);
+        let mut rule = request.message_mut().clone();
+        rule.header.family = if ipv4 { AF_INET } else { AF_INET6 } as u8;
+
+        rule
+    }
+}
@@ -1,10 +1,40 @@
 use pnet::datalink::{self, NetworkInterface};
 use pnet::ipnetwork::IpNetwork;
-use pnet::packet::{ethernet::EtherTypes, ip::IpNextHeaderProtocols, Packet};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::icmpv6::Icmpv6Types;
+use pnet::packet::ipv4::Ipv4Flags;
+use pnet::packet::{Packet, ethernet::EtherTypes, ip::IpNextHeaderProtocol, ip::IpNextHeaderProtocols};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use super::packet::*;
 
+const ICMP_HEADER_SIZE: usize = 8;
+const IPV6_HEADER_SIZE: usize = 40;
+const IPV6_FRAGMENT_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: IpNextHeaderProtocol,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: IpNextHeaderProtocol,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment {
+    pub key: FragmentKey,
+    pub first: bool,
+}
+
 pub fn get_source_identity(buf: &[u8]) -> Option<String> {
     let eth_pkt = from_ip_payload(buf)?;
 
@@ -12,20 +42,21 @@ pub fn get_source_identity(buf: &[u8]) -> Option<String> {
         EtherTypes::Ipv4 => {
             let ip_pkt = to_ipv4(eth_pkt.payload())?;
             let src_ip = ip_pkt.get_source();
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
 
-            let src_id = match ip_pkt.get_next_level_protocol() {
+            let src_id = match protocol {
                 IpNextHeaderProtocols::Tcp => {
-                    let tcp_pkt = to_tcp(ip_pkt.payload())?;
+                    let tcp_pkt = to_tcp(payload?)?;
                     tcp_pkt.get_source()
                 }
                 IpNextHeaderProtocols::Udp => {
-                    let udp_pkt = to_udp(ip_pkt.payload())?;
+                    let udp_pkt = to_udp(payload?)?;
                     udp_pkt.get_source()
                 }
                 IpNextHeaderProtocols::Icmp => {
-                    return None;
+                    return get_icmp_identity(payload?, IpAddr::V4(src_ip), true);
                 }
-                _ => return None,
+                _ => return get_protocol_identity(protocol, payload, IpAddr::V4(src_ip), IpAddr::V4(ip_pkt.get_destination()), true),
             };
 
             format!("{}:{}", src_ip, src_id)
@@ -33,20 +64,21 @@ pub fn get_source_identity(buf: &[u8]) -> Option<String> {
         EtherTypes::Ipv6 => {
             let ip_pkt = to_ipv6(eth_pkt.payload())?;
             let src_ip = ip_pkt.get_source();
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
 
-            let src_id = match ip_pkt.get_next_header() {
+            let src_id = match protocol {
                 IpNextHeaderProtocols::Tcp => {
-                    let tcp_pkt = to_tcp(ip_pkt.payload())?;
+                    let tcp_pkt = to_tcp(payload?)?;
                     tcp_pkt.get_source()
                 }
                 IpNextHeaderProtocols::Udp => {
-                    let udp_pkt = to_udp(ip_pkt.payload())?;
+                    let udp_pkt = to_udp(payload?)?;
                     udp_pkt.get_source()
                 }
                 IpNextHeaderProtocols::Icmpv6 => {
-                    return None;
+                    return get_icmpv6_identity(payload?, IpAddr::V6(src_ip), true);
                 }
-                _ => return None,
+                _ => return get_protocol_identity(protocol, payload, IpAddr::V6(src_ip), IpAddr::V6(ip_pkt.get_destination()), true),
             };
 
             format!("{}:{}", src_ip, src_id)
@@ -64,20 +96,21 @@ pub fn get_destination_identity(buf: &[u8]) -> Option<String> {
         EtherTypes::Ipv4 => {
             let ip_pkt = to_ipv4(eth_pkt.payload())?;
             let dst_ip = ip_pkt.get_destination();
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
 
-            let dst_id = match ip_pkt.get_next_level_protocol() {
+            let dst_id = match protocol {
                 IpNextHeaderProtocols::Tcp => {
-                    let tcp_pkt = to_tcp(ip_pkt.payload())?;
+                    let tcp_pkt = to_tcp(payload?)?;
                     tcp_pkt.get_destination()
                 }
                 IpNextHeaderProtocols::Udp => {
-                    let udp_pkt = to_udp(ip_pkt.payload())?;
+                    let udp_pkt = to_udp(payload?)?;
                     udp_pkt.get_destination()
                 }
                 IpNextHeaderProtocols::Icmp => {
-                    return None;
+                    return get_icmp_identity(payload?, IpAddr::V4(dst_ip), false);
                 }
-                _ => return None,
+                _ => return get_protocol_identity(protocol, payload, IpAddr::V4(dst_ip), IpAddr::V4(ip_pkt.get_source()), false),
             };
 
             format!("{}:{}", dst_ip, dst_id)
@@ -85,20 +118,21 @@ pub fn get_destination_identity(buf: &[u8]) -> Option<String> {
         EtherTypes::Ipv6 => {
             let ip_pkt = to_ipv6(eth_pkt.payload())?;
             let dst_ip = ip_pkt.get_destination();
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
 
-            let dst_id = match ip_pkt.get_next_header() {
+            let dst_id = match protocol {
                 IpNextHeaderProtocols::Tcp => {
-                    let tcp_pkt = to_tcp(ip_pkt.payload())?;
+                    let tcp_pkt = to_tcp(payload?)?;
                     tcp_pkt.get_destination()
                 }
                 IpNextHeaderProtocols::Udp => {
-                    let udp_pkt = to_udp(ip_pkt.payload())?;
+                    let udp_pkt = to_udp(payload?)?;
                     udp_pkt.get_destination()
                 }
                 IpNextHeaderProtocols::Icmpv6 => {
-                    return None;
+                    return get_icmpv6_identity(payload?, IpAddr::V6(dst_ip), false);
                 }
-                _ => return None,
+                _ => return get_protocol_identity(protocol, payload, IpAddr::V6(dst_ip), IpAddr::V6(ip_pkt.get_source()), false),
             };
 
             format!("{}:{}", dst_ip, dst_id)
@@ -109,6 +143,286 @@ pub fn get_destination_identity(buf: &[u8]) -> Option<String> {
     Some(identity)
 }
 
+// NOTE(nosiee): an echo is keyed by its identifier. an error is keyed by the packet it's about, which is
+// embedded right after the icmp header. the error goes the opposite way, so the source of the error is
+// the destination of the embedded packet and vice versa
+fn get_icmp_identity(icmp: &[u8], addr: IpAddr, source: bool) -> Option<String> {
+    let icmp_pkt = to_icmp(icmp)?;
+
+    match icmp_pkt.get_icmp_type() {
+        IcmpTypes::EchoRequest => Some(format!("{}:icmp:{}", addr, to_icmp_request(icmp)?.get_identifier())),
+        IcmpTypes::EchoReply => Some(format!("{}:icmp:{}", addr, to_icmp_reply(icmp)?.get_identifier())),
+        IcmpTypes::DestinationUnreachable | IcmpTypes::TimeExceeded | IcmpTypes::ParameterProblem | IcmpTypes::SourceQuench => {
+            get_embedded_identity(icmp.get(ICMP_HEADER_SIZE..)?, !source)
+        }
+        _ => None,
+    }
+}
+
+fn get_icmpv6_identity(icmp: &[u8], addr: IpAddr, source: bool) -> Option<String> {
+    let icmp_pkt = to_icmpv6(icmp)?;
+
+    match icmp_pkt.get_icmpv6_type() {
+        Icmpv6Types::EchoRequest => Some(format!("{}:icmp:{}", addr, to_icmpv6_request(icmp)?.get_identifier())),
+        Icmpv6Types::EchoReply => Some(format!("{}:icmp:{}", addr, to_icmpv6_reply(icmp)?.get_identifier())),
+        Icmpv6Types::DestinationUnreachable | Icmpv6Types::PacketTooBig | Icmpv6Types::TimeExceeded | Icmpv6Types::ParameterProblem => {
+            get_embedded_identity(icmp.get(ICMP_HEADER_SIZE..)?, !source)
+        }
+        _ => None,
+    }
+}
+
+// NOTE(nosiee): the embedded packet is cut to the ip header and the first 8 bytes of its payload,
+// pnet can't parse the truncated tcp header, so the ports and the identifier are read directly
+fn get_embedded_identity(packet: &[u8], source: bool) -> Option<String> {
+    let (src_ip, dst_ip, protocol, payload): (IpAddr, IpAddr, u8, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let ip_pkt = to_ipv4(packet)?;
+            let header_length = ip_pkt.get_header_length() as usize * 4;
+
+            (
+                IpAddr::V4(ip_pkt.get_source()),
+                IpAddr::V4(ip_pkt.get_destination()),
+                ip_pkt.get_next_level_protocol().0,
+                packet.get(header_length..)?,
+            )
+        }
+        6 => {
+            let ip_pkt = to_ipv6(packet)?;
+
+            (
+                IpAddr::V6(ip_pkt.get_source()),
+                IpAddr::V6(ip_pkt.get_destination()),
+                ip_pkt.get_next_header().0,
+                packet.get(IPV6_HEADER_SIZE..)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let (addr, remote) = if source { (src_ip, dst_ip) } else { (dst_ip, src_ip) };
+    let field = |offset: usize| -> Option<u16> { Some(u16::from_be_bytes(payload.get(offset..offset + 2)?.try_into().ok()?)) };
+
+    match IpNextHeaderProtocol(protocol) {
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => Some(format!("{}:{}", addr, field(if source { 0 } else { 2 })?)),
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => Some(format!("{}:icmp:{}", addr, field(4)?)),
+        protocol => get_protocol_identity(protocol, Some(payload), addr, remote, source),
+    }
+}
+
+// NOTE(nosiee): sctp is keyed by its ports like tcp and udp, esp by the spi. anything else, gre or another
+// tunnel protocol, is keyed by the client address, the remote address and the protocol
+fn get_protocol_identity(protocol: IpNextHeaderProtocol, payload: Option<&[u8]>, addr: IpAddr, remote: IpAddr, source: bool) -> Option<String> {
+    match protocol {
+        IpNextHeaderProtocols::Sctp => {
+            let offset = if source { 0 } else { 2 };
+            let port = u16::from_be_bytes(payload?.get(offset..offset + 2)?.try_into().ok()?);
+
+            Some(format!("{}:sctp:{}", addr, port))
+        }
+        IpNextHeaderProtocols::Esp => {
+            let spi = u32::from_be_bytes(payload?.get(..4)?.try_into().ok()?);
+            Some(format!("{}:esp:{}", addr, spi))
+        }
+        _ => Some(tuple_identity(addr, remote, protocol)),
+    }
+}
+
+// NOTE(nosiee): the identity both directions of a protocol without ports share, the client address goes first
+pub fn get_tuple_identity(buf: &[u8], source: bool) -> Option<String> {
+    let flow = get_flow(buf)?;
+
+    match source {
+        true => Some(tuple_identity(flow.source, flow.destination, flow.protocol)),
+        false => Some(tuple_identity(flow.destination, flow.source, flow.protocol)),
+    }
+}
+
+fn tuple_identity(addr: IpAddr, remote: IpAddr, protocol: IpNextHeaderProtocol) -> String {
+    format!("{}-{}:proto:{}", addr, remote, protocol.0)
+}
+
+pub fn get_source_addr(buf: &[u8]) -> Option<IpAddr> {
+    let eth_pkt = from_ip_payload(buf)?;
+
+    match eth_pkt.get_ethertype() {
+        EtherTypes::Ipv4 => Some(IpAddr::V4(to_ipv4(eth_pkt.payload())?.get_source())),
+        EtherTypes::Ipv6 => Some(IpAddr::V6(to_ipv6(eth_pkt.payload())?.get_source())),
+        _ => None,
+    }
+}
+
+pub fn get_destination_addr(buf: &[u8]) -> Option<IpAddr> {
+    let eth_pkt = from_ip_payload(buf)?;
+
+    match eth_pkt.get_ethertype() {
+        EtherTypes::Ipv4 => Some(IpAddr::V4(to_ipv4(eth_pkt.payload())?.get_destination())),
+        EtherTypes::Ipv6 => Some(IpAddr::V6(to_ipv6(eth_pkt.payload())?.get_destination())),
+        _ => None,
+    }
+}
+
+// NOTE(nosiee): the packets that never leave the link, like ipv6 router solicitations and mld reports
+pub fn is_link_scoped(buf: &[u8]) -> bool {
+    match get_destination_addr(buf) {
+        Some(IpAddr::V4(addr)) => addr.is_link_local(),
+        Some(IpAddr::V6(addr)) => addr.is_unicast_link_local() || (addr.is_multicast() && addr.segments()[0] & 0x000f <= 2),
+        None => false,
+    }
+}
+
+pub fn get_flow(buf: &[u8]) -> Option<Flow> {
+    let eth_pkt = from_ip_payload(buf)?;
+
+    let flow = match eth_pkt.get_ethertype() {
+        EtherTypes::Ipv4 => {
+            let ip_pkt = to_ipv4(eth_pkt.payload())?;
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
+            let (source_port, destination_port) = get_ports(protocol, payload);
+
+            Flow {
+                source: IpAddr::V4(ip_pkt.get_source()),
+                destination: IpAddr::V4(ip_pkt.get_destination()),
+                protocol,
+                source_port,
+                destination_port,
+            }
+        }
+        EtherTypes::Ipv6 => {
+            let ip_pkt = to_ipv6(eth_pkt.payload())?;
+            let (protocol, payload) = get_transport(eth_pkt.payload())?;
+            let (source_port, destination_port) = get_ports(protocol, payload);
+
+            Flow {
+                source: IpAddr::V6(ip_pkt.get_source()),
+                destination: IpAddr::V6(ip_pkt.get_destination()),
+                protocol,
+                source_port,
+                destination_port,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(flow)
+}
+
+pub fn get_tcp_flags(buf: &[u8]) -> Option<u8> {
+    let eth_pkt = from_ip_payload(buf)?;
+
+    let flags = match eth_pkt.get_ethertype() {
+        EtherTypes::Ipv4 | EtherTypes::Ipv6 => match get_transport(eth_pkt.payload())? {
+            (IpNextHeaderProtocols::Tcp, Some(payload)) => to_tcp(payload)?.get_flags(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(flags)
+}
+
+// NOTE(nosiee): a fragmented packet is keyed by the ip id, only the first fragment has the transport header
+pub fn get_fragment(buf: &[u8]) -> Option<Fragment> {
+    let eth_pkt = from_ip_payload(buf)?;
+
+    let (key, offset, more) = match eth_pkt.get_ethertype() {
+        EtherTypes::Ipv4 => {
+            let ip_pkt = to_ipv4(eth_pkt.payload())?;
+            let key = FragmentKey {
+                source: IpAddr::V4(ip_pkt.get_source()),
+                destination: IpAddr::V4(ip_pkt.get_destination()),
+                protocol: ip_pkt.get_next_level_protocol(),
+                id: ip_pkt.get_identification() as u32,
+            };
+
+            (key, ip_pkt.get_fragment_offset(), ip_pkt.get_flags() & Ipv4Flags::MoreFragments != 0)
+        }
+        EtherTypes::Ipv6 => {
+            let ip_pkt = to_ipv6(eth_pkt.payload())?;
+            if ip_pkt.get_next_header() != IpNextHeaderProtocols::Ipv6Frag {
+                return None;
+            }
+
+            let fragment_header = ip_pkt.payload().get(..IPV6_FRAGMENT_HEADER_SIZE)?;
+            let offset_field = u16::from_be_bytes([fragment_header[2], fragment_header[3]]);
+            let key = FragmentKey {
+                source: IpAddr::V6(ip_pkt.get_source()),
+                destination: IpAddr::V6(ip_pkt.get_destination()),
+                protocol: IpNextHeaderProtocol(fragment_header[0]),
+                id: u32::from_be_bytes(fragment_header[4..8].try_into().ok()?),
+            };
+
+            (key, offset_field >> 3, offset_field & 1 != 0)
+        }
+        _ => return None,
+    };
+
+    if offset == 0 && !more {
+        return None;
+    }
+
+    Some(Fragment { key, first: offset == 0 })
+}
+
+// NOTE(nosiee): the transport protocol and its header, the header is none for a non-first fragment.
+// the ipv6 fragment header is expected right after the fixed header, other extension headers aren't walked
+fn get_transport(packet: &[u8]) -> Option<(IpNextHeaderProtocol, Option<&[u8]>)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ip_pkt = to_ipv4(packet)?;
+            let header_length = ip_pkt.get_header_length() as usize * 4;
+            let total_length = (ip_pkt.get_total_length() as usize).min(packet.len());
+            let payload = packet.get(header_length..total_length.max(header_length))?;
+
+            match ip_pkt.get_fragment_offset() {
+                0 => Some((ip_pkt.get_next_level_protocol(), Some(payload))),
+                _ => Some((ip_pkt.get_next_level_protocol(), None)),
+            }
+        }
+        6 => {
+            let ip_pkt = to_ipv6(packet)?;
+            let payload_length = (IPV6_HEADER_SIZE + ip_pkt.get_payload_length() as usize).min(packet.len());
+            let payload = packet.get(IPV6_HEADER_SIZE..payload_length)?;
+
+            if ip_pkt.get_next_header() != IpNextHeaderProtocols::Ipv6Frag {
+                return Some((ip_pkt.get_next_header(), Some(payload)));
+            }
+
+            let fragment_header = payload.get(..IPV6_FRAGMENT_HEADER_SIZE)?;
+            let protocol = IpNextHeaderProtocol(fragment_header[0]);
+
+            match u16::from_be_bytes([fragment_header[2], fragment_header[3]]) >> 3 {
+                0 => Some((protocol, Some(&payload[IPV6_FRAGMENT_HEADER_SIZE..]))),
+                _ => Some((protocol, None)),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn get_ports(protocol: IpNextHeaderProtocol, payload: Option<&[u8]>) -> (Option<u16>, Option<u16>) {
+    let payload = match payload {
+        Some(payload) => payload,
+        None => return (None, None),
+    };
+
+    match protocol {
+        IpNextHeaderProtocols::Tcp => match to_tcp(payload) {
+            Some(tcp_pkt) => (Some(tcp_pkt.get_source()), Some(tcp_pkt.get_destination())),
+            None => (None, None),
+        },
+        IpNextHeaderProtocols::Udp => match to_udp(payload) {
+            Some(udp_pkt) => (Some(udp_pkt.get_source()), Some(udp_pkt.get_destination())),
+            None => (None, None),
+        },
+        IpNextHeaderProtocols::Sctp if payload.len() >= 4 => (
+            Some(u16::from_be_bytes([payload[0], payload[1]])),
+            Some(u16::from_be_bytes([payload[2], payload[3]])),
+        ),
+        _ => (None, None),
+    }
+}
+
 pub fn get_device_ipv4(name: &str) -> Option<Ipv4Addr> {
     let filter = |iface: &NetworkInterface| iface.name == name;
     let interfaces = datalink::interfaces();
@@ -129,8 +443,9 @@ pub fn get_device_ipv6(name: &str) -> Option<Ipv6Addr> {
     let interface = interfaces.into_iter().find(filter).unwrap_or_else(|| panic!("no such device: {}", name));
 
     for ip in interface.ips {
-        if let IpNetwork::V6(ipv6) = ip {
-            return Some(ipv6.ip());
+        match ip {
+            IpNetwork::V6(ipv6) if !ipv6.ip().is_unicast_link_local() => return Some(ipv6.ip()),
+            _ => continue,
         }
     }
 
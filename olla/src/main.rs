@@ -12,13 +12,14 @@ use std::path::PathBuf;
 enum AppMode {
     Client,
     Node,
+    Unlock,
 }
 
 #[derive(Parser, Debug)]
 struct AppArguments {
     /// Path to the config toml file
     config: PathBuf,
-    /// App mode: client, mode
+    /// App mode: client, mode, unlock
     mode: AppMode,
 }
 
@@ -27,6 +28,7 @@ impl From<&str> for AppMode {
         match mode {
             "client" => AppMode::Client,
             "node" => AppMode::Node,
+            "unlock" => AppMode::Unlock,
             _ => panic!("unknown app mode: {}", mode),
         }
     }
@@ -40,5 +42,6 @@ async fn main() -> anyhow::Result<()> {
     match args.mode {
         AppMode::Client => client::run(args.config).await,
         AppMode::Node => node::run(args.config).await,
+        AppMode::Unlock => client::unlock(args.config).await,
     }
 }
@@ -1,64 +1,274 @@
 use async_channel::{Receiver, Sender};
+use pnet::ipnetwork::IpNetwork;
 use socket2::SockAddr;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::error;
+use std::time::Duration;
+use tracing::{error, info};
 
 use super::config;
-use super::coordinator::{node::Node, packet::PacketCoordinator, packet::PacketCoordinatorMessage};
-use super::device::{self, Device, config::DeviceConfig};
-use super::tunnels::{header::HEADER_SIZE, incoming, outgoing};
+use super::coordinator::control::{self, ClientEntry, ControlTable};
+use super::coordinator::{
+    conntrack::ConntrackConfig,
+    lease::{DEFAULT_LEASE_DURATION, LeaseTable},
+    masquerade::Masquerade,
+    node::DEFAULT_NODE_WEIGHT,
+    node::Node,
+    packet::PacketCoordinator,
+    packet::PacketCoordinatorMessage,
+    packet::PeerMessage,
+    reorder::{DEFAULT_REORDER_CAPACITY, DEFAULT_REORDER_TIMEOUT, ReorderConfig},
+    snat::SnatTable,
+};
+use super::device::{
+    self, Device,
+    config::{DEFAULT_IPV6_PREFIX, DeviceConfig},
+};
+use super::tunnels::{
+    header::HEADER_SIZE,
+    incoming,
+    noise::{self, StaticKeypair},
+    outgoing,
+};
 
 pub async fn run(path: PathBuf) -> anyhow::Result<()> {
     let config = config::from_file(path)?;
+    let keypair = Arc::new(StaticKeypair::from_hex(&config.private_key)?);
+    info!("local public key: {}", hex::encode(keypair.public));
 
-    let nodes = create_nodes(&config.nodes, config.device.mtu as usize);
+    let nodes = create_nodes(&config.nodes, config.device.mtu as usize, keypair.clone())?;
+    let control_table = Arc::new(create_control_table(&config)?);
     let device = new_network_device(&config.device)?;
 
-    let machine_addr = device::util::get_device_ipv4("eth0").unwrap();
-    let packet_coordinator = Arc::new(PacketCoordinator::new(machine_addr, nodes));
+    let machine_addrs = machine_addrs("eth0");
+    let mut packet_coordinator =
+        PacketCoordinator::new(machine_addrs, nodes, control_table.clone()).set_conntrack(conntrack_config(config.conntrack.as_ref()));
+
+    if let Some(reorder) = reorder_config(config.reorder.as_ref()) {
+        packet_coordinator = packet_coordinator.set_reorder(reorder);
+    }
+
+    if let Some(snat) = snat_table(config.snat.as_ref())? {
+        packet_coordinator = packet_coordinator.set_snat(snat);
+    }
+
+    if let Some(leases) = lease_table(config.lease.as_ref(), &config.device)? {
+        packet_coordinator = packet_coordinator.set_leases(leases);
+    }
+
+    if let Some(resolvers) = resolvers(config.dns.as_ref())? {
+        packet_coordinator = packet_coordinator.set_resolvers(resolvers);
+    }
+
+    if let Some(masquerade) = masquerade(config.masquerade.as_ref(), &config.device)? {
+        packet_coordinator = packet_coordinator.set_masquerade(masquerade);
+    }
+
+    let packet_coordinator = Arc::new(packet_coordinator);
 
     let (tun_tx, tun_rx) = device.forward().await?;
     let (pc_tx, pc_rx) = packet_coordinator.forward(tun_tx, tun_rx);
 
-    run_tunnel(config.tunnel.unwrap(), pc_tx, pc_rx).await
+    run_tunnel(config.tunnel.unwrap(), keypair, control_table, config.device.mtu as usize, pc_tx, pc_rx).await
 }
 
-fn create_nodes(nc: &Vec<config::NodeConfig>, mtu: usize) -> Vec<Arc<Node>> {
+fn create_nodes(nc: &Vec<config::NodeConfig>, mtu: usize, keypair: Arc<StaticKeypair>) -> anyhow::Result<Vec<Arc<Node>>> {
     let mut nodes = Vec::with_capacity(nc.len());
 
     for node in nc {
         let node = Node {
             id: node.id.clone(),
             addr: node.addr.parse().unwrap(),
-            tunnel: outgoing::OutgoingTunnel::new().set_addr(node.addr.parse().unwrap()),
+            tags: node.tags.clone().unwrap_or_default(),
+            weight: node.weight.unwrap_or(DEFAULT_NODE_WEIGHT),
+            tunnel: outgoing::OutgoingTunnel::new()
+                .set_addr(node.addr.parse().unwrap())
+                .set_keypair(keypair.clone())
+                .set_public_key(noise::decode_key(&node.public_key)?),
             max_fragment_size: mtu + HEADER_SIZE,
         };
 
         nodes.push(Arc::new(node));
     }
 
-    nodes
+    Ok(nodes)
+}
+
+fn create_control_table(config: &config::Config) -> anyhow::Result<ControlTable> {
+    let mut control_table = ControlTable::new();
+
+    for node in &config.nodes {
+        control_table.add_node(noise::decode_key(&node.public_key)?, node.id.clone());
+    }
+
+    for client in config.clients.iter().flatten() {
+        let mut allowed_ips = Vec::with_capacity(client.allowed_ips.len());
+        for net in &client.allowed_ips {
+            allowed_ips.push(net.parse()?);
+        }
+
+        let public_key = noise::decode_key(&client.public_key)?;
+        let entry = ClientEntry {
+            id: client.id.clone(),
+            tenant: control::tenant(&public_key),
+            allowed_ips,
+        };
+
+        control_table.add_client(public_key, entry);
+    }
+
+    Ok(control_table)
+}
+
+fn conntrack_config(conf: Option<&config::ConntrackConfig>) -> ConntrackConfig {
+    let default = ConntrackConfig::default();
+    let conf = match conf {
+        Some(conf) => conf,
+        None => return default,
+    };
+
+    ConntrackConfig {
+        max_entries: conf.max_entries.unwrap_or(default.max_entries).max(1),
+        tcp_timeout: conf.tcp_timeout.map(Duration::from_secs).unwrap_or(default.tcp_timeout),
+        udp_timeout: conf.udp_timeout.map(Duration::from_secs).unwrap_or(default.udp_timeout),
+        timeout: conf.timeout.map(Duration::from_secs).unwrap_or(default.timeout),
+    }
+}
+
+fn reorder_config(conf: Option<&config::ReorderConfig>) -> Option<ReorderConfig> {
+    let timeout = conf.and_then(|c| c.timeout).map(Duration::from_millis).unwrap_or(DEFAULT_REORDER_TIMEOUT);
+    let capacity = conf.and_then(|c| c.capacity).unwrap_or(DEFAULT_REORDER_CAPACITY);
+
+    if timeout.is_zero() || capacity == 0 {
+        return None;
+    }
+
+    Some(ReorderConfig { timeout, capacity })
+}
+
+// NOTE(nosiee): a frame whose primary node is any of these addresses is written locally
+fn machine_addrs(name: &str) -> Vec<IpAddr> {
+    let ipv4 = device::util::get_device_ipv4(name).map(IpAddr::V4);
+    let ipv6 = device::util::get_device_ipv6(name).map(IpAddr::V6);
+
+    ipv4.into_iter().chain(ipv6).collect()
+}
+
+fn snat_table(conf: Option<&config::SnatConfig>) -> anyhow::Result<Option<SnatTable>> {
+    let conf = match conf {
+        Some(conf) if !conf.pools.is_empty() => conf,
+        _ => return Ok(None),
+    };
+
+    let mut pools = Vec::with_capacity(conf.pools.len());
+    for pool in &conf.pools {
+        pools.push(pool.parse()?);
+    }
+
+    Ok(Some(SnatTable::new(pools)))
+}
+
+// NOTE(nosiee): the device address of the node is never leased even if it's in the pool
+fn lease_table(conf: Option<&config::LeaseConfig>, device: &config::DeviceConfig) -> anyhow::Result<Option<LeaseTable>> {
+    let conf = match conf {
+        Some(conf) => conf,
+        None => return Ok(None),
+    };
+
+    let mut dns = Vec::new();
+    for addr in conf.dns.iter().flatten() {
+        dns.push(addr.parse()?);
+    }
+
+    let reserved = [device.addr.as_ref(), device.ipv6_addr.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|addr| addr.parse().ok())
+        .collect();
+
+    let pool: IpNetwork = conf.pool.parse()?;
+    if !pool.is_ipv4() {
+        return Err(anyhow::anyhow!("the lease pool must be ipv4: {}", conf.pool));
+    }
+
+    let duration = conf.duration.map(Duration::from_secs).unwrap_or(DEFAULT_LEASE_DURATION);
+    let mut leases = LeaseTable::new(pool, dns, duration).set_reserved(reserved);
+
+    if let Some(path) = conf.path.as_ref() {
+        leases = leases.set_path(PathBuf::from(path))?;
+    }
+
+    Ok(Some(leases))
+}
+
+// NOTE(nosiee): the clients send their dns queries through the tunnel to these resolvers
+fn resolvers(conf: Option<&config::DnsConfig>) -> anyhow::Result<Option<Vec<IpAddr>>> {
+    let conf = match conf {
+        Some(conf) => conf,
+        None => return Ok(None),
+    };
+
+    let mut resolvers = Vec::new();
+    for addr in conf.resolvers.iter().flatten() {
+        resolvers.push(addr.parse()?);
+    }
+
+    if resolvers.is_empty() {
+        return Err(anyhow::anyhow!("at least one resolver must be set"));
+    }
+
+    Ok(Some(resolvers))
+}
+
+// NOTE(nosiee): the egress address is the ipv4 address of eth0 unless set, the device network stays local
+fn masquerade(conf: Option<&config::MasqueradeConfig>, device: &config::DeviceConfig) -> anyhow::Result<Option<Masquerade>> {
+    let conf = match conf {
+        Some(conf) => conf,
+        None => return Ok(None),
+    };
+
+    let addr = match conf.addr.as_ref() {
+        Some(addr) => addr.parse()?,
+        None => device::util::get_device_ipv4("eth0").ok_or_else(|| anyhow::anyhow!("no egress address to masquerade to"))?,
+    };
+
+    let mut local = Vec::new();
+    if let (Some(addr), Some(mask)) = (device.addr.as_ref(), device.mask.as_ref()) {
+        local.push(IpNetwork::with_netmask(addr.parse()?, mask.parse()?)?);
+    }
+
+    Ok(Some(Masquerade::new(addr, local)?))
 }
 
 fn new_network_device(conf: &config::DeviceConfig) -> anyhow::Result<Device> {
     Device::new_tun(DeviceConfig {
         name: conf.name.clone(),
         mtu: conf.mtu,
-        addr: conf.addr.parse().unwrap(),
-        mask: conf.mask.clone(),
+        addr: conf
+            .addr
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("the device address of a node must be set"))?,
+        mask: conf
+            .mask
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("the device mask of a node must be set"))?,
+        ipv6_addr: conf.ipv6_addr.clone(),
+        ipv6_prefix: conf.ipv6_prefix.unwrap_or(DEFAULT_IPV6_PREFIX),
         disable_on_exit: conf.disable_on_exit,
     })
 }
 
 async fn run_tunnel(
     tunnel: config::TunnelConfig,
-    tx: Sender<PacketCoordinatorMessage>,
+    keypair: Arc<StaticKeypair>,
+    control_table: Arc<ControlTable>,
+    mtu: usize,
+    tx: Sender<PeerMessage>,
     rx: Receiver<PacketCoordinatorMessage>,
 ) -> anyhow::Result<()> {
     let addr: SocketAddr = tunnel.addr.parse().unwrap();
-    let mut incomingtun = incoming::IncomingTunnel::new(SockAddr::from(addr));
+    let mut incomingtun = incoming::IncomingTunnel::new(SockAddr::from(addr), keypair, control_table, mtu);
     let _ = incomingtun.forward(tx).await;
 
     while let Ok((peer, payload)) = rx.recv().await {
@@ -11,12 +11,17 @@ pub const SNI_PARSING_ERROR: ErrorCode = -4;
 pub const TLS_CONNECT_ERROR: ErrorCode = -5;
 pub const NO_PEER_FOUND: ErrorCode = -6;
 pub const NO_IDENTITY_FOUND: ErrorCode = -7;
+pub const HANDSHAKE_ERROR: ErrorCode = -8;
+pub const ENCRYPT_ERROR: ErrorCode = -9;
+pub const DECRYPT_ERROR: ErrorCode = -10;
+pub const REPLAY_DETECTED: ErrorCode = -11;
 
 #[derive(Debug)]
 pub enum TunnelError {
     IO(ErrorMessage),
     Connection(ErrorMessage),
     Strict(ErrorMessage),
+    Crypto(ErrorMessage),
 }
 
 impl From<TunnelError> for anyhow::Error {
@@ -25,6 +30,7 @@ impl From<TunnelError> for anyhow::Error {
             TunnelError::IO(e) => format!("io error: {}, code: {}", e.0, e.1),
             TunnelError::Connection(e) => format!("connection error: {}, code: {}", e.0, e.1),
             TunnelError::Strict(e) => format!("pedantic error: {}, code: {}", e.0, e.1),
+            TunnelError::Crypto(e) => format!("crypto error: {}, code: {}", e.0, e.1),
         };
 
         anyhow!(error_text)
@@ -1,27 +1,167 @@
 use bytes::{Bytes, BytesMut};
 use std::net::SocketAddr;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv6Addr};
 
-pub const HEADER_SIZE: usize = 0x10;
+pub const HEADER_SIZE: usize = 0x3c;
+pub const MAX_SEQUENCE: u64 = (1 << 48) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Data,
+    Keepalive,
+    Probe,
+    ProbeReply,
+    Parity,
+    LeaseRequest,
+    LeaseReply,
+    ResolverRequest,
+    ResolverReply,
+    Unknown(u8),
+}
+
+impl From<u8> for FrameKind {
+    fn from(kind: u8) -> Self {
+        match kind {
+            0x00 => FrameKind::Data,
+            0x01 => FrameKind::Keepalive,
+            0x02 => FrameKind::Probe,
+            0x03 => FrameKind::ProbeReply,
+            0x04 => FrameKind::Parity,
+            0x05 => FrameKind::LeaseRequest,
+            0x06 => FrameKind::LeaseReply,
+            0x07 => FrameKind::ResolverRequest,
+            0x08 => FrameKind::ResolverReply,
+            _ => FrameKind::Unknown(kind),
+        }
+    }
+}
+
+impl From<FrameKind> for u8 {
+    fn from(kind: FrameKind) -> Self {
+        match kind {
+            FrameKind::Data => 0x00,
+            FrameKind::Keepalive => 0x01,
+            FrameKind::Probe => 0x02,
+            FrameKind::ProbeReply => 0x03,
+            FrameKind::Parity => 0x04,
+            FrameKind::LeaseRequest => 0x05,
+            FrameKind::LeaseReply => 0x06,
+            FrameKind::ResolverRequest => 0x07,
+            FrameKind::ResolverReply => 0x08,
+            FrameKind::Unknown(kind) => kind,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct HeaderFrame {
     pub frame_size: u32,
-    pub primary_node_ip: Ipv4Addr,
+    pub primary_node_ip: IpAddr,
     pub primary_node_port: u16,
+    pub sequence: u64,
+    pub kind: FrameKind,
+    pub flow_sequence: u32,
+    pub redundancy: u8,
+    pub client_sequence: u64,
+    pub group_id: u32,
+    pub group_index: u8,
+    pub group_data: u8,
+    pub group_parity: u8,
+    pub tenant: u64,
+}
+
+impl HeaderFrame {
+    pub fn new(kind: FrameKind) -> Self {
+        Self {
+            frame_size: 0,
+            primary_node_ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            primary_node_port: 0,
+            sequence: 0,
+            kind,
+            flow_sequence: 0,
+            redundancy: 0,
+            client_sequence: 0,
+            group_id: 0,
+            group_index: 0,
+            group_data: 0,
+            group_parity: 0,
+            tenant: 0,
+        }
+    }
+
+    pub fn set_primary_node(mut self, pnode_addr: Option<SocketAddr>) -> Self {
+        if let Some(addr) = pnode_addr {
+            self.primary_node_ip = addr.ip();
+            self.primary_node_port = addr.port();
+        }
+
+        self
+    }
+
+    pub fn set_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    // NOTE(nosiee): the flow sequence is set by the client and kept as is by the relays,
+    // so the primary node can restore the order of a sprayed flow. 0 means unordered
+    pub fn set_flow_sequence(mut self, flow_sequence: u32) -> Self {
+        self.flow_sequence = flow_sequence;
+        self
+    }
+
+    // NOTE(nosiee): the same packet is sent through `redundancy` nodes, the receiving end drops
+    // the copies by the client sequence. it's numbered per client, in both directions
+    pub fn set_redundancy(mut self, redundancy: u8) -> Self {
+        self.redundancy = redundancy;
+        self
+    }
+
+    pub fn set_client_sequence(mut self, client_sequence: u64) -> Self {
+        self.client_sequence = client_sequence;
+        self
+    }
+
+    // NOTE(nosiee): the fec group the packet belongs to, 0 means no group. the data frames carry only the index,
+    // the parity frames carry the size of the group as well, so a group may be closed before it's full
+    pub fn set_group(mut self, group_id: u32, group_index: u8) -> Self {
+        self.group_id = group_id;
+        self.group_index = group_index;
+        self
+    }
+
+    pub fn set_group_size(mut self, group_data: u8, group_parity: u8) -> Self {
+        self.group_data = group_data;
+        self.group_parity = group_parity;
+        self
+    }
+
+    // NOTE(nosiee): the client a relayed frame belongs to, set by the nodes only. the primary node
+    // keeps the state of every client apart by it, the clients ignore it
+    pub fn set_tenant(mut self, tenant: u64) -> Self {
+        self.tenant = tenant;
+        self
+    }
 }
 
-pub fn extend_payload(payload: &[u8], pnode_addr: Option<SocketAddr>) -> Bytes {
+pub fn extend_payload(payload: &[u8], frame: &HeaderFrame) -> Bytes {
     let extended_size: usize = payload.len() + HEADER_SIZE;
     let mut extended_buffer = BytesMut::zeroed(extended_size);
 
     extended_buffer[HEADER_SIZE..].copy_from_slice(payload);
 
     total_packet_size(&mut extended_buffer);
-
-    if let Some(node_addr) = pnode_addr {
-        primary_node(&mut extended_buffer, &node_addr);
-    }
+    primary_node(&mut extended_buffer, frame);
+    frame_sequence(&mut extended_buffer, frame.sequence);
+    extended_buffer[28] = frame.kind.into();
+    extended_buffer[29] = frame.redundancy;
+    extended_buffer[32..36].copy_from_slice(&frame.flow_sequence.to_be_bytes());
+    extended_buffer[36..42].copy_from_slice(&(frame.client_sequence & MAX_SEQUENCE).to_be_bytes()[2..]);
+    extended_buffer[44..48].copy_from_slice(&frame.group_id.to_be_bytes());
+    extended_buffer[48] = frame.group_index;
+    extended_buffer[49] = frame.group_data;
+    extended_buffer[50] = frame.group_parity;
+    extended_buffer[52..60].copy_from_slice(&frame.tenant.to_be_bytes());
 
     extended_buffer.freeze()
 }
@@ -29,8 +169,18 @@ pub fn extend_payload(payload: &[u8], pnode_addr: Option<SocketAddr>) -> Bytes {
 pub fn decode(buf: [u8; HEADER_SIZE]) -> HeaderFrame {
     HeaderFrame {
         frame_size: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
-        primary_node_ip: Ipv4Addr::from(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]])),
-        primary_node_port: u16::from_be_bytes([buf[8], buf[9]]),
+        primary_node_ip: Ipv6Addr::from(<[u8; 16]>::try_from(&buf[4..20]).unwrap()).to_canonical(),
+        primary_node_port: u16::from_be_bytes([buf[20], buf[21]]),
+        sequence: u64::from_be_bytes([0, 0, buf[22], buf[23], buf[24], buf[25], buf[26], buf[27]]),
+        kind: FrameKind::from(buf[28]),
+        flow_sequence: u32::from_be_bytes([buf[32], buf[33], buf[34], buf[35]]),
+        redundancy: buf[29],
+        client_sequence: u64::from_be_bytes([0, 0, buf[36], buf[37], buf[38], buf[39], buf[40], buf[41]]),
+        group_id: u32::from_be_bytes([buf[44], buf[45], buf[46], buf[47]]),
+        group_index: buf[48],
+        group_data: buf[49],
+        group_parity: buf[50],
+        tenant: u64::from_be_bytes(buf[52..60].try_into().unwrap()),
     }
 }
 
@@ -39,9 +189,19 @@ fn total_packet_size(payload: &mut [u8]) {
     payload[0..4].copy_from_slice(&len.to_be_bytes());
 }
 
-fn primary_node(payload: &mut [u8], node_addr: &SocketAddr) {
-    if let IpAddr::V4(ipv4) = node_addr.ip() {
-        payload[4..8].copy_from_slice(&ipv4.octets());
-        payload[8..10].copy_from_slice(&node_addr.port().to_be_bytes());
-    }
+// NOTE(nosiee): the address takes 16 bytes either way, an ipv4 address is sent ipv4-mapped
+// and comes back as ipv4 on decode. the unspecified address of any family means no primary node
+fn primary_node(payload: &mut [u8], frame: &HeaderFrame) {
+    let octets = match frame.primary_node_ip {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    };
+
+    payload[4..20].copy_from_slice(&octets);
+    payload[20..22].copy_from_slice(&frame.primary_node_port.to_be_bytes());
+}
+
+// NOTE(nosiee): only the lower 48 bits are sent, it's enough for a session
+fn frame_sequence(payload: &mut [u8], sequence: u64) {
+    payload[22..28].copy_from_slice(&(sequence & MAX_SEQUENCE).to_be_bytes()[2..]);
 }
@@ -1,27 +1,63 @@
 use async_channel::Sender;
 use bytes::BytesMut;
 use nix::sys::socket::setsockopt;
-use nix::sys::socket::sockopt::Ipv4PacketInfo;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::net::SocketAddr;
+use nix::sys::socket::sockopt::{Ipv4PacketInfo, Ipv6RecvPacketInfo};
+use socket2::{Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::net::UdpSocket;
-use tracing::error;
+use tokio::sync::RwLock;
+use tracing::{debug, error};
 
 use super::errors::*;
-use crate::coordinator::packet::PacketCoordinatorMessage;
+use super::header::HEADER_SIZE;
+use super::noise::{self, Key, NoiseMessage, Responder, Session, StaticKeypair};
+use crate::coordinator::conntrack::SWEEP_INTERVAL;
+use crate::coordinator::control::ControlTable;
+use crate::coordinator::fec::PARITY_OVERHEAD;
+use crate::coordinator::packet::PeerMessage;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: String,
+    pub public_key: Key,
+    pub session: u32,
+}
+
+#[derive(Debug, Default)]
+struct SessionTable {
+    by_index: HashMap<u32, (String, Arc<Session>)>,
+    by_peer: HashMap<String, Arc<Session>>,
+    initiations: HashMap<Key, u64>,
+}
 
 pub struct IncomingTunnel {
     addr: SockAddr,
     socket: Option<Arc<UdpSocket>>,
+
+    keypair: Arc<StaticKeypair>,
+    control_table: Arc<ControlTable>,
+    sessions: Arc<RwLock<SessionTable>>,
+    buffer_size: usize,
 }
 
 impl IncomingTunnel {
-    pub fn new(addr: SockAddr) -> Self {
-        Self { addr, socket: None }
+    // NOTE(nosiee): the biggest message is a parity packet of a full sized packet, it carries the source
+    // address of its group and the length of the packet along with the header
+    pub fn new(addr: SockAddr, keypair: Arc<StaticKeypair>, control_table: Arc<ControlTable>, mtu: usize) -> Self {
+        Self {
+            addr,
+            socket: None,
+            buffer_size: mtu + HEADER_SIZE + PARITY_OVERHEAD + noise::TRANSPORT_OVERHEAD,
+
+            keypair,
+            control_table,
+            sessions: Arc::new(RwLock::new(SessionTable::default())),
+        }
     }
 
-    pub async fn forward(&mut self, tx: Sender<PacketCoordinatorMessage>) -> anyhow::Result<(), TunnelError> {
+    pub async fn forward(&mut self, tx: Sender<PeerMessage>) -> anyhow::Result<(), TunnelError> {
         let cpu_cores = match std::thread::available_parallelism() {
             Ok(n) => n.get(),
             Err(err) => {
@@ -30,9 +66,15 @@ impl IncomingTunnel {
             }
         };
 
+        Self::sweep(self.sessions.clone());
+
         for _ in 0..cpu_cores {
             let sock = self.recreate_socket(&self.addr).unwrap();
             let tx = tx.clone();
+            let keypair = self.keypair.clone();
+            let control_table = self.control_table.clone();
+            let sessions = self.sessions.clone();
+            let buffer_size = self.buffer_size;
 
             if self.socket.is_none() {
                 self.socket = Some(sock.clone());
@@ -40,8 +82,7 @@ impl IncomingTunnel {
 
             tokio::spawn(async move {
                 loop {
-                    // FIXME(nosiee): should use device mtu + HEADER_SIZE
-                    let mut buffer = BytesMut::zeroed(1500);
+                    let mut buffer = BytesMut::zeroed(buffer_size);
 
                     let r = sock.recv_from(&mut buffer).await;
                     if r.is_err() {
@@ -50,10 +91,50 @@ impl IncomingTunnel {
                     }
 
                     let (n, addr) = r.unwrap();
+                    let addr = canonical_addr(addr);
                     buffer.truncate(n);
 
-                    if let Err(err) = tx.send((addr.to_string(), buffer.freeze())).await {
-                        panic!("{}", err);
+                    match noise::parse(&buffer) {
+                        Some(NoiseMessage::Initiation { sender, payload }) => {
+                            if let Err(err) = Self::handshake(&sock, &keypair, &control_table, &sessions, addr, sender, payload).await {
+                                error!("failed to handshake with {}: {:?}", addr.to_string(), err);
+                            }
+                        }
+                        Some(NoiseMessage::Transport { receiver, counter, payload }) => {
+                            let session = match sessions.read().await.by_index.get(&receiver) {
+                                Some((peer, session)) if *peer == addr.to_string() => session.clone(),
+                                _ => {
+                                    debug!("{} message omitted, no session found for {}", hex::encode(&buffer), addr.to_string());
+                                    continue;
+                                }
+                            };
+
+                            let peer = match session.remote_static() {
+                                Some(public_key) => Peer {
+                                    addr: addr.to_string(),
+                                    public_key,
+                                    session: session.local_index,
+                                },
+                                None => {
+                                    debug!("{} message omitted, no remote static key for {}", hex::encode(&buffer), addr.to_string());
+                                    continue;
+                                }
+                            };
+
+                            let mut plaintext = BytesMut::zeroed(payload.len());
+                            match session.decrypt(counter, payload, &mut plaintext) {
+                                Ok(n) => plaintext.truncate(n),
+                                Err(err) => {
+                                    debug!("{} message omitted, {:?}", hex::encode(&buffer), err);
+                                    continue;
+                                }
+                            }
+
+                            if let Err(err) = tx.send((peer, plaintext.freeze())).await {
+                                panic!("{}", err);
+                            }
+                        }
+                        _ => debug!("{} message omitted, unexpected noise message", hex::encode(&buffer)),
                     }
                 }
             });
@@ -63,24 +144,123 @@ impl IncomingTunnel {
     }
 
     pub async fn write(&self, peer: String, payload: &[u8]) -> anyhow::Result<usize, TunnelError> {
-        let peer: SocketAddr = peer.parse().unwrap();
+        let session = match self.sessions.read().await.by_peer.get(&peer) {
+            Some(session) => session.clone(),
+            None => return Err(TunnelError::Connection((format!("no session for {}", peer), NO_PEER_FOUND))),
+        };
+
+        let message = session.encrypt(payload)?;
+        let socket = self.socket.as_ref().unwrap();
+        let peer = socket_addr(socket, peer.parse().unwrap());
 
-        match self.socket.as_ref().unwrap().send_to(payload, peer).await {
+        match socket.send_to(&message, peer).await {
             Ok(n) => Ok(n),
             Err(err) => Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE)))),
         }
     }
 
+    async fn handshake(
+        sock: &UdpSocket,
+        keypair: &StaticKeypair,
+        control_table: &ControlTable,
+        sessions: &RwLock<SessionTable>,
+        addr: SocketAddr,
+        sender: u32,
+        payload: &[u8],
+    ) -> anyhow::Result<(), TunnelError> {
+        let responder = Responder::new(keypair, sender, payload)?;
+        let timestamp = responder.timestamp();
+        let public_key = match responder.remote_static() {
+            Some(public_key) if control_table.is_known(&public_key) => public_key,
+            _ => return Err(TunnelError::Strict((format!("unknown static key from {}", addr), HANDSHAKE_ERROR))),
+        };
+
+        // NOTE(nosiee): a replayed initiation would otherwise take the session of the peer over
+        let mut w_sessions_guard = sessions.write().await;
+        if w_sessions_guard.initiations.get(&public_key).is_some_and(|last| *last >= timestamp) {
+            return Err(TunnelError::Strict((
+                format!("{} initiation replayed, timestamp: {}", hex::encode(public_key), timestamp),
+                REPLAY_DETECTED,
+            )));
+        }
+
+        let (session, response) = responder.finish()?;
+        let session = Arc::new(session);
+        let peer = addr.to_string();
+
+        debug!("handshake with {} completed, session: {:?}", peer, session);
+
+        w_sessions_guard.initiations.insert(public_key, timestamp);
+        if let Some(previous) = w_sessions_guard.by_peer.insert(peer.clone(), session.clone()) {
+            w_sessions_guard.by_index.remove(&previous.local_index);
+        }
+
+        w_sessions_guard.by_index.insert(session.local_index, (peer, session));
+        drop(w_sessions_guard);
+
+        if let Err(err) = sock.send_to(&response, socket_addr(sock, addr)).await {
+            return Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE))));
+        }
+
+        Ok(())
+    }
+
+    // NOTE(nosiee): the initiations are kept, there is one per known key at most and the replay check needs them
+    fn sweep(sessions: Arc<RwLock<SessionTable>>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+            loop {
+                ticker.tick().await;
+
+                let mut w_sessions_guard = sessions.write().await;
+                let before = w_sessions_guard.by_index.len();
+
+                w_sessions_guard
+                    .by_index
+                    .retain(|_, (_, session)| session.since_received() < noise::SESSION_TIMEOUT);
+                w_sessions_guard
+                    .by_peer
+                    .retain(|_, session| session.since_received() < noise::SESSION_TIMEOUT);
+
+                let expired = before - w_sessions_guard.by_index.len();
+                if expired != 0 {
+                    debug!("{} sessions expired", expired);
+                }
+            }
+        });
+    }
+
     fn recreate_socket(&self, addr: &SockAddr) -> anyhow::Result<Arc<UdpSocket>> {
-        let rawfd = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        let rawfd = Socket::new(addr.domain(), Type::DGRAM, Some(Protocol::UDP))?;
+
+        // NOTE(nosiee): an ipv6 listener is dual-stack, the ipv4 peers come as ipv4-mapped addresses
+        if addr.is_ipv6() {
+            rawfd.set_only_v6(false)?;
+        }
 
         rawfd.set_reuse_port(true)?;
         rawfd.set_cloexec(true)?;
         rawfd.set_nonblocking(true)?;
         rawfd.bind(addr)?;
 
-        setsockopt(&rawfd, Ipv4PacketInfo, &true)?;
+        match addr.is_ipv6() {
+            true => setsockopt(&rawfd, Ipv6RecvPacketInfo, &true)?,
+            false => setsockopt(&rawfd, Ipv4PacketInfo, &true)?,
+        }
 
         Ok(Arc::new(UdpSocket::from_std(rawfd.into())?))
     }
 }
+
+// NOTE(nosiee): the peers are keyed by their plain address, whatever the listener family is
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn socket_addr(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => addr,
+    }
+}
@@ -1,4 +1,6 @@
 pub mod errors;
 pub mod header;
 pub mod incoming;
+pub mod noise;
 pub mod outgoing;
+pub mod replay;
@@ -0,0 +1,435 @@
+use bytes::{Bytes, BytesMut};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::errors::*;
+use super::replay::ReplayWindow;
+
+// NOTE(nosiee): the same pattern wireguard uses. the initiator knows responder static key
+// beforehand, the responder learns the initiator static key from the first message
+pub const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+pub const KEY_SIZE: usize = 0x20;
+pub const TAG_SIZE: usize = 0x10;
+
+pub const HANDSHAKE_INITIATION: u8 = 0x01;
+pub const HANDSHAKE_RESPONSE: u8 = 0x02;
+pub const TRANSPORT_DATA: u8 = 0x04;
+
+pub const INITIATION_HEADER_SIZE: usize = 0x08;
+pub const RESPONSE_HEADER_SIZE: usize = 0x0c;
+pub const TRANSPORT_HEADER_SIZE: usize = 0x10;
+pub const TRANSPORT_OVERHEAD: usize = TRANSPORT_HEADER_SIZE + TAG_SIZE;
+pub const MAX_HANDSHAKE_SIZE: usize = 0x100;
+pub const TIMESTAMP_SIZE: usize = 0x08;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// NOTE(nosiee): the responder forgets a session nothing was received on for that long. the initiator does a new
+// handshake well before that, so a session it still sends on is never forgotten under it
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(180);
+pub const REKEY_AFTER_IDLE: Duration = Duration::from_secs(120);
+
+pub type Key = [u8; KEY_SIZE];
+
+#[derive(Clone)]
+pub struct StaticKeypair {
+    pub private: Key,
+    pub public: Key,
+}
+
+impl std::fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticKeypair").field("public", &hex::encode(self.public)).finish()
+    }
+}
+
+impl StaticKeypair {
+    pub fn from_hex(private: &str) -> anyhow::Result<Self> {
+        let private = decode_key(private)?;
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .ok_or_else(|| anyhow::anyhow!("curve25519 is not supported by the resolver"))?;
+
+        dh.set(&private);
+        let public = dh.pubkey().try_into()?;
+
+        Ok(Self { private, public })
+    }
+}
+
+pub fn decode_key(key: &str) -> anyhow::Result<Key> {
+    let key = hex::decode(key)?;
+    key.try_into()
+        .map_err(|key: Vec<u8>| anyhow::anyhow!("invalid key size: {}b, expected {}b", key.len(), KEY_SIZE))
+}
+
+#[derive(Debug)]
+pub enum NoiseMessage<'a> {
+    Initiation { sender: u32, payload: &'a [u8] },
+    Response { sender: u32, receiver: u32, payload: &'a [u8] },
+    Transport { receiver: u32, counter: u64, payload: &'a [u8] },
+}
+
+pub fn parse(buf: &[u8]) -> Option<NoiseMessage<'_>> {
+    match *buf.first()? {
+        HANDSHAKE_INITIATION if buf.len() > INITIATION_HEADER_SIZE => Some(NoiseMessage::Initiation {
+            sender: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            payload: &buf[INITIATION_HEADER_SIZE..],
+        }),
+        HANDSHAKE_RESPONSE if buf.len() > RESPONSE_HEADER_SIZE => Some(NoiseMessage::Response {
+            sender: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            receiver: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            payload: &buf[RESPONSE_HEADER_SIZE..],
+        }),
+        TRANSPORT_DATA if buf.len() >= TRANSPORT_OVERHEAD => Some(NoiseMessage::Transport {
+            receiver: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            counter: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            payload: &buf[TRANSPORT_HEADER_SIZE..],
+        }),
+        _ => None,
+    }
+}
+
+pub struct Session {
+    pub local_index: u32,
+    pub remote_index: u32,
+
+    transport: StatelessTransportState,
+    counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+    sent: Mutex<Instant>,
+    received: Mutex<Instant>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("local_index", &self.local_index)
+            .field("remote_index", &self.remote_index)
+            .field("counter", &self.counter)
+            .finish()
+    }
+}
+
+impl Session {
+    fn new(local_index: u32, remote_index: u32, state: HandshakeState) -> anyhow::Result<Self, TunnelError> {
+        let transport = state.into_stateless_transport_mode().map_err(handshake_error)?;
+
+        Ok(Self {
+            local_index,
+            remote_index,
+            transport,
+            counter: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::new()),
+            sent: Mutex::new(Instant::now()),
+            received: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn since_sent(&self) -> Duration {
+        self.sent.lock().unwrap().elapsed()
+    }
+
+    pub fn since_received(&self) -> Duration {
+        self.received.lock().unwrap().elapsed()
+    }
+
+    pub fn remote_static(&self) -> Option<Key> {
+        self.transport.get_remote_static()?.try_into().ok()
+    }
+
+    pub fn encrypt(&self, payload: &[u8]) -> anyhow::Result<Bytes, TunnelError> {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut message = BytesMut::zeroed(payload.len() + TRANSPORT_OVERHEAD);
+
+        message[0] = TRANSPORT_DATA;
+        message[4..8].copy_from_slice(&self.remote_index.to_be_bytes());
+        message[8..16].copy_from_slice(&counter.to_be_bytes());
+
+        let n = self
+            .transport
+            .write_message(counter, payload, &mut message[TRANSPORT_HEADER_SIZE..])
+            .map_err(|err| TunnelError::Crypto((err.to_string(), ENCRYPT_ERROR)))?;
+
+        message.truncate(TRANSPORT_HEADER_SIZE + n);
+        *self.sent.lock().unwrap() = Instant::now();
+
+        Ok(message.freeze())
+    }
+
+    pub fn decrypt(&self, counter: u64, ciphertext: &[u8], buffer: &mut [u8]) -> anyhow::Result<usize, TunnelError> {
+        if ciphertext.len() - TAG_SIZE > buffer.len() {
+            return Err(TunnelError::Strict((
+                format!("{}b payload doesn't fit into {}b buffer", ciphertext.len() - TAG_SIZE, buffer.len()),
+                PAYLOAD_SIZE_OVERFLOW,
+            )));
+        }
+
+        if !self.replay.lock().unwrap().check(counter) {
+            return Err(TunnelError::Strict((format!("{} counter replayed", counter), REPLAY_DETECTED)));
+        }
+
+        let n = self
+            .transport
+            .read_message(counter, ciphertext, buffer)
+            .map_err(|err| TunnelError::Crypto((err.to_string(), DECRYPT_ERROR)))?;
+
+        // NOTE(nosiee): the window is updated only after the frame is authenticated,
+        // otherwise anyone could move it forward with garbage
+        if !self.replay.lock().unwrap().update(counter) {
+            return Err(TunnelError::Strict((format!("{} counter replayed", counter), REPLAY_DETECTED)));
+        }
+
+        *self.received.lock().unwrap() = Instant::now();
+        Ok(n)
+    }
+}
+
+pub struct Initiator {
+    index: u32,
+    state: HandshakeState,
+}
+
+impl Initiator {
+    pub fn new(keypair: &StaticKeypair, remote_public: &Key) -> anyhow::Result<Self, TunnelError> {
+        let state = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&keypair.private)
+            .and_then(|b| b.remote_public_key(remote_public))
+            .and_then(|b| b.build_initiator())
+            .map_err(handshake_error)?;
+
+        Ok(Self {
+            index: rand::random(),
+            state,
+        })
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn initiation(&mut self) -> anyhow::Result<Bytes, TunnelError> {
+        let mut message = BytesMut::zeroed(MAX_HANDSHAKE_SIZE);
+
+        message[0] = HANDSHAKE_INITIATION;
+        message[4..8].copy_from_slice(&self.index.to_be_bytes());
+
+        let n = self
+            .state
+            .write_message(&timestamp().to_be_bytes(), &mut message[INITIATION_HEADER_SIZE..])
+            .map_err(handshake_error)?;
+
+        message.truncate(INITIATION_HEADER_SIZE + n);
+        Ok(message.freeze())
+    }
+
+    pub fn finish(mut self, sender: u32, payload: &[u8]) -> anyhow::Result<Session, TunnelError> {
+        let mut buffer = [0u8; MAX_HANDSHAKE_SIZE];
+        self.state.read_message(payload, &mut buffer).map_err(handshake_error)?;
+
+        Session::new(self.index, sender, self.state)
+    }
+}
+
+// NOTE(nosiee): the initiation is read before anything is answered, so the caller can check the initiator static key
+// and the timestamp it carries. a session is made only for an initiation that is newer than the last one of the key
+pub struct Responder {
+    sender: u32,
+    timestamp: u64,
+    state: HandshakeState,
+}
+
+impl Responder {
+    pub fn new(keypair: &StaticKeypair, sender: u32, payload: &[u8]) -> anyhow::Result<Self, TunnelError> {
+        let mut state = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&keypair.private)
+            .and_then(|b| b.build_responder())
+            .map_err(handshake_error)?;
+
+        let mut buffer = [0u8; MAX_HANDSHAKE_SIZE];
+        let n = state.read_message(payload, &mut buffer).map_err(handshake_error)?;
+
+        if n != TIMESTAMP_SIZE {
+            return Err(TunnelError::Strict((
+                format!("unexpected initiation payload size: {}b", n),
+                HANDSHAKE_ERROR,
+            )));
+        }
+
+        Ok(Self {
+            sender,
+            timestamp: u64::from_be_bytes(buffer[..TIMESTAMP_SIZE].try_into().unwrap()),
+            state,
+        })
+    }
+
+    pub fn remote_static(&self) -> Option<Key> {
+        self.state.get_remote_static()?.try_into().ok()
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<(Session, Bytes), TunnelError> {
+        let index: u32 = rand::random();
+        let mut message = BytesMut::zeroed(MAX_HANDSHAKE_SIZE);
+
+        message[0] = HANDSHAKE_RESPONSE;
+        message[4..8].copy_from_slice(&index.to_be_bytes());
+        message[8..12].copy_from_slice(&self.sender.to_be_bytes());
+
+        let n = self
+            .state
+            .write_message(&[], &mut message[RESPONSE_HEADER_SIZE..])
+            .map_err(handshake_error)?;
+        message.truncate(RESPONSE_HEADER_SIZE + n);
+
+        Ok((Session::new(index, self.sender, self.state)?, message.freeze()))
+    }
+}
+
+// NOTE(nosiee): nanoseconds since the unix epoch, the initiations of a key must only grow
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+fn handshake_error(err: snow::Error) -> TunnelError {
+    TunnelError::Crypto((err.to_string(), HANDSHAKE_ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair(byte: u8) -> StaticKeypair {
+        StaticKeypair::from_hex(&hex::encode([byte; KEY_SIZE])).unwrap()
+    }
+
+    fn handshake(initiator_keys: &StaticKeypair, responder_keys: &StaticKeypair) -> (Session, Session) {
+        let mut initiator = Initiator::new(initiator_keys, &responder_keys.public).unwrap();
+        let initiation = initiator.initiation().unwrap();
+
+        let (sender, payload) = match parse(&initiation) {
+            Some(NoiseMessage::Initiation { sender, payload }) => (sender, payload),
+            message => panic!("unexpected message: {:?}", message),
+        };
+
+        let responder = Responder::new(responder_keys, sender, payload).unwrap();
+        assert_eq!(responder.remote_static(), Some(initiator_keys.public));
+
+        let (responder_session, response) = responder.finish().unwrap();
+        let (sender, receiver, payload) = match parse(&response) {
+            Some(NoiseMessage::Response { sender, receiver, payload }) => (sender, receiver, payload),
+            message => panic!("unexpected message: {:?}", message),
+        };
+
+        assert_eq!(receiver, initiator.index());
+        (initiator.finish(sender, payload).unwrap(), responder_session)
+    }
+
+    fn transport(from: &Session, to: &Session, payload: &[u8]) -> anyhow::Result<Vec<u8>, TunnelError> {
+        let message = from.encrypt(payload)?;
+        let (receiver, counter, ciphertext) = match parse(&message) {
+            Some(NoiseMessage::Transport { receiver, counter, payload }) => (receiver, counter, payload),
+            message => panic!("unexpected message: {:?}", message),
+        };
+
+        assert_eq!(receiver, to.local_index);
+
+        let mut buffer = vec![0; payload.len()];
+        let n = to.decrypt(counter, ciphertext, &mut buffer)?;
+        buffer.truncate(n);
+
+        Ok(buffer)
+    }
+
+    #[test]
+    fn transport_both_ways() {
+        let (client, node) = (keypair(1), keypair(2));
+        let (initiator, responder) = handshake(&client, &node);
+
+        assert_eq!(initiator.remote_static(), Some(node.public));
+        assert_eq!(responder.remote_static(), Some(client.public));
+        assert_eq!(initiator.remote_index, responder.local_index);
+        assert_eq!(responder.remote_index, initiator.local_index);
+
+        for i in 0..4u8 {
+            let payload = vec![i; 100 * i as usize];
+            assert_eq!(transport(&initiator, &responder, &payload).unwrap(), payload);
+            assert_eq!(transport(&responder, &initiator, &payload).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_and_tampered_messages() {
+        let (initiator, responder) = handshake(&keypair(1), &keypair(2));
+        let mut buffer = vec![0; 0x100];
+
+        let message = initiator.encrypt(b"olla").unwrap();
+        let (counter, ciphertext) = match parse(&message) {
+            Some(NoiseMessage::Transport { counter, payload, .. }) => (counter, payload.to_vec()),
+            message => panic!("unexpected message: {:?}", message),
+        };
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(responder.decrypt(counter, &tampered, &mut buffer), Err(TunnelError::Crypto(_))));
+
+        assert_eq!(responder.decrypt(counter, &ciphertext, &mut buffer).unwrap(), 4);
+        assert!(matches!(
+            responder.decrypt(counter, &ciphertext, &mut buffer),
+            Err(TunnelError::Strict((_, REPLAY_DETECTED)))
+        ));
+    }
+
+    #[test]
+    fn rejects_payload_overflow() {
+        let (initiator, responder) = handshake(&keypair(1), &keypair(2));
+        let message = initiator.encrypt(&[0; 64]).unwrap();
+
+        let mut buffer = vec![0; 32];
+        assert!(matches!(
+            responder.decrypt(0, &message[TRANSPORT_HEADER_SIZE..], &mut buffer),
+            Err(TunnelError::Strict((_, PAYLOAD_SIZE_OVERFLOW)))
+        ));
+    }
+
+    #[test]
+    fn rejects_initiation_for_another_responder() {
+        let (client, node, other) = (keypair(1), keypair(2), keypair(3));
+        let mut initiator = Initiator::new(&client, &node.public).unwrap();
+        let initiation = initiator.initiation().unwrap();
+
+        assert!(Responder::new(&other, 1, &initiation[INITIATION_HEADER_SIZE..]).is_err());
+        assert!(Responder::new(&node, 1, &initiation[INITIATION_HEADER_SIZE..]).is_ok());
+    }
+
+    #[test]
+    fn initiation_timestamps_grow() {
+        let (client, node) = (keypair(1), keypair(2));
+
+        let timestamps: Vec<u64> = (0..3)
+            .map(|_| {
+                let initiation = Initiator::new(&client, &node.public).unwrap().initiation().unwrap();
+                Responder::new(&node, 1, &initiation[INITIATION_HEADER_SIZE..]).unwrap().timestamp()
+            })
+            .collect();
+
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn parses_only_complete_headers() {
+        assert!(parse(&[]).is_none());
+        assert!(parse(&[HANDSHAKE_INITIATION; INITIATION_HEADER_SIZE]).is_none());
+        assert!(parse(&[HANDSHAKE_RESPONSE; RESPONSE_HEADER_SIZE]).is_none());
+        assert!(parse(&[TRANSPORT_DATA; TRANSPORT_OVERHEAD - 1]).is_none());
+        assert!(parse(&[TRANSPORT_DATA; TRANSPORT_OVERHEAD]).is_some());
+        assert!(parse(&[0xff; 0x40]).is_none());
+    }
+}
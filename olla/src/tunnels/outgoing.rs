@@ -1,17 +1,41 @@
+use bytes::BytesMut;
+use socket2::SockRef;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, watch};
 use tracing::debug;
 
 use super::errors::*;
-use super::header;
+use super::header::{self, FrameKind, HEADER_SIZE, HeaderFrame};
+use super::noise::{self, Initiator, Key, NoiseMessage, Session, StaticKeypair};
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+pub const REQUEST_ATTEMPTS: usize = 5;
+
+#[derive(Debug)]
+struct Connection {
+    socket: UdpSocket,
+    session: Session,
+    sequence: AtomicU64,
+}
 
 #[derive(Debug)]
 pub struct OutgoingTunnel {
-    socket: RwLock<Option<UdpSocket>>,
+    connection: watch::Sender<Option<Arc<Connection>>>,
+    connecting: Mutex<()>,
     addr: Option<SocketAddr>,
 
-    pnode_addr: Option<SocketAddr>,
+    keypair: Option<Arc<StaticKeypair>>,
+    public_key: Option<Key>,
+
+    pnode_addr: RwLock<Option<SocketAddr>>,
+    fwmark: Option<u32>,
+    probe_ack: AtomicU64,
+    replies: HashMap<u8, watch::Sender<Option<Vec<u8>>>>,
 }
 
 impl Default for OutgoingTunnel {
@@ -23,10 +47,20 @@ impl Default for OutgoingTunnel {
 impl OutgoingTunnel {
     pub fn new() -> Self {
         Self {
-            socket: RwLock::new(None),
+            connection: watch::Sender::new(None),
+            connecting: Mutex::new(()),
             addr: None,
 
-            pnode_addr: None,
+            keypair: None,
+            public_key: None,
+
+            pnode_addr: RwLock::new(None),
+            fwmark: None,
+            probe_ack: AtomicU64::new(0),
+            replies: [FrameKind::LeaseReply, FrameKind::ResolverReply]
+                .into_iter()
+                .map(|kind| (u8::from(kind), watch::Sender::new(None)))
+                .collect(),
         }
     }
 
@@ -35,51 +69,186 @@ impl OutgoingTunnel {
         self
     }
 
-    pub fn set_primary_node(mut self, pnode_addr: SocketAddr) -> Self {
-        self.pnode_addr = Some(pnode_addr);
+    pub fn set_keypair(mut self, keypair: Arc<StaticKeypair>) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    pub fn set_public_key(mut self, public_key: Key) -> Self {
+        self.public_key = Some(public_key);
         self
     }
 
-    pub async fn send(&self, payload: &[u8]) -> anyhow::Result<usize, TunnelError> {
-        if self.socket.read().await.is_none() {
-            let socket = self.connect().await?;
-            let mut w_socket_guard = self.socket.write().await;
+    // NOTE(nosiee): the socket is marked before it's connected, the route is chosen by the mark
+    pub fn set_fwmark(mut self, fwmark: u32) -> Self {
+        self.fwmark = Some(fwmark);
+        self
+    }
+
+    pub fn set_primary_node(self, pnode_addr: SocketAddr) -> Self {
+        self.switch_primary_node(pnode_addr);
+        self
+    }
+
+    // NOTE(nosiee): the primary node is read for every frame, so the switch shows up in the very next header
+    pub fn switch_primary_node(&self, pnode_addr: SocketAddr) {
+        *self.pnode_addr.write().unwrap() = Some(pnode_addr);
+    }
+
+    // NOTE(nosiee): the caller fills the end-to-end fields of the frame (flow sequence and so on),
+    // the primary node and the sequence belong to the tunnel and are always overwritten
+    pub async fn send(&self, payload: &[u8], frame: HeaderFrame) -> anyhow::Result<usize, TunnelError> {
+        self.send_frame(frame, payload).await
+    }
+
+    pub async fn send_keepalive(&self) -> anyhow::Result<usize, TunnelError> {
+        self.send_frame(HeaderFrame::new(FrameKind::Keepalive), &[]).await
+    }
+
+    pub async fn send_probe(&self, id: u64) -> anyhow::Result<usize, TunnelError> {
+        self.send_frame(HeaderFrame::new(FrameKind::Probe), &id.to_be_bytes()).await
+    }
+
+    pub fn probe_ack(&self) -> u64 {
+        self.probe_ack.load(Ordering::Relaxed)
+    }
+
+    // NOTE(nosiee): a control request to the node itself, the lease or the resolvers, it has no payload
+    pub async fn send_request(&self, kind: FrameKind) -> anyhow::Result<usize, TunnelError> {
+        self.send_frame(HeaderFrame::new(kind), &[]).await
+    }
+
+    // NOTE(nosiee): the payload of the last control reply of the kind, it's updated by the pending recv.
+    // only the lease and the resolver replies are kept
+    pub fn subscribe_reply(&self, kind: FrameKind) -> watch::Receiver<Option<Vec<u8>>> {
+        self.replies[&u8::from(kind)].subscribe()
+    }
+
+    // NOTE(nosiee): drops the current session, the next frame does a new handshake.
+    // the pending recv switches to the new connection as soon as it's established
+    pub fn reset(&self) {
+        self.connection.send_replace(None);
+    }
+
+    // NOTE(nosiee): returns the data without the header, the header is returned along with it
+    pub async fn recv(&self, buffer: &mut [u8]) -> anyhow::Result<(usize, HeaderFrame), TunnelError> {
+        let mut connection_rx = self.connection.subscribe();
+        let mut message = BytesMut::zeroed(buffer.len() + noise::TRANSPORT_OVERHEAD);
+
+        loop {
+            let connection = connection_rx.borrow_and_update().clone();
+            let connection = match connection {
+                Some(connection) => connection,
+                None => {
+                    let _ = connection_rx.changed().await;
+                    continue;
+                }
+            };
+
+            let (n, addr) = tokio::select! {
+                r = connection.socket.recv_from(&mut message) => match r {
+                    Ok(r) => r,
+                    Err(err) => return Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE)))),
+                },
+                _ = connection_rx.changed() => continue,
+            };
 
-            *w_socket_guard = Some(socket);
+            debug!("{} bytes read from {}", n, addr.to_string());
+
+            let n = match noise::parse(&message[..n]) {
+                Some(NoiseMessage::Transport { receiver, counter, payload }) if receiver == connection.session.local_index => {
+                    connection.session.decrypt(counter, payload, buffer)?
+                }
+                _ => {
+                    debug!("{} message omitted, unexpected noise message", hex::encode(&message[..n]));
+                    continue;
+                }
+            };
+
+            if n < HEADER_SIZE {
+                debug!("{} frame omitted, unusual size: {}b", hex::encode(&buffer[..n]), n);
+                continue;
+            }
+
+            let header_frame = header::decode(buffer[..HEADER_SIZE].try_into().unwrap());
+            match header_frame.kind {
+                FrameKind::Data => {
+                    buffer.copy_within(HEADER_SIZE..n, 0);
+                    return Ok((n - HEADER_SIZE, header_frame));
+                }
+                FrameKind::ProbeReply if n >= HEADER_SIZE + 8 => {
+                    let id = u64::from_be_bytes(buffer[HEADER_SIZE..HEADER_SIZE + 8].try_into().unwrap());
+                    self.probe_ack.fetch_max(id, Ordering::Relaxed);
+                }
+                kind @ (FrameKind::LeaseReply | FrameKind::ResolverReply) => {
+                    self.replies[&u8::from(kind)].send_replace(Some(buffer[HEADER_SIZE..n].to_vec()));
+                }
+                kind => debug!("{:?} frame omitted, unexpected kind", kind),
+            }
         }
+    }
+
+    async fn send_frame(&self, frame: HeaderFrame, payload: &[u8]) -> anyhow::Result<usize, TunnelError> {
+        let connection = self.connection().await?;
+        let frame = frame
+            .set_primary_node(*self.pnode_addr.read().unwrap())
+            .set_sequence(connection.sequence.fetch_add(1, Ordering::Relaxed));
 
-        let w_socket_guard = self.socket.read().await;
-        let socket = w_socket_guard.as_ref().unwrap();
-        let payload = header::extend_payload(payload, self.pnode_addr);
+        let payload = header::extend_payload(payload, &frame);
+        let message = connection.session.encrypt(&payload)?;
 
-        if let Err(err) = socket.send(&payload).await {
+        if let Err(err) = connection.socket.send(&message).await {
             return Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE))));
         }
 
-        debug!("{} bytes written to {}", payload.len(), self.addr.unwrap().to_string());
+        debug!("{} bytes written to {}", message.len(), self.addr.unwrap().to_string());
         Ok(payload.len())
     }
 
-    pub async fn recv(&self, buffer: &mut [u8]) -> anyhow::Result<usize, TunnelError> {
-        let w_socket_guard = self.socket.read().await;
-        let socket = w_socket_guard.as_ref().unwrap();
+    async fn connection(&self) -> anyhow::Result<Arc<Connection>, TunnelError> {
+        if let Some(connection) = self.current() {
+            return Ok(connection);
+        }
 
-        match socket.recv_from(buffer).await {
-            Ok((n, addr)) => {
-                debug!("{} bytes read from {}", n, addr.to_string());
-                Ok(n)
-            }
-            Err(err) => Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE)))),
+        let _connecting_guard = self.connecting.lock().await;
+        if let Some(connection) = self.current() {
+            return Ok(connection);
         }
+
+        let connection = Arc::new(self.connect().await?);
+        self.connection.send_replace(Some(connection.clone()));
+
+        Ok(connection)
+    }
+
+    // NOTE(nosiee): a session that has been idle for a while may be forgotten by the node already, it's replaced
+    fn current(&self) -> Option<Arc<Connection>> {
+        let connection = self.connection.borrow().clone();
+        connection.filter(|connection| connection.session.since_sent() < noise::REKEY_AFTER_IDLE)
     }
 
-    async fn connect(&self) -> anyhow::Result<UdpSocket, TunnelError> {
-        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+    async fn connect(&self) -> anyhow::Result<Connection, TunnelError> {
+        let addr = self.addr.unwrap();
+        let bind_addr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+
+        let socket = match UdpSocket::bind(bind_addr).await {
             Ok(socket) => socket,
             Err(err) => return Err(TunnelError::Connection((err.to_string(), err.raw_os_error().unwrap_or(CONNECT_ERROR)))),
         };
 
-        if let Err(err) = socket.connect(self.addr.unwrap()).await {
+        let marked = match self.fwmark {
+            Some(fwmark) => SockRef::from(&socket).set_mark(fwmark),
+            None => Ok(()),
+        };
+
+        if let Err(err) = marked {
+            return Err(TunnelError::Connection((err.to_string(), err.raw_os_error().unwrap_or(CONNECT_ERROR))));
+        }
+
+        if let Err(err) = socket.connect(addr).await {
             return Err(TunnelError::Connection((err.to_string(), err.raw_os_error().unwrap_or(CONNECT_ERROR))));
         }
 
@@ -89,6 +258,50 @@ impl OutgoingTunnel {
             socket.peer_addr().unwrap().to_string()
         );
 
-        Ok(socket)
+        let session = self.handshake(&socket).await?;
+        debug!("handshake with {} completed, session: {:?}", self.addr.unwrap().to_string(), session);
+
+        Ok(Connection {
+            socket,
+            session,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    async fn handshake(&self, socket: &UdpSocket) -> anyhow::Result<Session, TunnelError> {
+        let (keypair, public_key) = match (self.keypair.as_ref(), self.public_key.as_ref()) {
+            (Some(keypair), Some(public_key)) => (keypair, public_key),
+            _ => return Err(TunnelError::Strict(("the tunnel keys must be set".into(), HANDSHAKE_ERROR))),
+        };
+
+        let mut initiator = Initiator::new(keypair, public_key)?;
+        let initiation = initiator.initiation()?;
+
+        if let Err(err) = socket.send(&initiation).await {
+            return Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE))));
+        }
+
+        let mut buffer = [0u8; noise::MAX_HANDSHAKE_SIZE];
+        let response = tokio::time::timeout(noise::HANDSHAKE_TIMEOUT, async {
+            loop {
+                let n = socket.recv(&mut buffer).await?;
+
+                if let Some(NoiseMessage::Response { sender, receiver, payload }) = noise::parse(&buffer[..n])
+                    && receiver == initiator.index()
+                {
+                    return Ok::<_, std::io::Error>((sender, payload.to_vec()));
+                }
+            }
+        })
+        .await;
+
+        match response {
+            Ok(Ok((sender, payload))) => initiator.finish(sender, &payload),
+            Ok(Err(err)) => Err(TunnelError::IO((err.to_string(), err.raw_os_error().unwrap_or(DEFAULT_ERROR_CODE)))),
+            Err(_) => Err(TunnelError::Connection((
+                format!("no handshake response from {}", self.addr.unwrap()),
+                HANDSHAKE_ERROR,
+            ))),
+        }
     }
 }
@@ -0,0 +1,153 @@
+// NOTE(nosiee): the sliding window from RFC 6479, the same one wireguard uses.
+// the bitmap is split into blocks, one extra block lets us move the window by clearing
+// whole blocks instead of shifting bits
+pub const WINDOW_SIZE: u64 = 0x800;
+
+const BLOCK_BITS: u64 = u64::BITS as u64;
+const BLOCKS: u64 = WINDOW_SIZE / BLOCK_BITS + 1;
+
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    last: u64,
+    bitmap: [u64; BLOCKS as usize],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            last: 0,
+            bitmap: [0; BLOCKS as usize],
+        }
+    }
+
+    pub fn check(&self, counter: u64) -> bool {
+        let counter = match counter.checked_add(1) {
+            Some(counter) => counter,
+            None => return false,
+        };
+
+        if counter > self.last {
+            return true;
+        }
+
+        if self.last - counter >= WINDOW_SIZE {
+            return false;
+        }
+
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] & bit == 0
+    }
+
+    pub fn update(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+
+        let counter = counter + 1;
+        if counter > self.last {
+            let current = self.last / BLOCK_BITS;
+            let diff = (counter / BLOCK_BITS - current).min(BLOCKS);
+
+            for i in 1..=diff {
+                self.bitmap[((current + i) % BLOCKS) as usize] = 0;
+            }
+
+            self.last = counter;
+        }
+
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] |= bit;
+
+        true
+    }
+
+    // NOTE(nosiee): the highest counter seen so far, none if the window is empty
+    pub fn last(&self) -> Option<u64> {
+        self.last.checked_sub(1)
+    }
+
+    fn position(counter: u64) -> (usize, u64) {
+        (((counter / BLOCK_BITS) % BLOCKS) as usize, 1 << (counter % BLOCK_BITS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_repeated_counters() {
+        let mut window = ReplayWindow::new();
+
+        assert_eq!(window.last(), None);
+        assert!(window.update(0));
+        assert!(!window.update(0));
+        assert!(window.update(1));
+        assert!(!window.check(1));
+        assert_eq!(window.last(), Some(1));
+    }
+
+    #[test]
+    fn accepts_out_of_order_counters_within_window() {
+        let mut window = ReplayWindow::new();
+
+        assert!(window.update(10));
+        assert!(window.update(5));
+        assert!(window.update(7));
+        assert!(!window.update(5));
+        assert!(window.update(6));
+        assert_eq!(window.last(), Some(10));
+    }
+
+    #[test]
+    fn window_edges() {
+        let mut window = ReplayWindow::new();
+        let last = WINDOW_SIZE * 3;
+
+        assert!(window.update(last));
+        assert!(!window.check(last - WINDOW_SIZE));
+        assert!(window.check(last - WINDOW_SIZE + 1));
+        assert!(window.update(last - WINDOW_SIZE + 1));
+        assert!(!window.update(last - WINDOW_SIZE + 1));
+    }
+
+    #[test]
+    fn bitmap_wraps_around() {
+        let mut window = ReplayWindow::new();
+
+        for counter in 0..WINDOW_SIZE * 4 {
+            assert!(window.update(counter));
+        }
+
+        let last = WINDOW_SIZE * 4 - 1;
+        for counter in last + 1 - WINDOW_SIZE..=last {
+            assert!(!window.check(counter));
+        }
+
+        // NOTE(nosiee): the blocks reused by the jump must not keep the bits of the counters they held before
+        let jump = last + WINDOW_SIZE / 2;
+        assert!(window.update(jump));
+        for counter in last + 1..jump {
+            assert!(window.check(counter));
+        }
+
+        assert!(window.update(jump + WINDOW_SIZE * 10));
+        assert!(!window.check(jump));
+        assert!(window.check(jump + WINDOW_SIZE * 10 - 1));
+    }
+
+    #[test]
+    fn rejects_max_counter() {
+        let mut window = ReplayWindow::new();
+
+        assert!(!window.check(u64::MAX));
+        assert!(!window.update(u64::MAX));
+        assert!(window.update(u64::MAX - 1));
+    }
+}